@@ -0,0 +1,133 @@
+//! Feed benchmarks
+//!
+//! A DyDx feed with a deep book is pushed the frames of one second of a busy
+//! market. Every event is held, with its snapshot of the book, until the next
+//! one like the strategy does.
+
+#[allow(dead_code)]
+#[path = "../src/capture.rs"]
+mod capture;
+// The modules are found in their directories from the sources of the bot
+#[allow(dead_code, unused_imports)]
+#[path = "../src"]
+mod src {
+    pub mod exchange;
+    #[cfg(test)]
+    pub mod mock;
+}
+
+use src::exchange;
+// The tests of the connections and of the live backends use the mock servers
+#[cfg(test)]
+use src::mock;
+
+use std::time::SystemTime;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use exchange::{FeedSource, FeedStream, MarketEvent, Symbol, Venue, ViolationPolicy};
+use futures_util::{FutureExt, StreamExt};
+use rand::{rngs::StdRng, seq::index::sample, Rng, SeedableRng};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde_json::json;
+
+const MARKET: &str = "BTC-USD";
+const MID: Decimal = dec!(60000);
+const TICK: Decimal = dec!(0.5);
+/// Levels of each side of the snapshot
+const DEPTH: i64 = 500;
+/// Frames of one second
+const FRAMES: usize = 1000;
+
+/// Price `ticks` away from the mid price, above it for `direction` 1 and
+/// below it for -1
+fn price(direction: i64, ticks: i64) -> String {
+    (MID + TICK * Decimal::from(direction * ticks)).to_string()
+}
+
+fn snapshot() -> String {
+    let side = |direction| {
+        (1..=DEPTH)
+            .map(|ticks| json!({"price": price(direction, ticks), "size": "1"}))
+            .collect::<Vec<_>>()
+    };
+    json!({
+        "type": "subscribed",
+        "message_id": 1,
+        "id": MARKET,
+        "contents": {"bids": side(-1), "asks": side(1)},
+    })
+    .to_string()
+}
+
+/// Frames setting 1 to 10 distinct levels of each side within 50 ticks of
+/// the mid price, a third of them removed
+fn frames() -> Vec<String> {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut side = |direction| {
+        let count = rng.gen_range(1..=10);
+        sample(&mut rng, 50, count)
+            .into_iter()
+            .map(|ticks| {
+                let amount = if rng.gen_bool(1.0 / 3.0) {
+                    dec!(0)
+                } else {
+                    Decimal::new(rng.gen_range(1..200), 2)
+                };
+                [price(direction, ticks as i64 + 1), amount.to_string()]
+            })
+            .collect::<Vec<_>>()
+    };
+
+    (0..FRAMES)
+        .map(|index| {
+            json!({
+                "type": "channel_data",
+                "message_id": index + 2,
+                "id": MARKET,
+                "contents": {"bids": side(-1), "asks": side(1)},
+            })
+            .to_string()
+        })
+        .collect()
+}
+
+/// Feed holding the snapshot, with the event of the snapshot
+fn feed() -> (FeedStream, MarketEvent) {
+    let mut feed = Venue::DyDx.feed(FeedSource::Manual, ViolationPolicy::Resync);
+    feed.order_book_subscribe(&MARKET.parse::<Symbol>().unwrap());
+    let event = push(&mut feed, &snapshot());
+    (feed, event)
+}
+
+fn push(feed: &mut FeedStream, frame: &str) -> MarketEvent {
+    feed.push_frame(frame, SystemTime::now())
+        .expect("frame pushed");
+    feed.next()
+        .now_or_never()
+        .flatten()
+        .expect("event of the frame")
+}
+
+fn feed_updates(c: &mut Criterion) {
+    let frames = frames();
+
+    let mut group = c.benchmark_group("feed updates");
+    group.throughput(Throughput::Elements(FRAMES as u64));
+    group.bench_function("one second, holding the last event", |b| {
+        b.iter_batched(
+            feed,
+            |(mut feed, mut event)| {
+                for frame in &frames {
+                    event = push(&mut feed, frame);
+                }
+                (feed, event)
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, feed_updates);
+criterion_main!(benches);
@@ -0,0 +1,113 @@
+//! Order book benchmarks
+//!
+//! A deep book receives the frames of one second of a busy perpetual market:
+//! every frame sets a few levels of both sides near the top of the book.
+
+#[allow(dead_code)]
+#[path = "../src/exchange/book.rs"]
+mod book;
+
+use std::hint::black_box;
+
+use book::{BookEntry, OrderBook, OrderBookMessage};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rand::{rngs::StdRng, seq::index::sample, Rng, SeedableRng};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+const MID: Decimal = dec!(60000);
+const TICK: Decimal = dec!(0.5);
+/// Levels of each side of the snapshot
+const DEPTH: i64 = 500;
+/// Frames of one second
+const FRAMES: usize = 1000;
+
+/// Level `ticks` away from the mid price, above it for `direction` 1 and
+/// below it for -1
+fn level(direction: i64, ticks: i64, amount: Decimal) -> BookEntry {
+    BookEntry {
+        price: MID + TICK * Decimal::from(direction * ticks),
+        amount,
+    }
+}
+
+fn snapshot() -> OrderBookMessage {
+    OrderBookMessage::Snapshot {
+        bids: (1..=DEPTH).map(|ticks| level(-1, ticks, dec!(1))).collect(),
+        asks: (1..=DEPTH).map(|ticks| level(1, ticks, dec!(1))).collect(),
+    }
+}
+
+/// Frames setting 1 to 10 distinct levels of each side within 50 ticks of
+/// the mid price, a third of them removed
+fn frames() -> Vec<OrderBookMessage> {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut side = |direction| {
+        let count = rng.gen_range(1..=10);
+        sample(&mut rng, 50, count)
+            .into_iter()
+            .map(|ticks| {
+                let amount = if rng.gen_bool(1.0 / 3.0) {
+                    dec!(0)
+                } else {
+                    Decimal::new(rng.gen_range(1..200), 2)
+                };
+                level(direction, ticks as i64 + 1, amount)
+            })
+            .collect()
+    };
+
+    (0..FRAMES)
+        .map(|_| OrderBookMessage::Update {
+            bids: side(-1),
+            asks: side(1),
+        })
+        .collect()
+}
+
+fn order_book(c: &mut Criterion) {
+    let frames = frames();
+    let mut book = OrderBook::new();
+    book.update(snapshot()).expect("valid snapshot");
+
+    c.bench_function("snapshot", |b| {
+        b.iter_batched(
+            snapshot,
+            |snapshot| {
+                let mut book = OrderBook::new();
+                book.update(snapshot).expect("valid snapshot");
+                book
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    let mut group = c.benchmark_group("updates");
+    group.throughput(Throughput::Elements(FRAMES as u64));
+    group.bench_function("one second", |b| {
+        b.iter_batched(
+            || (book.clone(), frames.clone()),
+            |(mut book, frames)| {
+                for frame in frames {
+                    book.update(frame).expect("valid frame");
+                }
+                book
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+
+    c.bench_function("best bid and ask", |b| {
+        b.iter(|| {
+            let book = black_box(&book);
+            (book.best_bid().cloned(), book.best_ask().cloned())
+        })
+    });
+    c.bench_function("buy price", |b| {
+        b.iter(|| black_box(&book).buy_price(black_box(dec!(5))))
+    });
+}
+
+criterion_group!(benches, order_book);
+criterion_main!(benches);
@@ -0,0 +1,431 @@
+//! Backtest of the arbitrage over captured order book data
+//!
+//! The frames of a capture file are pushed to the exchanges in the order they
+//! were recorded. Time is simulated: the backtest runs on a paused clock which
+//! jumps to the time of the next frame, or to the end of the latency of a
+//! paper order, as soon as every task waits. Frames keep being pushed while
+//! orders wait, their fills see the book moved like in the bot.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    iter::Peekable,
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use futures_util::{FutureExt, StreamExt};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use tokio::{runtime::Runtime, time::Instant};
+
+use crate::{
+    bot::Market,
+    capture::{CaptureEntry, CaptureReader},
+    exchange::{FeedSource, FeedStream, MarketEvent, OrderExecutor, PaperExecutor, Symbol, Venue},
+    pnl::PnlReport,
+    portfolio::PortfolioSnapshot,
+    Config,
+};
+
+#[derive(Debug, Default)]
+pub struct BacktestReport {
+    /// Timestamp of the first and last frame, in milliseconds
+    pub start: u64,
+    pub end: u64,
+    pub frames: usize,
+    /// Result of every market
+    pub markets: Vec<MarketReport>,
+}
+
+#[derive(Debug, Default)]
+pub struct MarketReport {
+    /// `None` for the single market configured without `MARKETS`
+    pub name: Option<String>,
+    /// Largest drop of the equity from a previous peak, as a fraction of the
+    /// peak
+    pub max_drawdown: Decimal,
+    /// P&L at the end of the run
+    pub pnl: Option<PnlReport>,
+    /// Balances at the end of the run
+    pub portfolio: Option<PortfolioSnapshot>,
+}
+
+impl Display for BacktestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "frames {} over {:.1}s",
+            self.frames,
+            Decimal::from(self.end.saturating_sub(self.start)) / dec!(1000)
+        )?;
+        for (index, market) in self.markets.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", market)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for MarketReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(name) = &self.name {
+            writeln!(f, "market {}", name)?;
+        }
+        if let Some(pnl) = &self.pnl {
+            writeln!(f, "{}", pnl)?;
+        }
+        write!(f, "max drawdown {:.4}%", self.max_drawdown * dec!(100))?;
+        if let Some(portfolio) = &self.portfolio {
+            write!(f, "\nportfolio {}", portfolio)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runtime of the backtests, its clock starts paused
+pub fn runtime() -> std::io::Result<Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+}
+
+/// Run the markets of `config` over the capture file at `path`. The clock of
+/// the runtime must be paused, see [`runtime`]
+pub async fn run_backtest(config: &Config, path: &Path) -> anyhow::Result<BacktestReport> {
+    let mut frames = Frames {
+        entries: CaptureReader::open(path)?.peekable(),
+        start: None,
+    };
+
+    let mut feeds = config
+        .exchanges
+        .iter()
+        .map(|exchange| exchange.venue.feed(FeedSource::Manual, config.book_policy))
+        .collect::<Vec<_>>();
+    let names = feeds
+        .iter()
+        .map(|feed| feed.to_string())
+        .collect::<Vec<_>>();
+
+    // Trades are always simulated
+    let mut markets = Vec::new();
+    let mut routes = HashMap::new();
+    for (index, market_config) in config.markets.iter().enumerate() {
+        let executors = config
+            .exchanges
+            .iter()
+            .zip(&mut feeds)
+            .zip(&market_config.symbols)
+            .enumerate()
+            .map(|(key, ((exchange, feed), symbol))| {
+                routes.insert((exchange.venue, symbol.clone()), (index, key));
+                Box::new(PaperExecutor::new(
+                    exchange.venue,
+                    exchange.fee,
+                    feed.order_book_subscribe(symbol),
+                    config.paper.clone(),
+                )) as Box<dyn OrderExecutor>
+            })
+            .collect::<Vec<_>>();
+        markets.push(Market::new(config, market_config, executors));
+    }
+
+    let mut report = BacktestReport {
+        markets: config
+            .markets
+            .iter()
+            .map(|market| MarketReport {
+                name: market.name.clone(),
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    };
+    let mut peaks = vec![dec!(0); markets.len()];
+
+    // Events of every book received while orders were executed, by market
+    // and exchange
+    let mut pending = BTreeMap::<(usize, usize), Vec<MarketEvent>>::new();
+    let mut finished = false;
+    loop {
+        // Updates of the books by persistent trades
+        drain(&mut feeds, &routes, &mut pending);
+        let ((index, key), event) = match pending.pop_first() {
+            Some((route, mut events)) => {
+                let Some(event) = events.pop() else {
+                    continue;
+                };
+                // Only the last book is traded on, like in the bot
+                for event in &events {
+                    markets[route.0].arbitrage.record_feed(route.1, event);
+                }
+                (route, event)
+            }
+            None => match frames.next().await? {
+                Some(entry) => {
+                    push(&mut feeds, &names, &mut report, &entry)?;
+                    continue;
+                }
+                None => break,
+            },
+        };
+
+        // The next frames are pushed while the orders wait for their latency
+        let market = &mut markets[index];
+        {
+            let update = market.update(key, event);
+            tokio::pin!(update);
+            loop {
+                tokio::select! {
+                    biased;
+                    result = &mut update => {
+                        result?;
+                        break;
+                    }
+                    entry = frames.next(), if !finished => match entry? {
+                        Some(entry) => {
+                            push(&mut feeds, &names, &mut report, &entry)?;
+                            drain(&mut feeds, &routes, &mut pending);
+                        }
+                        None => finished = true,
+                    },
+                }
+            }
+        }
+
+        if let Some(pnl) = market.arbitrage.pnl() {
+            let peak = &mut peaks[index];
+            let market_report = &mut report.markets[index];
+            *peak = (*peak).max(pnl.equity);
+            if *peak > dec!(0) {
+                market_report.max_drawdown =
+                    market_report.max_drawdown.max((*peak - pnl.equity) / *peak);
+            }
+            market_report.pnl = Some(pnl);
+        }
+    }
+
+    if let Some((start, _)) = frames.start {
+        report.start = start;
+    }
+    for (market, market_report) in markets.iter().zip(&mut report.markets) {
+        market_report.portfolio = Some(market.arbitrage.snapshot());
+    }
+    Ok(report)
+}
+
+/// Frames of the capture, each one at its time on the clock of the runtime
+struct Frames {
+    entries: Peekable<CaptureReader>,
+    /// Timestamp of the first frame and the time it was taken at
+    start: Option<(u64, Instant)>,
+}
+
+impl Frames {
+    /// Wait until the time of the next frame and take it, `None` at the end
+    /// of the capture. The frame is left in place if the wait is cancelled
+    async fn next(&mut self) -> anyhow::Result<Option<CaptureEntry>> {
+        let timestamp = match self.entries.peek() {
+            Some(Ok(entry)) => entry.timestamp,
+            _ => return self.entries.next().transpose(),
+        };
+        let (first, start) = *self.start.get_or_insert((timestamp, Instant::now()));
+        tokio::time::sleep_until(start + Duration::from_millis(timestamp.saturating_sub(first)))
+            .await;
+        self.entries.next().transpose()
+    }
+}
+
+/// Push `entry` to the feed of its exchange, if it is backtested
+fn push(
+    feeds: &mut [FeedStream],
+    names: &[String],
+    report: &mut BacktestReport,
+    entry: &CaptureEntry,
+) -> anyhow::Result<()> {
+    report.end = entry.timestamp;
+    let Some(key) = names.iter().position(|name| *name == entry.exchange) else {
+        return Ok(());
+    };
+    // The bot runs on the time of the capture: staleness, risk limits and
+    // rebalancing intervals are measured between captured frames
+    let received_at = UNIX_EPOCH + Duration::from_millis(entry.timestamp);
+    feeds[key].push_frame(&entry.frame, received_at)?;
+    report.frames += 1;
+    Ok(())
+}
+
+/// Move every update the feeds have ready to `pending`, by route
+fn drain(
+    feeds: &mut [FeedStream],
+    routes: &HashMap<(Venue, Symbol), (usize, usize)>,
+    pending: &mut BTreeMap<(usize, usize), Vec<MarketEvent>>,
+) {
+    while let Some(event) = feeds
+        .iter_mut()
+        .find_map(|feed| feed.next().now_or_never()?)
+    {
+        if let Some(&route) = routes.get(&(event.venue, event.symbol.clone())) {
+            pending.entry(route).or_default().push(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::exchange::{aevo_checksum, BookEntry, PaperConfig};
+
+    fn config(latency: Duration) -> Config {
+        Config {
+            paper: PaperConfig {
+                latency,
+                seed: Some(0),
+                persistent_trades: true,
+                ..Default::default()
+            },
+            ..Config::test()
+        }
+    }
+
+    fn levels(levels: &[(Decimal, Decimal)]) -> Vec<BookEntry> {
+        levels
+            .iter()
+            .map(|&(price, amount)| BookEntry { price, amount })
+            .collect()
+    }
+
+    /// Aevo snapshot frame with its checksum
+    fn aevo(bids: &[(Decimal, Decimal)], asks: &[(Decimal, Decimal)]) -> String {
+        let (bids, asks) = (levels(bids), levels(asks));
+        let side = |levels: &[BookEntry]| {
+            levels
+                .iter()
+                .map(|level| [level.price.to_string(), level.amount.to_string()])
+                .collect::<Vec<_>>()
+        };
+        json!({
+            "channel": "orderbook:BTC-PERP",
+            "data": {
+                "type": "snapshot",
+                "instrument_id": "1",
+                "instrument_name": "BTC-PERP",
+                "instrument_type": "PERPETUAL",
+                "bids": side(&bids),
+                "asks": side(&asks),
+                "last_updated": "1",
+                "checksum": aevo_checksum(&bids, &asks).to_string(),
+            },
+            "write_ts": "0",
+        })
+        .to_string()
+    }
+
+    /// Capture of an arbitrage: the DyDx bids go above the Aevo asks at 1s,
+    /// the Aevo asks follow them 50ms later
+    fn capture() -> PathBuf {
+        let frames = [
+            (
+                0,
+                "Aevo",
+                aevo(&[(dec!(59990), dec!(1))], &[(dec!(60000), dec!(1))]),
+            ),
+            (
+                10,
+                "DyDx",
+                json!({
+                    "type": "subscribed",
+                    "connection_id": "fixture",
+                    "message_id": 1,
+                    "channel": "v4_orderbook",
+                    "id": "BTC-USD",
+                    "contents": {
+                        "bids": [{"price": "59990", "size": "1"}],
+                        "asks": [{"price": "60000", "size": "1"}],
+                    },
+                })
+                .to_string(),
+            ),
+            (
+                1000,
+                "DyDx",
+                json!({
+                    "type": "channel_data",
+                    "connection_id": "fixture",
+                    "message_id": 2,
+                    "channel": "v4_orderbook",
+                    "id": "BTC-USD",
+                    "contents": {
+                        "bids": [["60600", "1"]],
+                        "asks": [["60000", "0"], ["60700", "1"]],
+                    },
+                })
+                .to_string(),
+            ),
+            (
+                1050,
+                "Aevo",
+                aevo(&[(dec!(59990), dec!(1))], &[(dec!(60650), dec!(1))]),
+            ),
+        ];
+        let path = std::env::temp_dir().join(format!("backtest-{}.jsonl", std::process::id()));
+        let lines = frames
+            .into_iter()
+            .map(|(timestamp, exchange, frame)| {
+                serde_json::to_string(&CaptureEntry {
+                    timestamp,
+                    exchange: exchange.to_string(),
+                    frame,
+                })
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        path
+    }
+
+    /// Final P&L and wallets of the only market
+    fn result(report: &BacktestReport) -> (PnlReport, PortfolioSnapshot) {
+        let market = &report.markets[0];
+        (
+            market.pnl.clone().unwrap(),
+            market.portfolio.clone().unwrap(),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn orders_fill_on_the_book_at_the_end_of_their_latency() {
+        let path = capture();
+        let instant = run_backtest(&config(Duration::ZERO), &path).await.unwrap();
+        let delayed = run_backtest(&config(Duration::from_millis(100)), &path)
+            .await
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!((instant.start, instant.end, instant.frames), (0, 1050, 4));
+
+        // 500 of quote on Aevo buy 1/120 at 60000, sold at 60600 on DyDx
+        let (pnl, portfolio) = result(&instant);
+        assert_eq!(pnl.round_trips, 1);
+        assert_eq!(pnl.gross.round_dp(4), dec!(5));
+        assert_eq!(pnl.realized.round_dp(4), dec!(5));
+        assert_eq!(portfolio.venues[0].quote.total.round_dp(4), dec!(0));
+        assert_eq!(portfolio.venues[1].quote.total.round_dp(4), dec!(1005));
+
+        // The Aevo asks moved above the limit before the buy was filled. The
+        // base sold on DyDx is realized at the cost it was bought at, 59990,
+        // the unwind finds no ask at 60600
+        let (pnl, portfolio) = result(&delayed);
+        assert_eq!(pnl.gross, dec!(0));
+        assert_eq!(pnl.realized.round_dp(4), dec!(5.0833));
+        assert_eq!(portfolio.venues[0].quote.total, dec!(500));
+        assert_eq!(portfolio.venues[1].quote.total.round_dp(4), dec!(1005));
+    }
+}
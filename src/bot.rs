@@ -1,129 +1,640 @@
 //! Arbitrage bot
 
-use std::pin::Pin;
-
+#[cfg(feature = "sqlite")]
+use crate::storage::Storage;
 use crate::{
-    exchange::{Aevo, BookEntry, DyDx, Exchange, Wallet},
-    Config,
+    capture::Recorder,
+    exchange::{
+        BookEntry, BookHandle, BookState, ConnectionState, FeedSource, FeedStream, LiveExecution,
+        MarketEvent, OrderBook, OrderExecutor, PaperConfig, PaperExecutor, Side, Symbol,
+    },
+    execution::{Coordinator, LegVenue},
+    health::{FeedMonitor, HealthReport},
+    pnl::{LegFill, PnlEngine, PnlReport},
+    portfolio::{Asset, Portfolio, PortfolioSnapshot},
+    rebalance::Rebalancer,
+    risk::{RiskManager, TradeIntent},
+    Config, ExchangeConfig, MarketConfig,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    time::{Duration, SystemTime},
 };
+
 use futures_util::StreamExt;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use tokio_stream::StreamMap;
+use tokio::{sync::mpsc, time::Instant};
+use tokio_stream::{wrappers::ReceiverStream, StreamMap};
+use tracing::Instrument;
 
-pub async fn run_bot(config: &Config) -> anyhow::Result<()> {
-    let aevo = Aevo::new(config.persistent_trades, config.aevo_fee);
-    let dydx = DyDx::new(config.persistent_trades, config.dydx_fee);
+/// Period of the feed health reports
+const HEALTH_INTERVAL: Duration = Duration::from_secs(30);
 
-    aevo.order_book_subscribe(&config.aevo_symbol);
-    dydx.order_book_subscribe(&config.dydx_symbol);
-
-    let mut wallets_initialized = false;
-    let mut aevo_wallet = Wallet::new(config.starting_value);
-    let mut dydx_wallet = Wallet::new(config.starting_value);
+/// Run the markets of `config` until their feeds end or `shutdown` completes.
+/// The orders being executed are closed first. Returns the markets as they
+/// were left
+pub async fn run_bot(
+    config: &Config,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<Vec<Market>> {
+    let recorder = match (&config.replay_file, &config.record_file) {
+        (None, Some(path)) => {
+            tracing::info!("recording to {:?}", path);
+            Some(Recorder::create(path).await?)
+        }
+        _ => None,
+    };
+    if let Some(path) = &config.replay_file {
+        tracing::info!("replaying {:?}", path);
+    }
 
-    let mut exchanges = StreamMap::<
-        usize,
-        Pin<Box<dyn Exchange<Item = (Option<BookEntry>, Option<BookEntry>)>>>,
-    >::new();
+    // One feed and one live execution backend per exchange, shared by every
+    // market
+    let mut feeds = Vec::new();
+    for exchange_config in &config.exchanges {
+        let source = match &config.replay_file {
+            Some(path) => FeedSource::Replay(path.clone()),
+            None => FeedSource::Live {
+                url: exchange_config.url.clone(),
+                recorder: recorder.clone(),
+                connection: config.connection.clone(),
+            },
+        };
+        let feed = exchange_config
+            .venue
+            .feed(source.clone(), config.book_policy);
+        #[cfg(any(feature = "aevo-live", feature = "dydx-live"))]
+        let live = exchange_config.execution.clone().and_then(|execution| {
+            exchange_config
+                .venue
+                .live_execution(exchange_config.fee, execution, &source)
+        });
+        #[cfg(not(any(feature = "aevo-live", feature = "dydx-live")))]
+        let live = None;
+        feeds.push((feed, live));
+    }
 
-    exchanges.insert(0, Box::pin(aevo));
-    exchanges.insert(1, Box::pin(dydx));
+    let mut markets = Vec::new();
+    for market_config in &config.markets {
+        let executors = config
+            .exchanges
+            .iter()
+            .zip(&mut feeds)
+            .zip(&market_config.symbols)
+            .map(|((exchange_config, (feed, live)), symbol)| {
+                let book = feed.order_book_subscribe(symbol);
+                executor(exchange_config, live.as_ref(), symbol, book, &config.paper)
+            })
+            .collect::<Vec<_>>();
+        #[cfg_attr(not(feature = "sqlite"), allow(unused_mut))]
+        let mut market = Market::new(config, market_config, executors);
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = &config.database_file {
+            let venues = market
+                .executors
+                .iter()
+                .map(|executor| market_config.venue_name(&executor.to_string()))
+                .collect();
+            tracing::info!("storing to {:?}", path);
+            market.arbitrage = market.arbitrage.with_storage(Storage::open(path, venues)?);
+        }
+        markets.push(market);
+    }
 
-    let mut best_prices = vec![(None, None), (None, None)];
+    // The book of every symbol of every exchange, routed to its market and
+    // the index of the exchange in it
+    let mut books = StreamMap::new();
+    let mut routes = HashMap::new();
+    for (index, (feed, _)) in feeds.into_iter().enumerate() {
+        let venue = config.exchanges[index].venue;
+        let symbols = config
+            .markets
+            .iter()
+            .map(|market| market.symbols[index].clone())
+            .collect::<Vec<_>>();
+        for (market, (symbol, stream)) in split_feed(feed, &symbols).into_iter().enumerate() {
+            routes.insert((venue, symbol.clone()), (market, index));
+            books.insert((venue, symbol), stream);
+        }
+    }
     tracing::info!("bot initialized, starting...");
 
-    while let Some((key, update)) = exchanges.next().await {
-        match update {
-            (Some(bid), Some(ask)) => best_prices[key] = (Some(bid), Some(ask)),
-            _ => continue,
+    // Events of every book received while orders were executed, by market
+    // and exchange
+    let mut pending = BTreeMap::<(usize, usize), Vec<MarketEvent>>::new();
+    let mut health = tokio::time::interval_at(Instant::now() + HEALTH_INTERVAL, HEALTH_INTERVAL);
+    tokio::pin!(shutdown);
+    loop {
+        let ((market, key), event) = match pending.pop_first() {
+            Some((route, mut events)) => {
+                let Some(event) = events.pop() else {
+                    continue;
+                };
+                // Only the last book is traded on, the others still count for
+                // the health of the feed
+                for event in &events {
+                    markets[route.0].arbitrage.record_feed(route.1, event);
+                }
+                (route, event)
+            }
+            None => tokio::select! {
+                next = books.next() => match next {
+                    Some((book, event)) => (routes[&book], event),
+                    None => break,
+                },
+                _ = health.tick() => {
+                    let now = SystemTime::now();
+                    for market in &mut markets {
+                        for line in market.arbitrage.feed_health(now).to_string().lines() {
+                            tracing::info!("{}", line);
+                        }
+                    }
+                    continue;
+                }
+                _ = &mut shutdown => {
+                    tracing::info!("shutting down");
+                    break;
+                }
+            },
         };
+        tracing::trace!(
+            "{:?} {} update last_updated {:?} message_id {:?} received at {:?}",
+            event.venue,
+            event.symbol,
+            event.last_updated,
+            event.message_id,
+            event.received_at
+        );
 
-        // The firsts iterations have empty order books. Wait until are filled
-        if best_prices
-            .iter()
-            .all(|price| price.0.is_none() && price.1.is_none())
-        {
-            continue;
+        // Keep the feeds running while the orders are executed, paper orders
+        // are filled against the book at the time of the fill
+        let update = markets[market].update(key, event);
+        tokio::pin!(update);
+        loop {
+            tokio::select! {
+                result = &mut update => {
+                    result?;
+                    break;
+                }
+                Some((book, event)) = books.next() => {
+                    pending.entry(routes[&book]).or_default().push(event);
+                }
+            }
+        }
+    }
+
+    Ok(markets)
+}
+
+/// Split the events of `feed` by symbol, a stream for every one of `symbols`
+/// in their order. The feed is polled by a task of its own until every stream
+/// is dropped.
+fn split_feed(
+    mut feed: FeedStream,
+    symbols: &[Symbol],
+) -> Vec<(Symbol, ReceiverStream<MarketEvent>)> {
+    let mut senders = HashMap::new();
+    let streams = symbols
+        .iter()
+        .map(|symbol| {
+            let (sender, receiver) = mpsc::channel(10000);
+            senders.insert(symbol.clone(), sender);
+            (symbol.clone(), ReceiverStream::new(receiver))
+        })
+        .collect();
+
+    tokio::spawn(async move {
+        while let Some(event) = feed.next().await {
+            let symbol = event.symbol.clone();
+            let Some(sender) = senders.get(&symbol) else {
+                continue;
+            };
+            // Nobody reads the book anymore, stop following it
+            if sender.send(event).await.is_err() {
+                senders.remove(&symbol);
+                feed.order_book_unsubscribe(&symbol);
+                if senders.is_empty() {
+                    return;
+                }
+            }
+        }
+    });
+
+    streams
+}
+
+/// Live execution of the exchange on `live` if configured and available,
+/// paper trading against `book` otherwise
+pub fn executor(
+    config: &ExchangeConfig,
+    live: Option<&LiveExecution>,
+    symbol: &Symbol,
+    book: BookHandle,
+    paper: &PaperConfig,
+) -> Box<dyn OrderExecutor> {
+    let live = live.and_then(|live| live.executor(symbol));
+
+    live.unwrap_or_else(|| {
+        Box::new(PaperExecutor::new(
+            config.venue,
+            config.fee,
+            book,
+            paper.clone(),
+        ))
+    })
+}
+
+/// Arbitrage of one market with its executor on every exchange
+pub struct Market {
+    pub arbitrage: Arbitrage,
+    /// Indexed like the exchanges
+    pub executors: Vec<Box<dyn OrderExecutor>>,
+    /// Span of every log of the market
+    span: tracing::Span,
+}
+
+impl Market {
+    /// The wallets and limits of the market are set from `market`, the rest
+    /// of the strategy from `config`
+    pub fn new(
+        config: &Config,
+        market: &MarketConfig,
+        executors: Vec<Box<dyn OrderExecutor>>,
+    ) -> Self {
+        let portfolio = Portfolio::new(
+            executors
+                .iter()
+                .zip(&market.max_positions)
+                .map(|(executor, max_position)| {
+                    (market.venue_name(&executor.to_string()), *max_position)
+                })
+                .collect(),
+            market.starting_value,
+        );
+        let arbitrage = Arbitrage::new(
+            portfolio,
+            Coordinator::new(config.leg_policy, config.leg_slippage),
+            Rebalancer::new(config.rebalance.clone()),
+            RiskManager::new(market.risk.clone()),
+            config.feed_stale_after,
+        );
+
+        Self {
+            arbitrage,
+            executors,
+            span: market.span(),
         }
+    }
+
+    /// Process a market event of the exchange `key`
+    pub async fn update(
+        &mut self,
+        key: usize,
+        event: MarketEvent,
+    ) -> anyhow::Result<Option<Trade>> {
+        self.arbitrage
+            .update(key, event, &self.executors)
+            .instrument(self.span.clone())
+            .await
+    }
+}
+
+/// Arbitrage executed by `run_strategy`
+#[derive(Debug)]
+pub struct Trade {
+    /// Amount both bought and sold
+    pub amount: Decimal,
+    /// Average prices of every buy and sell, the correcting orders included
+    pub buy_price: Decimal,
+    pub sell_price: Decimal,
+    /// Fees paid on every order, in quote token
+    pub fees: Decimal,
+    /// Amount bought and not sold, negative if more was sold
+    pub exposure: Decimal,
+    pub fills: Vec<LegFill>,
+}
 
-        //Let assume the current price of base token is always the bid price from Aevo
-        let curr_base_price = best_prices[0].0.as_ref().map(|entry| entry.price).unwrap();
+impl Trade {
+    /// Profit of the trade before fees
+    pub fn gross(&self) -> Decimal {
+        self.amount * (self.sell_price - self.buy_price)
+    }
+}
+
+/// Arbitrage state between any number of exchanges, identified by their
+/// index. It is fed the market events of the exchanges and trades on the best
+/// pair when there is an opportunity.
+pub struct Arbitrage {
+    wallets_initialized: bool,
+    /// The wallets were restored from a previous run, they are not split
+    /// again
+    resumed: bool,
+    portfolio: Portfolio,
+    pnl: PnlEngine,
+    /// Last event with both sides of the book of every exchange
+    events: Vec<Option<MarketEvent>>,
+    /// Last mid price of every exchange, kept when its book is not usable
+    mids: Vec<Option<Decimal>>,
+    coordinator: Coordinator,
+    rebalancer: Rebalancer,
+    risk: RiskManager,
+    feeds: FeedMonitor,
+    #[cfg(feature = "sqlite")]
+    storage: Option<Storage>,
+}
 
-        if !wallets_initialized {
-            aevo_wallet.rebalance(curr_base_price);
-            dydx_wallet.rebalance(curr_base_price);
-            wallets_initialized = true;
-            tracing::debug!("wallets rebalanced {:?} {:?}", aevo_wallet, dydx_wallet);
+impl Arbitrage {
+    /// The venues of `portfolio` are the exchanges. Exchanges without any
+    /// update for `stale_after` are not traded on.
+    pub fn new(
+        portfolio: Portfolio,
+        coordinator: Coordinator,
+        rebalancer: Rebalancer,
+        risk: RiskManager,
+        stale_after: Duration,
+    ) -> Self {
+        let venues = portfolio
+            .snapshot()
+            .venues
+            .into_iter()
+            .map(|venue| venue.venue)
+            .collect::<Vec<_>>();
+        Self {
+            wallets_initialized: false,
+            resumed: false,
+            events: vec![None; venues.len()],
+            mids: vec![None; venues.len()],
+            feeds: FeedMonitor::new(venues.clone(), stale_after),
+            pnl: PnlEngine::new(venues),
+            portfolio,
+            coordinator,
+            rebalancer,
+            risk,
+            #[cfg(feature = "sqlite")]
+            storage: None,
         }
+    }
 
-        // We want the smallest best ask because we buy from it
-        let best_ask = best_prices
+    /// Store the history in `storage` and resume from its last wallets
+    #[cfg(feature = "sqlite")]
+    pub fn with_storage(mut self, storage: Storage) -> Self {
+        if let Some(wallets) = storage.last_wallets() {
+            self.portfolio.restore(wallets);
+            self.resumed = true;
+            tracing::info!("wallets resumed {}", self.portfolio.snapshot());
+        }
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn snapshot(&self) -> PortfolioSnapshot {
+        self.portfolio.snapshot()
+    }
+
+    /// Record an event of the exchange `key` that is not traded on
+    pub fn record_feed(&mut self, key: usize, event: &MarketEvent) {
+        self.feeds.record(key, event);
+    }
+
+    pub fn feed_health(&mut self, now: SystemTime) -> HealthReport {
+        self.feeds.report(now)
+    }
+
+    /// Value of every wallet at the last mid price of its exchange
+    fn equity(&self) -> Option<Decimal> {
+        self.mids
             .iter()
             .enumerate()
-            .min_by(|(_, num1), (_, num2)| {
-                num1.1
-                    .clone()
-                    .unwrap()
-                    .price
-                    .cmp(&num2.1.clone().unwrap().price)
+            .map(|(venue, mid)| {
+                let wallet = self.portfolio.wallet(venue);
+                Some(wallet.quote + wallet.base * (*mid)?)
             })
-            .map(|(key, ask)| (key, ask.1.clone().unwrap()))
-            .unwrap();
+            .sum()
+    }
+
+    /// P&L with every exchange marked at its last mid price. `None` until the
+    /// wallets are initialized.
+    pub fn pnl(&self) -> Option<PnlReport> {
+        if !self.wallets_initialized {
+            return None;
+        }
+        let mids = self.mids.iter().copied().collect::<Option<Vec<_>>>()?;
+
+        Some(self.pnl.report(&self.portfolio, &mids))
+    }
+
+    fn log_pnl(&self) {
+        if let Some(report) = self.pnl() {
+            tracing::info!(
+                realized = %report.realized,
+                unrealized = %report.unrealized,
+                fees = %report.fees,
+                equity = %report.equity,
+                "total P&L {:.4}%",
+                report.total_return() * dec!(100)
+            );
+        }
+    }
 
-        // We want the biggest best bid because we sell to it
-        let best_bid = best_prices
+    /// Process a market event of the exchange `key`. `exchanges` are indexed
+    /// like the venues of the portfolio.
+    pub async fn update(
+        &mut self,
+        key: usize,
+        event: MarketEvent,
+        exchanges: &[Box<dyn OrderExecutor>],
+    ) -> anyhow::Result<Option<Trade>> {
+        self.feeds.record(key, &event);
+        match &event.connection {
+            Some(connection @ ConnectionState::Disconnected { .. }) => {
+                tracing::warn!(venue = ?event.venue, "{}", connection);
+            }
+            Some(connection) => tracing::info!(venue = ?event.venue, "{}", connection),
+            None => {}
+        }
+
+        // A book with an empty side, waiting for a new snapshot, halted or
+        // lost with its connection can not be traded on. Forget the last one,
+        // it is stale
+        if event.state != BookState::Live
+            || event.best_bid().is_none()
+            || event.best_ask().is_none()
+        {
+            self.events[key] = None;
+            return Ok(None);
+        }
+        let received_at = event.received_at;
+        self.mids[key] = event.order_book.mid_price();
+        self.events[key] = Some(event);
+
+        // The firsts iterations have empty order books. Wait until are filled
+        let Some(books) = self
+            .events
             .iter()
-            .enumerate()
-            .max_by(|(_, num1), (_, num2)| {
-                num1.0
-                    .clone()
-                    .unwrap()
-                    .price
-                    .cmp(&num2.0.clone().unwrap().price)
-            })
-            .map(|(key, ask)| (key, ask.0.clone().unwrap()))
-            .unwrap();
-
-        // Check best_bid and best_ask are in different exchanges
-        if best_bid.0 == best_ask.0 {
-            continue;
-        }
-
-        if calculate_spread(best_ask.1.price, best_bid.1.price) > dec!(0) {
-            if best_ask.0 == 0 {
-                // Buy on Aevo and sell on DyDx
-                //FIXME: Not working
-                (aevo_wallet, dydx_wallet) = run_strategy(
-                    &aevo,
-                    &dydx,
-                    &best_ask.1,
-                    &best_bid.1,
-                    aevo_wallet,
-                    dydx_wallet,
-                    config.starting_value,
-                    curr_base_price,
-                )
-                .await?;
-            } else {
-                // Buy on DyDx and sell on Aevo
-                //FIXME: Not working
-                (dydx_wallet, aevo_wallet) = run_strategy(
-                    &dydx,
-                    &aevo,
-                    &best_ask.1,
-                    &best_bid.1,
-                    dydx_wallet,
-                    aevo_wallet,
-                    config.starting_value,
-                    curr_base_price,
+            .map(|event| event.as_ref().map(|event| event.order_book.clone()))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return Ok(None);
+        };
+        // Every stored book has both sides
+        let best_prices = books
+            .iter()
+            .map(|book| (book.best_bid().unwrap(), book.best_ask().unwrap()))
+            .collect::<Vec<_>>();
+
+        //Let assume the current price of base token is always the bid price
+        //from the first exchange
+        let curr_base_price = best_prices[0].0.price;
+
+        if !self.wallets_initialized {
+            if !self.resumed {
+                self.portfolio.rebalance(curr_base_price);
+                tracing::debug!("wallets rebalanced {}", self.portfolio.snapshot());
+            }
+            self.pnl.open(&self.portfolio, curr_base_price);
+            self.wallets_initialized = true;
+            #[cfg(feature = "sqlite")]
+            self.store();
+        }
+
+        if let Some(equity) = self.equity() {
+            self.risk.mark(equity, received_at);
+        }
+        // Keep following the market, without trading
+        if self.risk.killed().is_some() {
+            return Ok(None);
+        }
+
+        // A frozen feed would show a quote that is long gone
+        let fresh = (0..books.len())
+            .map(|key| self.feeds.is_fresh(key, received_at))
+            .collect::<Vec<_>>();
+
+        // Checked on every event, not only after a trade: a bot left with no
+        // base on one exchange and no quote on the other does not trade again
+        // until it is rebalanced
+        if fresh.iter().all(|fresh| *fresh) {
+            let venues = exchanges
+                .iter()
+                .zip(&books)
+                .enumerate()
+                .map(|(index, (executor, book))| LegVenue {
+                    index,
+                    executor: executor.as_ref(),
+                    book,
+                })
+                .collect::<Vec<_>>();
+            if self
+                .rebalancer
+                .run(
+                    &mut self.portfolio,
+                    &mut self.pnl,
+                    &mut self.risk,
+                    &venues,
+                    received_at,
                 )
-                .await?;
+                .await
+            {
+                #[cfg(feature = "sqlite")]
+                self.store();
+                self.log_pnl();
             }
         }
+
+        // Evaluate every ordered pair, buying on the ask of the first and
+        // selling on the bid of the second. Keep the pair with the biggest
+        // spread net of the fees of both exchanges
+        let mut best_pair = None;
+        for (buy, (_, ask)) in best_prices.iter().enumerate() {
+            for (sell, (bid, _)) in best_prices.iter().enumerate() {
+                if buy == sell || !fresh[buy] || !fresh[sell] {
+                    continue;
+                }
+
+                let spread = calculate_spread(ask.price, bid.price)
+                    - exchanges[buy].fee()
+                    - exchanges[sell].fee();
+                if spread > dec!(0)
+                    && best_pair.is_none_or(|(_, _, best_spread)| spread > best_spread)
+                {
+                    best_pair = Some((buy, sell, spread));
+                }
+            }
+        }
+
+        let Some((buy, sell, spread)) = best_pair else {
+            return Ok(None);
+        };
+        tracing::debug!(
+            buy = %exchanges[buy],
+            sell = %exchanges[sell],
+            spread = %spread,
+            "opportunity"
+        );
+        #[cfg(feature = "sqlite")]
+        if let Some(storage) = &self.storage {
+            storage.store_opportunity(
+                buy,
+                sell,
+                best_prices[buy].1.price,
+                best_prices[sell].0.price,
+                spread,
+            );
+        }
+
+        let trade = run_strategy(
+            &self.coordinator,
+            &mut self.risk,
+            received_at,
+            &mut self.portfolio,
+            LegVenue {
+                index: buy,
+                executor: exchanges[buy].as_ref(),
+                book: &books[buy],
+            },
+            LegVenue {
+                index: sell,
+                executor: exchanges[sell].as_ref(),
+                book: &books[sell],
+            },
+        )
+        .await?;
+
+        if let Some(trade) = &trade {
+            let round_trip = self.pnl.record_round_trip(trade);
+            tracing::info!(
+                amount = %round_trip.amount,
+                exposure = %round_trip.exposure,
+                fees = %round_trip.fees,
+                realized = %round_trip.realized,
+                "round trip closed"
+            );
+
+            #[cfg(feature = "sqlite")]
+            self.store();
+
+            self.log_pnl();
+            tracing::info!(
+                "================================================================================"
+            );
+            tracing::info!("");
+        }
+
+        Ok(trade)
     }
+}
 
-    Ok(())
+#[cfg(feature = "sqlite")]
+impl Arbitrage {
+    /// Store the fills recorded since the last call and the wallets
+    fn store(&mut self) {
+        if let Some(storage) = &mut self.storage {
+            storage.store_ledger(self.pnl.ledger());
+            storage.store_wallets(self.portfolio.wallets());
+        }
+    }
 }
 
 fn calculate_spread(ask: Decimal, bid: Decimal) -> Decimal {
@@ -132,71 +643,126 @@ fn calculate_spread(ask: Decimal, bid: Decimal) -> Decimal {
     num / ((bid + ask) / dec!(2))
 }
 
-fn calculate_pl(
-    starting_value: Decimal,
-    curr_price: Decimal,
-    wallet1: &Wallet,
-    wallet2: &Wallet,
-) -> (Decimal, Decimal) {
-    // This is not the standard formula for calculating P&L
-    let starting_value = starting_value * dec!(2);
-    let total =
-        wallet1.quote + wallet2.quote + wallet1.base * curr_price + wallet2.base * curr_price;
-    let pl = (total / starting_value) - dec!(1);
-
-    (pl, total)
-}
-
-fn is_profitable(
+fn calculate_net_profit(
     amount: Decimal,
     sell_price: Decimal,
     buy_price: Decimal,
     sell_fee: Decimal,
     buy_fee: Decimal,
-) -> bool {
+) -> Decimal {
     let sell = amount * sell_price;
     let buy = amount * buy_price;
 
-    sell - buy - sell * sell_fee - buy * buy_fee > dec!(0)
+    sell - buy - sell * sell_fee - buy * buy_fee
+}
+
+/// Find the amount, up to `max_amount`, that maximises the profit net of fees
+/// of buying on `buy_book` and selling on `sell_book`. Returns the amount
+/// with the volume weighted buy and sell prices.
+fn find_best_size(
+    buy_book: &OrderBook,
+    sell_book: &OrderBook,
+    buy_fee: Decimal,
+    sell_fee: Decimal,
+    max_amount: Decimal,
+) -> Option<(Decimal, Decimal, Decimal)> {
+    // The profit is linear between two levels, so the best amount is either
+    // the end of a level or the maximum amount
+    let mut candidates = vec![max_amount];
+    for levels in [
+        Box::new(buy_book.asks()) as Box<dyn Iterator<Item = BookEntry>>,
+        Box::new(sell_book.bids()),
+    ] {
+        let mut cumulative = dec!(0);
+        for level in levels {
+            cumulative += level.amount;
+            if cumulative >= max_amount {
+                break;
+            }
+            candidates.push(cumulative);
+        }
+    }
+
+    candidates
+        .into_iter()
+        .filter(|amount| !amount.is_zero())
+        .filter_map(|amount| {
+            let buy_price = buy_book.buy_price(amount)?;
+            let sell_price = sell_book.sell_price(amount)?;
+            let profit = calculate_net_profit(amount, sell_price, buy_price, sell_fee, buy_fee);
+            Some((profit, amount, buy_price, sell_price))
+        })
+        .filter(|(profit, ..)| *profit > dec!(0))
+        .max_by(|(profit1, ..), (profit2, ..)| profit1.cmp(profit2))
+        .map(|(_, amount, buy_price, sell_price)| (amount, buy_price, sell_price))
 }
 
 async fn run_strategy(
-    exc1: &impl Exchange,
-    exc2: &impl Exchange,
-    exc1_prices: &BookEntry,
-    exc2_prices: &BookEntry,
-    exc1_wallet: Wallet,
-    exc2_wallet: Wallet,
-    starting_value: Decimal,
-    ticker_base_price: Decimal,
-) -> anyhow::Result<(Wallet, Wallet)> {
+    coordinator: &Coordinator,
+    risk: &mut RiskManager,
+    now: SystemTime,
+    portfolio: &mut Portfolio,
+    buy: LegVenue<'_>,
+    sell: LegVenue<'_>,
+) -> anyhow::Result<Option<Trade>> {
+    let (exc1, exc2) = (buy.executor, sell.executor);
+    let (exc1_book, exc2_book) = (buy.book, sell.book);
+
     // We are going to buy on exc1 and sell on exc2
-    // Find the maximum amount we can trade. The amount is calculated as the
-    // minimum between exc1 best ask, exc2 best bid and the amount of the base
-    // token in the exc1 wallet.
-    let mut amount = exc1_prices
-        .amount
-        .min(exc2_prices.amount.min(exc2_wallet.base));
-
-    // We need to find out if we have money to trade. Otherwise, use the whole
-    // budget.
-    let max_quote_amount = exc1_wallet.quote.min(amount * exc1_prices.price);
-    amount = max_quote_amount / exc1_prices.price;
-
-    if amount.is_zero()
-        || !is_profitable(
-            amount,
-            exc2_prices.price,
-            exc1_prices.price,
-            exc2.fee(),
-            exc1.fee(),
-        )
+    // Find the maximum amount we can trade. The amount is limited by the
+    // depth of exc1 asks and exc2 bids, the amount of the base token
+    // available on exc2, the amount of quote token available on exc1 and the
+    // position limit of exc1.
+    let depth = |levels: &mut dyn Iterator<Item = BookEntry>| {
+        levels.map(|level| level.amount).sum::<Decimal>()
+    };
+    let mut max_amount = depth(&mut exc1_book.asks())
+        .min(depth(&mut exc2_book.bids()))
+        .min(portfolio.balance(sell.index, Asset::Base).available())
+        // The fee is paid on top of the quote token spent
+        .min(exc1_book.buy_amount(
+            portfolio.balance(buy.index, Asset::Quote).available() / (dec!(1) + exc1.fee()),
+        ));
+    if let Some(capacity) = portfolio.position_capacity(buy.index) {
+        max_amount = max_amount.min(capacity);
+    }
+    let Some(buy_mid) = exc1_book.mid_price() else {
+        return Ok(None);
+    };
+    let buy_position = portfolio.wallet(buy.index).base;
+    if let Some(limit) = risk.max_amount(buy_position, buy_mid, |quote| exc1_book.buy_amount(quote))
     {
-        return Ok((exc1_wallet, exc2_wallet));
+        max_amount = max_amount.min(limit);
     }
 
-    let exc1_wallet = exc1.buy(amount, exc1_prices.price, exc1_wallet).await?;
-    let exc2_wallet = exc2.sell(amount, exc2_prices.price, exc2_wallet).await?;
+    let Some((amount, buy_price, sell_price)) =
+        find_best_size(exc1_book, exc2_book, exc1.fee(), exc2.fee(), max_amount)
+    else {
+        return Ok(None);
+    };
+
+    // Limit prices at the last level needed, the orders can walk the book
+    let buy_limit = exc1_book
+        .take_asks(amount)
+        .last()
+        .map_or(buy_price, |level| level.price);
+    let sell_limit = exc2_book
+        .take_bids(amount)
+        .last()
+        .map_or(sell_price, |level| level.price);
+
+    let intent = TradeIntent {
+        venue: buy.index,
+        side: Side::Buy,
+        amount,
+        price: buy_price,
+        position: buy_position,
+        mid: buy_mid,
+    };
+    if let Err(breach) = risk.check(&intent, now) {
+        tracing::warn!(breach = %breach, "trade blocked by the risk limits");
+        return Ok(None);
+    }
 
     tracing::info!(
         "================================================================================"
@@ -205,28 +771,132 @@ async fn run_strategy(
         "BUY on {} amount: {:.4} price: {:.4}",
         exc1,
         amount,
-        exc1_prices.price
+        buy_price
     );
     tracing::info!(
         "SELL on {} amount {:.4} price {:.4}",
         exc2,
         amount,
-        exc2_prices.price
+        sell_price
     );
-    tracing::info!("{} wallet {}", exc1, exc1_wallet);
-    tracing::info!("{} wallet {}", exc2, exc2_wallet);
-
-    let (pl, total) = calculate_pl(
-        starting_value,
-        ticker_base_price,
-        &exc1_wallet,
-        &exc2_wallet,
-    );
-    tracing::info!("total balance {}. New P&L {:.4}%", total, pl);
-    tracing::info!(
-        "================================================================================"
-    );
-    tracing::info!("");
 
-    Ok((exc1_wallet, exc2_wallet))
+    let trade = coordinator
+        .execute(
+            portfolio, risk, now, buy, sell, amount, buy_limit, sell_limit,
+        )
+        .await;
+
+    tracing::info!("{} wallet {}", exc1, portfolio.wallet(buy.index));
+    tracing::info!("{} wallet {}", exc2, portfolio.wallet(sell.index));
+
+    Ok(trade)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{frames, MockServer};
+
+    fn books() -> (OrderBook, OrderBook) {
+        let buy_book = OrderBook::with_levels(
+            &[(dec!(99), dec!(1))],
+            &[
+                (dec!(100), dec!(1)),
+                (dec!(101), dec!(1)),
+                (dec!(103), dec!(5)),
+            ],
+        );
+        let sell_book = OrderBook::with_levels(
+            &[
+                (dec!(102), dec!(1)),
+                (dec!(101.5), dec!(1)),
+                (dec!(99), dec!(5)),
+            ],
+            &[(dec!(104), dec!(1))],
+        );
+        (buy_book, sell_book)
+    }
+
+    #[test]
+    fn best_size_ends_a_level() {
+        let (buy_book, sell_book) = books();
+
+        // Two levels make 2.5 of profit, one 2 and three a loss
+        assert_eq!(
+            find_best_size(&buy_book, &sell_book, dec!(0), dec!(0), dec!(10)),
+            Some((dec!(2), dec!(100.5), dec!(101.75)))
+        );
+    }
+
+    #[test]
+    fn best_size_is_capped_by_the_maximum_amount() {
+        let (buy_book, sell_book) = books();
+
+        assert_eq!(
+            find_best_size(&buy_book, &sell_book, dec!(0), dec!(0), dec!(0.5)),
+            Some((dec!(0.5), dec!(100), dec!(102)))
+        );
+    }
+
+    #[test]
+    fn no_size_without_profit_net_of_fees() {
+        let (buy_book, sell_book) = books();
+
+        assert_eq!(
+            find_best_size(&buy_book, &sell_book, dec!(0.01), dec!(0.01), dec!(10)),
+            None
+        );
+        assert_eq!(
+            find_best_size(&sell_book, &buy_book, dec!(0), dec!(0), dec!(10)),
+            None
+        );
+        assert_eq!(
+            find_best_size(&buy_book, &sell_book, dec!(0), dec!(0), dec!(0)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn bot_trades_against_the_mock_servers() {
+        let mut config = Config::test();
+        let mut servers = Vec::new();
+        // DyDx quotes 200 above Aevo all along: every trade buys on Aevo and
+        // sells on DyDx at a profit
+        let mids = [dec!(60000), dec!(60200)];
+        for (index, exchange) in config.exchanges.iter_mut().enumerate() {
+            let server = MockServer::bind("127.0.0.1:0").await.unwrap();
+            exchange.url = server.url().unwrap();
+            let books = [(config.markets[0].symbols[index].clone(), mids[index])];
+            servers.push(server.serve(
+                frames(exchange.venue, &books, 0, 10),
+                Duration::from_millis(1),
+            ));
+        }
+
+        // Every frame is sent well before the shutdown
+        let shutdown = tokio::time::sleep(Duration::from_secs(2));
+        let markets = run_bot(&config, shutdown).await.unwrap();
+        for server in servers {
+            assert!(!server.received().is_empty());
+        }
+
+        let arbitrage = &markets[0].arbitrage;
+        let pnl = arbitrage.pnl().unwrap();
+        assert!(pnl.round_trips > 0);
+        assert!(pnl.gross > dec!(0));
+        assert_eq!(pnl.fees, dec!(0));
+        // The wallets account for the P&L of the trades
+        assert_eq!(
+            (pnl.equity - pnl.starting_equity).round_dp(8),
+            pnl.total().round_dp(8)
+        );
+        // Bought on Aevo, sold on DyDx and every order closed
+        let venues = arbitrage.snapshot().venues;
+        assert!(venues[0].base.total > venues[1].base.total);
+        for venue in venues {
+            assert_eq!(venue.base.reserved, dec!(0));
+            assert_eq!(venue.quote.reserved, dec!(0));
+            assert!(venue.base.total >= dec!(0) && venue.quote.total >= dec!(0));
+        }
+    }
 }
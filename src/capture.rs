@@ -0,0 +1,306 @@
+//! Record and replay of raw exchange WebSocket frames
+//!
+//! Captures are JSONL files, one [`CaptureEntry`] per line. A single file can
+//! hold the frames of several exchanges, the `exchange` field tells them apart.
+//! Captures are read one line at a time, they can be larger than the memory.
+
+use std::{
+    io::{BufRead, BufReader, Lines},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+    time::Instant,
+};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CaptureEntry {
+    /// Receive time in milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub exchange: String,
+    pub frame: String,
+}
+
+/// Appends raw frames to a capture file. Cloning the recorder shares the same
+/// file.
+#[derive(Clone)]
+pub struct Recorder {
+    sender: mpsc::UnboundedSender<CaptureEntry>,
+}
+
+impl Recorder {
+    pub async fn create(path: &Path) -> anyhow::Result<Self> {
+        let file = File::create(path).await?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(write_entries(BufWriter::new(file), receiver));
+
+        Ok(Self { sender })
+    }
+
+    pub fn record(&self, exchange: &str, frame: &str) {
+        let entry = CaptureEntry {
+            timestamp: now_millis(),
+            exchange: exchange.to_string(),
+            frame: frame.to_string(),
+        };
+        // The writer only stops if the file is broken, recording is best effort
+        let _ = self.sender.send(entry);
+    }
+}
+
+async fn write_entries(
+    mut writer: BufWriter<File>,
+    mut receiver: mpsc::UnboundedReceiver<CaptureEntry>,
+) {
+    while let Some(entry) = receiver.recv().await {
+        let Ok(mut line) = serde_json::to_string(&entry) else {
+            continue;
+        };
+        line.push('\n');
+        // Flush every line so a crash does not lose the frames leading to it
+        let result = async {
+            writer.write_all(line.as_bytes()).await?;
+            writer.flush().await
+        }
+        .await;
+        if let Err(err) = result {
+            tracing::error!("failed to write capture: {}", err);
+            return;
+        }
+    }
+}
+
+/// Entries of a capture file, in the order they were recorded
+pub struct CaptureReader {
+    lines: Lines<BufReader<std::fs::File>>,
+    /// Only the entries of this exchange are read, if set
+    exchange: Option<String>,
+}
+
+impl CaptureReader {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            lines: BufReader::new(std::fs::File::open(path)?).lines(),
+            exchange: None,
+        })
+    }
+
+    /// Skip the entries of the other exchanges
+    pub fn exchange(mut self, exchange: &str) -> Self {
+        self.exchange = Some(exchange.to_string());
+        self
+    }
+}
+
+impl Iterator for CaptureReader {
+    type Item = anyhow::Result<CaptureEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = match serde_json::from_str::<CaptureEntry>(&line) {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err.into())),
+            };
+            if self
+                .exchange
+                .as_ref()
+                .is_some_and(|exchange| *exchange != entry.exchange)
+            {
+                continue;
+            }
+            return Some(Ok(entry));
+        }
+    }
+}
+
+/// Send the frames of `exchange` found in the capture at `path` to `channel`.
+/// Frames are parsed with `parse` and keep the timing they were recorded
+/// with. The timing is relative to the first frame of the file, so replaying
+/// several exchanges from the same capture preserves their interleaving.
+pub async fn replay<T>(
+    path: PathBuf,
+    exchange: &str,
+    parse: impl Fn(&str) -> Option<T>,
+    channel: mpsc::Sender<T>,
+) {
+    let first = CaptureReader::open(&path).and_then(|mut reader| reader.next().transpose());
+    let entries = CaptureReader::open(&path).map(|reader| reader.exchange(exchange));
+    let (first_timestamp, entries) = match (first, entries) {
+        (Ok(Some(first)), Ok(entries)) => (first.timestamp, entries),
+        (Ok(None), _) => return,
+        (Err(err), _) | (_, Err(err)) => {
+            tracing::error!("failed to read capture {:?}: {}", path, err);
+            return;
+        }
+    };
+    let start = Instant::now();
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                tracing::error!("failed to read capture {:?}: {}", path, err);
+                return;
+            }
+        };
+        let offset = entry.timestamp.saturating_sub(first_timestamp);
+        tokio::time::sleep_until(start + Duration::from_millis(offset)).await;
+
+        if let Some(msg) = parse(&entry.frame) {
+            if channel.send(msg).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    tracing::info!("{} replay finished", exchange);
+}
+
+/// Current time in milliseconds since the unix epoch
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    /// Capture file of the test `name`, with the lines `lines`
+    fn capture(name: &str, lines: &[String]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("capture-{}-{}.jsonl", std::process::id(), name));
+        let mut file = std::fs::File::create(&path).unwrap();
+        for line in lines {
+            writeln!(file, "{}", line).unwrap();
+        }
+        path
+    }
+
+    fn entry(timestamp: u64, exchange: &str, frame: &str) -> String {
+        serde_json::to_string(&CaptureEntry {
+            timestamp,
+            exchange: exchange.to_string(),
+            frame: frame.to_string(),
+        })
+        .unwrap()
+    }
+
+    fn frames(reader: CaptureReader) -> Vec<String> {
+        reader.map(|entry| entry.unwrap().frame).collect()
+    }
+
+    #[tokio::test]
+    async fn recorded_frames_are_read_back() {
+        let path =
+            std::env::temp_dir().join(format!("capture-{}-recorded.jsonl", std::process::id()));
+        let recorder = Recorder::create(&path).await.unwrap();
+        recorder.record("Aevo", "{\"a\": 1}");
+        recorder.record("DyDx", "b\nc");
+        recorder.record("Aevo", "");
+
+        // Written in the background, one line at a time
+        let entries = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let entries = CaptureReader::open(&path)
+                    .unwrap()
+                    .collect::<anyhow::Result<Vec<_>>>()
+                    .unwrap();
+                if entries.len() == 3 {
+                    return entries;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let frames = entries
+            .iter()
+            .map(|entry| (entry.exchange.as_str(), entry.frame.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            frames,
+            [("Aevo", "{\"a\": 1}"), ("DyDx", "b\nc"), ("Aevo", "")]
+        );
+        assert!(entries
+            .windows(2)
+            .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn readers_keep_the_frames_of_their_exchange() {
+        let path = capture(
+            "filter",
+            &[
+                entry(1, "Aevo", "1"),
+                entry(2, "DyDx", "2"),
+                String::new(),
+                entry(3, "Aevo", "3"),
+            ],
+        );
+
+        assert_eq!(frames(CaptureReader::open(&path).unwrap()), ["1", "2", "3"]);
+        let reader = CaptureReader::open(&path).unwrap().exchange("Aevo");
+        assert_eq!(frames(reader), ["1", "3"]);
+        let reader = CaptureReader::open(&path).unwrap().exchange("Binance");
+        assert!(frames(reader).is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unreadable_lines_are_errors() {
+        let path = capture("unreadable", &[entry(1, "Aevo", "1"), "{".to_string()]);
+
+        let mut reader = CaptureReader::open(&path).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().frame, "1");
+        assert!(reader.next().unwrap().is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_keeps_the_timing_of_the_capture() {
+        let path = capture(
+            "replay",
+            &[
+                entry(1000, "DyDx", "0"),
+                entry(1100, "Aevo", "1"),
+                entry(1150, "DyDx", "2"),
+                entry(1400, "Aevo", "3"),
+            ],
+        );
+        let (sender, mut receiver) = mpsc::channel(10);
+        let start = Instant::now();
+        tokio::spawn(replay(
+            path.clone(),
+            "Aevo",
+            |frame| Some(frame.to_string()),
+            sender,
+        ));
+
+        // Relative to the first frame of the file, of any exchange
+        let mut frames = Vec::new();
+        while let Some(frame) = receiver.recv().await {
+            frames.push((frame, start.elapsed().as_millis()));
+        }
+        assert_eq!(frames, [("1".to_string(), 100), ("3".to_string(), 400)]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
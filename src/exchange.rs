@@ -1,154 +1,362 @@
 //! Exchange implementations
 
 mod aevo;
+mod book;
+mod connection;
 mod dydx;
+mod paper;
+mod registry;
 
-use std::{convert, fmt::Display, str::FromStr};
+use std::{convert, fmt::Display, path::PathBuf, str::FromStr, sync::Arc, time::SystemTime};
 
-pub use aevo::Aevo;
+pub use aevo::{checksum as aevo_checksum, Aevo};
+#[cfg(feature = "aevo-live")]
+pub use aevo::{
+    sign as aevo_sign, sign_order as aevo_sign_order, signing_key as aevo_signing_key,
+    OrderRequest as AevoOrderRequest,
+};
 use async_trait::async_trait;
+use book::OrderBookMessage;
+pub use book::{BookEntry, BookError, OrderBook};
+pub use connection::{ConnectionConfig, ConnectionState};
 pub use dydx::DyDx;
+pub use paper::{PaperConfig, PaperExecutor};
 
+use anyhow::anyhow;
 use futures_util::Stream;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
-use serde::Deserialize;
+#[cfg(any(feature = "aevo-live", feature = "dydx-live"))]
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
 
-#[async_trait]
-pub trait Exchange: Stream + Display + Send + Sync {
-    fn order_book_subscribe(&self, symbol: &Symbol);
-    fn fee(&self) -> Decimal;
+use crate::capture::Recorder;
 
-    async fn buy(&self, amount: Decimal, price: Decimal, wallet: Wallet) -> anyhow::Result<Wallet> {
-        // We are buying base token for quote token
-        let fee = price * amount * self.fee();
-        let new_base_amount = wallet.base + amount;
-        let new_quote_amount = wallet.quote - (price * amount) - fee;
-        // Sometimes, the first trade brings the amount below 0. This is
-        // probably because we do not manage the number of digit. Add a check
-        // for simplicity
-        let new_quote_amount = new_quote_amount.max(dec!(0));
-        self.handle_persistent_buy(amount, price).await?;
+pub type DynFeed = dyn MarketFeed<Item = MarketEvent>;
+pub type FeedStream = Box<DynFeed>;
 
-        Ok(Wallet {
-            base: new_base_amount,
-            quote: new_quote_amount,
-        })
-    }
+/// Order book update emitted by the market data feeds
+#[derive(Clone, Debug)]
+pub struct MarketEvent {
+    pub venue: Venue,
+    pub symbol: Symbol,
+    /// The whole book after the update. Later updates do not modify it
+    pub order_book: Arc<OrderBook>,
+    /// Aevo `last_updated` timestamp, in nanoseconds
+    pub last_updated: Option<u64>,
+    /// DyDx `message_id`
+    pub message_id: Option<usize>,
+    /// When the update was received from the exchange
+    pub received_at: SystemTime,
+    pub state: BookState,
+    /// New state of the connection to the exchange, if the event reports it
+    pub connection: Option<ConnectionState>,
+}
 
-    async fn sell(
-        &self,
-        amount: Decimal,
-        price: Decimal,
-        wallet: Wallet,
-    ) -> anyhow::Result<Wallet> {
-        // We are selling base token for quote token
-        let fee = price * amount * self.fee();
-        let new_base_amount = wallet.base - amount;
-        let new_quote_amount = wallet.quote + (price * amount) - fee;
-        self.handle_persistent_sell(amount, price).await?;
+impl MarketEvent {
+    pub fn best_bid(&self) -> Option<&BookEntry> {
+        self.order_book.best_bid()
+    }
 
-        Ok(Wallet {
-            base: new_base_amount,
-            quote: new_quote_amount,
-        })
+    pub fn best_ask(&self) -> Option<&BookEntry> {
+        self.order_book.best_ask()
     }
+}
 
-    // The methods are here to handle fake trades. A real exchange implementation
-    // should not have it
-    async fn handle_persistent_buy(&self, amount: Decimal, price: Decimal) -> anyhow::Result<()>;
-    async fn handle_persistent_sell(&self, amount: Decimal, price: Decimal) -> anyhow::Result<()>;
+/// Whether the local book of a feed can be traded on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BookState {
+    #[default]
+    Live,
+    /// The book was dropped and waits for a new snapshot
+    Resyncing,
+    /// The book was dropped for good after breaking its invariants
+    Halted,
 }
 
-#[derive(Debug)]
-pub struct OrderBook {
-    pub bids: Vec<BookEntry>,
-    pub asks: Vec<BookEntry>,
+/// What a feed does when its book breaks an invariant, see [`BookError`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ViolationPolicy {
+    /// Drop the book and subscribe again for a new snapshot
+    #[default]
+    Resync,
+    /// Drop the book and stop trading on the exchange
+    Halt,
 }
 
-impl OrderBook {
-    pub fn new() -> Self {
-        Self {
-            bids: Vec::new(),
-            asks: Vec::new(),
+impl FromStr for ViolationPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "resync" => Ok(ViolationPolicy::Resync),
+            "halt" => Ok(ViolationPolicy::Halt),
+            _ => Err(anyhow!("unknown book violation policy {}", s)),
         }
     }
+}
 
-    pub fn best_ask(&self) -> Option<&BookEntry> {
-        self.asks.first()
-    }
+/// Supported exchanges
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Venue {
+    Aevo,
+    DyDx,
+}
 
-    pub fn best_bid(&self) -> Option<&BookEntry> {
-        self.bids.first()
+impl Venue {
+    /// Market data feed of the venue, reacting to invalid books with
+    /// `policy`. A live feed connects at once
+    pub fn feed(self, source: FeedSource, policy: ViolationPolicy) -> FeedStream {
+        match self {
+            Venue::Aevo => Box::new(Aevo::new(source, policy)),
+            Venue::DyDx => Box::new(DyDx::new(source, policy)),
+        }
     }
 
-    fn update(&mut self, update: OrderBookMessage) {
-        match update {
-            OrderBookMessage::Snapshot { bids, asks } => {
-                self.bids = bids;
-                self.asks = asks;
+    /// Live order execution on the venue, shared by all its markets. `None`
+    /// if the bot was built without the backend of the venue or if `source`
+    /// is not live. A DyDx backend follows its subaccount at once
+    #[cfg(any(feature = "aevo-live", feature = "dydx-live"))]
+    pub fn live_execution(
+        self,
+        fee: Decimal,
+        execution: ExecutionConfig,
+        source: &FeedSource,
+    ) -> Option<LiveExecution> {
+        // Real orders are not sent on replayed books
+        if !matches!(source, FeedSource::Live { .. }) {
+            tracing::warn!(
+                "{:?} live execution needs a live feed, trades are simulated",
+                self
+            );
+            return None;
+        }
+
+        match self {
+            #[cfg(feature = "aevo-live")]
+            Venue::Aevo => Some(LiveExecution::Aevo {
+                fee,
+                config: execution,
+            }),
+            #[cfg(feature = "dydx-live")]
+            Venue::DyDx => {
+                // Fills are read from the indexer serving the order book
+                let FeedSource::Live {
+                    url, connection, ..
+                } = source
+                else {
+                    return None;
+                };
+                let account =
+                    dydx::DyDxAccount::follow(&execution.key, url.clone(), connection.clone());
+                Some(LiveExecution::DyDx {
+                    fee,
+                    config: execution,
+                    account,
+                })
             }
-            OrderBookMessage::BidUpdate(entry) => {
-                // Remove the entry
-                if entry.amount.is_zero() {
-                    if let Some(index) = self.bids.iter().position(|bid| bid.price == entry.price) {
-                        self.bids.remove(index);
-                    }
-                } else {
-                    // Update the entry
-                    if let Some(index) = self.bids.iter().position(|bid| bid.price == entry.price) {
-                        self.bids[index].amount = entry.amount;
-                    } else {
-                        // New entry
-                        self.bids.push(entry);
-
-                        self.bids
-                            .sort_by(|val1, val2| val2.price.partial_cmp(&val1.price).unwrap())
-                    }
-                }
+            #[cfg(not(all(feature = "aevo-live", feature = "dydx-live")))]
+            _ => {
+                tracing::warn!(
+                    "{:?} live execution not available, trades are simulated",
+                    self
+                );
+                None
             }
+        }
+    }
+}
 
-            OrderBookMessage::AskUpdate(entry) => {
-                // Remove the entry
-                if entry.amount.is_zero() {
-                    if let Some(index) = self.asks.iter().position(|ask| ask.price == entry.price) {
-                        self.asks.remove(index);
-                    }
-                } else {
-                    // Update the entry
-                    if let Some(index) = self.asks.iter().position(|ask| ask.price == entry.price) {
-                        self.asks[index].amount = entry.amount;
-                    } else {
-                        // New entry
-                        self.asks.push(entry);
-
-                        self.asks
-                            .sort_by(|val1, val2| val1.price.partial_cmp(&val2.price).unwrap());
+/// Live execution backend of an exchange, see [`Venue::live_execution`].
+/// There is none without the live features
+pub enum LiveExecution {
+    #[cfg(feature = "aevo-live")]
+    Aevo {
+        fee: Decimal,
+        config: ExecutionConfig,
+    },
+    /// The subaccount is followed for the fills of every market
+    #[cfg(feature = "dydx-live")]
+    DyDx {
+        fee: Decimal,
+        config: ExecutionConfig,
+        account: dydx::DyDxAccount,
+    },
+}
+
+impl LiveExecution {
+    /// Order execution of `symbol`, `None` if it can not be traded live
+    #[cfg(any(feature = "aevo-live", feature = "dydx-live"))]
+    pub fn executor(&self, symbol: &Symbol) -> Option<Box<dyn OrderExecutor>> {
+        match self {
+            #[cfg(feature = "aevo-live")]
+            LiveExecution::Aevo { fee, config } => {
+                match aevo::AevoClient::new(config.clone(), symbol.clone(), *fee) {
+                    Ok(client) => Some(Box::new(client)),
+                    Err(err) => {
+                        tracing::error!(
+                            "Aevo live execution not available, trades are simulated: {}",
+                            err
+                        );
+                        None
                     }
                 }
             }
+            #[cfg(feature = "dydx-live")]
+            LiveExecution::DyDx {
+                fee,
+                config,
+                account,
+            } => Some(Box::new(dydx::DyDxClient::new(
+                config.clone(),
+                account.clone(),
+                symbol.clone(),
+                *fee,
+            ))),
         }
     }
+
+    #[cfg(not(any(feature = "aevo-live", feature = "dydx-live")))]
+    pub fn executor(&self, _symbol: &Symbol) -> Option<Box<dyn OrderExecutor>> {
+        match *self {}
+    }
 }
 
-#[derive(Clone, Debug)]
-enum OrderBookMessage {
-    Snapshot {
-        bids: Vec<BookEntry>,
-        asks: Vec<BookEntry>,
+impl FromStr for Venue {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "aevo" => Ok(Venue::Aevo),
+            "dydx" => Ok(Venue::DyDx),
+            _ => Err(anyhow!("unknown exchange {}", s)),
+        }
+    }
+}
+
+/// Order book stream of an exchange, with the events of every subscribed
+/// symbol. All the subscriptions share the connection of the exchange.
+pub trait MarketFeed: Stream + Display + Send + Sync + Unpin {
+    /// Subscribe to the order book of `symbol`. Returns a handle to read and
+    /// update its local book
+    fn order_book_subscribe(&mut self, symbol: &Symbol) -> BookHandle;
+    /// Drop the order book of `symbol`, it has no more events
+    fn order_book_unsubscribe(&mut self, symbol: &Symbol);
+    /// Process a raw frame as if it was received from the exchange at
+    /// `received_at`. Used with [`FeedSource::Manual`], the time of the
+    /// pushed frames is the clock of the feed
+    fn push_frame(&self, frame: &str, received_at: SystemTime) -> anyhow::Result<()>;
+}
+
+/// Order placement on an exchange
+#[async_trait]
+pub trait OrderExecutor: Display + Send + Sync {
+    fn fee(&self) -> Decimal;
+
+    /// Place an order of `amount` at the limit `price`. Returns what was
+    /// filled.
+    async fn execute_order(
+        &self,
+        side: Side,
+        amount: Decimal,
+        price: Decimal,
+    ) -> anyhow::Result<Fill>;
+}
+
+/// Handle to the local order book of a symbol of a feed
+#[derive(Clone)]
+pub struct BookHandle {
+    symbol: Symbol,
+    updates: mpsc::Sender<(Symbol, OrderBookMessage)>,
+    book: watch::Receiver<Arc<OrderBook>>,
+}
+
+impl BookHandle {
+    /// Last order book of the feed
+    pub fn current(&self) -> Arc<OrderBook> {
+        self.book.borrow().clone()
+    }
+
+    /// Update the book without the exchange. Applied before the pending
+    /// exchange frames
+    async fn send(&self, update: OrderBookMessage) -> anyhow::Result<()> {
+        self.updates.send((self.symbol.clone(), update)).await?;
+        Ok(())
+    }
+}
+
+/// Where a feed gets its order book frames from
+#[derive(Clone)]
+pub enum FeedSource {
+    /// Connect to the exchange WebSocket. If a recorder is set every raw frame
+    /// received is captured.
+    Live {
+        url: String,
+        recorder: Option<Recorder>,
+        connection: ConnectionConfig,
     },
-    AskUpdate(BookEntry),
-    BidUpdate(BookEntry),
+    /// Replay the frames of a capture file
+    Replay(PathBuf),
+    /// Nothing is spawned, frames are pushed by the caller with `push_frame`
+    Manual,
 }
 
-#[derive(Clone, Deserialize, Debug)]
-pub struct BookEntry {
-    pub price: Decimal,
-    #[serde(alias = "size")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+/// Result of an order executed on an exchange
+#[derive(Clone, Debug)]
+pub struct Fill {
+    /// Filled amount, can be less than the order amount
     pub amount: Decimal,
+    /// Average fill price
+    pub price: Decimal,
 }
 
-#[derive(Clone)]
+#[cfg(any(feature = "aevo-live", feature = "dydx-live"))]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub enum TimeInForce {
+    /// Limit order resting on the book until filled or cancelled
+    #[serde(rename = "GTC")]
+    GoodTillCancel,
+    #[default]
+    #[serde(rename = "IOC")]
+    ImmediateOrCancel,
+}
+
+#[cfg(any(feature = "aevo-live", feature = "dydx-live"))]
+impl FromStr for TimeInForce {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "GTC" => Ok(TimeInForce::GoodTillCancel),
+            "IOC" => Ok(TimeInForce::ImmediateOrCancel),
+            _ => Err(anyhow!("unknown time in force {}", s)),
+        }
+    }
+}
+
+/// Settings of the live execution backend of an exchange
+#[cfg(any(feature = "aevo-live", feature = "dydx-live"))]
+#[derive(Clone, Debug)]
+pub struct ExecutionConfig {
+    pub rest_url: String,
+    pub key: String,
+    pub secret: String,
+    pub time_in_force: TimeInForce,
+    /// Address of the account trading, the maker of the Aevo orders
+    #[cfg(feature = "aevo-live")]
+    pub account: Option<String>,
+    /// Hex encoded private key signing the Aevo orders
+    #[cfg(feature = "aevo-live")]
+    pub signing_key: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
 pub struct Symbol(String);
 
 impl FromStr for Symbol {
@@ -159,7 +367,13 @@ impl FromStr for Symbol {
     }
 }
 
-#[derive(Debug)]
+impl Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Wallet {
     pub base: Decimal,
     pub quote: Decimal,
@@ -1,174 +1,227 @@
 //! Aevo exchange implementation
 
-use std::{fmt::Display, task::Poll};
+use std::{
+    fmt::Display,
+    task::{ready, Poll},
+    time::SystemTime,
+};
 
-use async_trait::async_trait;
-use futures_util::{SinkExt, Stream, StreamExt};
-use rust_decimal::Decimal;
+use crc32fast::Hasher;
+
+use futures_util::Stream;
 use serde::Deserialize;
 use serde_json::json;
-use tokio::sync::mpsc;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{
+    connection::Protocol,
+    registry::{BookRegistry, Polled, SymbolBook},
+    BookEntry, BookError, BookHandle, BookState, FeedSource, MarketEvent, MarketFeed, OrderBook,
+    OrderBookMessage, Symbol, Venue, ViolationPolicy,
+};
 
-use super::{BookEntry, Exchange, OrderBook, OrderBookMessage, Symbol};
+#[cfg(feature = "aevo-live")]
+mod rest;
+#[cfg(feature = "aevo-live")]
+pub use rest::{sign, sign_order, signing_key, AevoClient, OrderRequest};
 
-const WSS_URL: &str = "wss://ws.aevo.xyz";
+const NAME: &str = "Aevo";
+/// Levels of each side covered by the checksum
+const CHECKSUM_DEPTH: usize = 25;
 
 pub struct Aevo {
-    receiver: mpsc::Receiver<BookRawMessage>,
-    sender: mpsc::Sender<BookRawMessage>,
-    order_book: OrderBook,
-    persistent_trades: bool,
-    fee: Decimal,
+    books: BookRegistry<BookRawMessage, ExchangeBook>,
 }
 
-impl Aevo {
-    pub fn new(persistent_trades: bool, fee: Decimal) -> Self {
-        let (sender, receiver) = mpsc::channel(10000);
+/// Book as sent by the exchange, without the local updates. The checksums are
+/// verified against it
+#[derive(Default)]
+struct ExchangeBook(OrderBook);
 
-        Self {
-            receiver,
-            sender,
-            order_book: OrderBook::new(),
-            persistent_trades,
-            fee,
-        }
+/// Apply a frame of the exchange to `book` and check it against the
+/// `expected` checksum. Returns false on a mismatch, or if the checksum can
+/// not be read, the local book is not updated then.
+fn apply(
+    book: &mut SymbolBook<ExchangeBook>,
+    update: OrderBookMessage,
+    expected: &str,
+) -> Result<bool, BookError> {
+    let exchange_book = &mut book.venue.0;
+    exchange_book.update(update.clone())?;
+
+    let Ok(expected) = expected.parse::<u32>() else {
+        tracing::warn!(checksum = expected, "{} book checksum unreadable", NAME);
+        return Ok(false);
+    };
+    let bids = exchange_book
+        .bids()
+        .take(CHECKSUM_DEPTH)
+        .collect::<Vec<_>>();
+    let asks = exchange_book
+        .asks()
+        .take(CHECKSUM_DEPTH)
+        .collect::<Vec<_>>();
+    if expected != checksum(&bids, &asks) {
+        return Ok(false);
     }
+
+    book.update(update)?;
+    Ok(true)
 }
 
-#[async_trait]
-impl Exchange for Aevo {
-    fn order_book_subscribe(&self, symbol: &Symbol) {
-        // This is very ugly and should not be done. The connection with the
-        // exchange should be created when the object is created. The method
-        // should only send the subscription to the order book channel
-        let symbol = symbol.clone();
-        tokio::spawn(handle_wss(symbol, self.sender.clone()));
+impl Aevo {
+    /// Create the exchange, connected at once if `source` is live
+    pub fn new(source: FeedSource, policy: ViolationPolicy) -> Self {
+        Self {
+            books: BookRegistry::new(
+                NAME,
+                Venue::Aevo,
+                source,
+                OrderBookChannel,
+                parse_message,
+                policy,
+            ),
+        }
     }
 
-    async fn handle_persistent_buy(&self, amount: Decimal, price: Decimal) -> anyhow::Result<()> {
-        if !self.persistent_trades {
-            return Ok(());
-        }
+    /// Apply a frame to the book of its symbol. Returns the event of the
+    /// book, `None` if the frame is not of a subscribed symbol or is ignored
+    fn process(&mut self, msg: BookRawMessage, received_at: SystemTime) -> Option<MarketEvent> {
+        let symbol = Symbol(msg.instrument_name.clone());
+        // Frames of the symbols not subscribed, or not anymore
+        let book = self.books.book(&symbol)?;
+        let last_updated = msg.last_updated.parse().ok();
 
-        let Some(ask) = self.order_book.best_ask() else {
-            return Ok(());
+        let update = match msg.msg_type.as_ref() {
+            _ if book.state == BookState::Halted => return None,
+            "snapshot" => {
+                if book.state == BookState::Resyncing {
+                    tracing::info!("{} {} book resynced", NAME, symbol);
+                    book.state = BookState::Live;
+                }
+                OrderBookMessage::Snapshot {
+                    bids: msg.bids,
+                    asks: msg.asks,
+                }
+            }
+            // The book is rebuilt from the next snapshot
+            _ if book.state == BookState::Resyncing => return None,
+            "update" => OrderBookMessage::Update {
+                bids: msg.bids,
+                asks: msg.asks,
+            },
+            msg_type => {
+                tracing::warn!(msg_type, "{} {} unknown book message ignored", NAME, symbol);
+                return None;
+            }
         };
+        match apply(book, update, &msg.checksum) {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::warn!(
+                    expected = msg.checksum,
+                    "{} {} book checksum mismatch, resubscribing",
+                    NAME,
+                    symbol
+                );
+                self.books.resync(&symbol);
+            }
+            Err(err) => self.books.violation(&symbol, err),
+        }
 
-        // Update the entry
-        let entry = BookEntry {
-            price,
-            amount: ask.amount - amount,
-        };
-        let mut update = BookRawMessage {
-            msg_type: "update".to_string(),
-            ..Default::default()
-        };
-        update.asks.push(entry);
-        self.sender.send(update).await?;
-        Ok(())
+        let event = self.books.publish(&symbol, received_at)?;
+        Some(MarketEvent {
+            last_updated,
+            ..event
+        })
     }
+}
 
-    async fn handle_persistent_sell(&self, amount: Decimal, price: Decimal) -> anyhow::Result<()> {
-        if !self.persistent_trades {
-            return Ok(());
-        }
-
-        let Some(bid) = self.order_book.best_bid() else {
-            return Ok(());
-        };
+impl MarketFeed for Aevo {
+    fn order_book_subscribe(&mut self, symbol: &Symbol) -> BookHandle {
+        self.books.subscribe(symbol)
+    }
 
-        // Update the entry
-        let entry = BookEntry {
-            price,
-            amount: bid.amount - amount,
-        };
-        let mut update = BookRawMessage {
-            msg_type: "update".to_string(),
-            ..Default::default()
-        };
-        update.bids.push(entry);
-        self.sender.send(update).await?;
-        Ok(())
+    fn order_book_unsubscribe(&mut self, symbol: &Symbol) {
+        self.books.unsubscribe(symbol);
     }
 
-    fn fee(&self) -> Decimal {
-        self.fee
+    fn push_frame(&self, frame: &str, received_at: SystemTime) -> anyhow::Result<()> {
+        self.books.push_frame(frame, received_at)
     }
 }
 
 impl Stream for Aevo {
-    type Item = (Option<BookEntry>, Option<BookEntry>);
+    type Item = MarketEvent;
 
     fn poll_next(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        // We process order book messages internally. Return only best ask/bid
-        match self.receiver.poll_recv(cx) {
-            Poll::Ready(Some(msg)) => {
-                let update = match msg.msg_type.as_ref() {
-                    "snapshot" => OrderBookMessage::Snapshot {
-                        bids: msg.bids,
-                        asks: msg.asks,
-                    },
-                    "update" => {
-                        if msg.asks.is_empty() {
-                            OrderBookMessage::BidUpdate(msg.bids[0].clone())
-                        } else {
-                            OrderBookMessage::AskUpdate(msg.asks[0].clone())
-                        }
+        loop {
+            match ready!(self.books.poll_next(cx)) {
+                Some(Polled::Event(event)) => return Poll::Ready(Some(event)),
+                Some(Polled::Message(msg, received_at)) => {
+                    if let Some(event) = self.process(msg, received_at) {
+                        return Poll::Ready(Some(event));
                     }
-                    _ => panic!("received unknown orderbook message"),
-                };
-                self.order_book.update(update);
-                Poll::Ready(Some((
-                    self.order_book.best_bid().cloned(),
-                    self.order_book.best_ask().cloned(),
-                )))
+                }
+                Some(Polled::Connection(connection)) => self.books.connection(connection),
+                None => return Poll::Ready(None),
             }
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => Poll::Pending,
         }
     }
 }
 
 impl Display for Aevo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Aevo")
+        write!(f, "{}", NAME)
     }
 }
 
-async fn handle_wss(symbol: Symbol, channel: mpsc::Sender<BookRawMessage>) {
-    loop {
-        // Connect to Aevo
-        let (mut wss_stream, _) = connect_async(WSS_URL).await.expect("Failed to connect");
-        // Send the order book subscription request
-        wss_stream
-            .send(Message::Text(
-                json!({"op":"subscribe", "data":[format!("orderbook:{}", symbol.0)]}).to_string(),
-            ))
-            .await
-            .expect("Failed to send orderbook subscription");
-
-        wss_stream
-            .for_each(|message| async {
-                // If we receive an error close the connection and try to reconnect
-                let Ok(message) = message else {
-                    return;
-                };
-                // Again, at the moment if we fail to convert in string close
-                // the connection and try to reconnect
-                let Ok(message) = message.into_text() else {
-                    return;
-                };
-
-                match serde_json::from_str::<AevoRawMessage>(&message) {
-                    Ok(msg) => channel.send(msg.data).await.unwrap(),
-                    Err(_) => tracing::debug!("received unknown message {:?}", message),
-                }
-            })
-            .await;
+/// Order book channels, by symbol
+struct OrderBookChannel;
+
+impl Protocol for OrderBookChannel {
+    fn subscription(&self, id: &str, subscribe: bool) -> Message {
+        let op = if subscribe {
+            "subscribe"
+        } else {
+            "unsubscribe"
+        };
+        Message::Text(json!({"op": op, "data": [format!("orderbook:{}", id)]}).to_string())
+    }
+
+    fn heartbeat(&self) -> Message {
+        Message::Text(json!({"id": 1, "op": "ping"}).to_string())
+    }
+}
+
+/// CRC32 of the top `CHECKSUM_DEPTH` levels of the book, alternating the bids
+/// and asks as `bid_price:bid_amount:ask_price:ask_amount:...`, with the
+/// prices and amounts written as the exchange sends them
+pub fn checksum(bids: &[BookEntry], asks: &[BookEntry]) -> u32 {
+    let mut levels = Vec::new();
+    for depth in 0..CHECKSUM_DEPTH {
+        for side in [bids, asks] {
+            if let Some(level) = side.get(depth) {
+                levels.push(format!("{}:{}", level.price, level.amount));
+            }
+        }
+    }
+
+    let mut hasher = Hasher::new();
+    hasher.update(levels.join(":").as_bytes());
+    hasher.finalize()
+}
+
+fn parse_message(message: &str) -> Option<BookRawMessage> {
+    match serde_json::from_str::<AevoRawMessage>(message) {
+        Ok(msg) => Some(msg.data),
+        Err(_) => {
+            tracing::debug!("received unknown message {:?}", message);
+            None
+        }
     }
 }
 
@@ -195,3 +248,212 @@ struct AevoRawMessage {
     data: BookRawMessage,
     write_ts: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::{FutureExt, StreamExt};
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    const SYMBOL: &str = "ETH-PERP";
+
+    fn levels(levels: &[(Decimal, Decimal)]) -> Vec<BookEntry> {
+        levels
+            .iter()
+            .map(|&(price, amount)| BookEntry { price, amount })
+            .collect()
+    }
+
+    fn frame(msg_type: &str, bids: &[BookEntry], asks: &[BookEntry], checksum: &str) -> String {
+        let side = |levels: &[BookEntry]| {
+            levels
+                .iter()
+                .map(|level| [level.price.to_string(), level.amount.to_string()])
+                .collect::<Vec<_>>()
+        };
+        json!({
+            "channel": format!("orderbook:{}", SYMBOL),
+            "data": {
+                "type": msg_type,
+                "instrument_id": "1",
+                "instrument_name": SYMBOL,
+                "instrument_type": "PERPETUAL",
+                "bids": side(bids),
+                "asks": side(asks),
+                "last_updated": "7",
+                "checksum": checksum,
+            },
+            "write_ts": "0",
+        })
+        .to_string()
+    }
+
+    fn feed() -> (Aevo, BookHandle) {
+        let mut feed = Aevo::new(FeedSource::Manual, ViolationPolicy::Resync);
+        let handle = feed.order_book_subscribe(&Symbol(SYMBOL.to_string()));
+        (feed, handle)
+    }
+
+    /// Push `frame` and return the event of the feed, `None` if the frame was
+    /// ignored
+    fn push(feed: &mut Aevo, frame: &str) -> Option<MarketEvent> {
+        push_at(feed, frame, SystemTime::now())
+    }
+
+    fn push_at(feed: &mut Aevo, frame: &str, received_at: SystemTime) -> Option<MarketEvent> {
+        feed.push_frame(frame, received_at).unwrap();
+        feed.next().now_or_never().flatten()
+    }
+
+    /// Book with a bid at 99 and an ask at 101, checked
+    fn snapshot(feed: &mut Aevo) -> MarketEvent {
+        let bids = levels(&[(dec!(99), dec!(1))]);
+        let asks = levels(&[(dec!(101), dec!(2))]);
+        let checksum = checksum(&bids, &asks).to_string();
+        push(feed, &frame("snapshot", &bids, &asks, &checksum)).unwrap()
+    }
+
+    #[test]
+    fn checksum_alternates_the_sides() {
+        let bids = levels(&[(dec!(99), dec!(1)), (dec!(98), dec!(3))]);
+        let asks = levels(&[(dec!(101), dec!(2))]);
+
+        // CRC32 of "99:1:101:2:98:3"
+        assert_eq!(checksum(&bids, &asks), 1671222929);
+        assert_eq!(checksum(&[], &[]), Hasher::new().finalize(), "empty book");
+    }
+
+    #[test]
+    fn checksum_covers_the_top_of_the_book() {
+        let bids = (0..30)
+            .map(|level| BookEntry {
+                price: Decimal::from(99 - level),
+                amount: dec!(1),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(checksum(&bids, &[]), checksum(&bids[..CHECKSUM_DEPTH], &[]));
+        assert_ne!(
+            checksum(&bids, &[]),
+            checksum(&bids[..CHECKSUM_DEPTH - 1], &[])
+        );
+    }
+
+    #[tokio::test]
+    async fn checked_frames_update_the_book() {
+        let (mut feed, handle) = feed();
+        let event = snapshot(&mut feed);
+        assert_eq!(event.state, BookState::Live);
+        assert_eq!(event.last_updated, Some(7));
+        assert_eq!(event.best_bid().unwrap().price, dec!(99));
+
+        let bids = levels(&[(dec!(100), dec!(1)), (dec!(99), dec!(1))]);
+        let asks = levels(&[(dec!(101), dec!(2))]);
+        let update = frame(
+            "update",
+            &bids[..1],
+            &[],
+            &checksum(&bids, &asks).to_string(),
+        );
+        let event = push(&mut feed, &update).unwrap();
+        assert_eq!(event.state, BookState::Live);
+        assert_eq!(event.best_bid().unwrap().price, dec!(100));
+        assert_eq!(handle.current().bids().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch_resyncs_the_book() {
+        let (mut feed, handle) = feed();
+        snapshot(&mut feed);
+
+        let update = frame("update", &levels(&[(dec!(100), dec!(1))]), &[], "1");
+        let event = push(&mut feed, &update).unwrap();
+        assert_eq!(event.state, BookState::Resyncing);
+        assert!(event.best_bid().is_none());
+        assert!(handle.current().best_ask().is_none());
+
+        // Updates wait for the next snapshot
+        let bids = levels(&[(dec!(99), dec!(1))]);
+        let update = frame("update", &bids, &[], &checksum(&bids, &[]).to_string());
+        assert!(push(&mut feed, &update).is_none());
+        assert_eq!(snapshot(&mut feed).state, BookState::Live);
+    }
+
+    #[tokio::test]
+    async fn unreadable_checksum_resyncs_the_book() {
+        let (mut feed, _) = feed();
+        snapshot(&mut feed);
+
+        let update = frame("update", &levels(&[(dec!(100), dec!(1))]), &[], "none");
+        let event = push(&mut feed, &update).unwrap();
+        assert_eq!(event.state, BookState::Resyncing);
+    }
+
+    #[tokio::test]
+    async fn unknown_messages_are_skipped() {
+        let (mut feed, handle) = feed();
+        snapshot(&mut feed);
+
+        let frame = frame("delta", &levels(&[(dec!(100), dec!(1))]), &[], "1");
+        assert!(push(&mut feed, &frame).is_none());
+        assert_eq!(handle.current().best_bid().unwrap().price, dec!(99));
+        assert_eq!(snapshot(&mut feed).state, BookState::Live);
+    }
+
+    #[tokio::test]
+    async fn repeated_mismatches_halt_the_book() {
+        let (mut feed, _) = feed();
+        let bids = levels(&[(dec!(99), dec!(1))]);
+        let snapshot = frame("snapshot", &bids, &[], "1");
+
+        // Resynced five times in a row, then halted
+        for _ in 0..5 {
+            let event = push(&mut feed, &snapshot).unwrap();
+            assert_eq!(event.state, BookState::Resyncing);
+        }
+        let event = push(&mut feed, &snapshot).unwrap();
+        assert_eq!(event.state, BookState::Halted);
+        assert!(push(&mut feed, &snapshot).is_none());
+    }
+
+    #[tokio::test]
+    async fn valid_snapshots_reset_the_resyncs() {
+        let (mut feed, _) = feed();
+        let bids = levels(&[(dec!(99), dec!(1))]);
+        let invalid = frame("snapshot", &bids, &[], "1");
+
+        for _ in 0..3 {
+            for _ in 0..5 {
+                let event = push(&mut feed, &invalid).unwrap();
+                assert_eq!(event.state, BookState::Resyncing);
+            }
+            assert_eq!(snapshot(&mut feed).state, BookState::Live);
+        }
+    }
+
+    #[tokio::test]
+    async fn resyncs_are_counted_again_after_a_while() {
+        let (mut feed, _) = feed();
+        let bids = levels(&[(dec!(99), dec!(1))]);
+        let invalid = frame("snapshot", &bids, &[], "1");
+        let start = SystemTime::now();
+
+        for _ in 0..5 {
+            let event = push_at(&mut feed, &invalid, start).unwrap();
+            assert_eq!(event.state, BookState::Resyncing);
+        }
+        // A minute after the last resync
+        let later = start + Duration::from_secs(61);
+        let event = push_at(&mut feed, &invalid, later).unwrap();
+        assert_eq!(event.state, BookState::Resyncing);
+        for _ in 0..4 {
+            push_at(&mut feed, &invalid, later).unwrap();
+        }
+        let event = push_at(&mut feed, &invalid, later).unwrap();
+        assert_eq!(event.state, BookState::Halted);
+    }
+}
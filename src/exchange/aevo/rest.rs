@@ -0,0 +1,633 @@
+//! Aevo REST API client used for live execution
+//!
+//! Requests are authenticated with the API key and an HMAC-SHA256 signature
+//! of the request, see [`sign`]. Orders are also signed by the signing key of
+//! the account, as EIP-712 typed data, see [`sign_order`].
+
+use std::{
+    fmt::Display,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use k256::ecdsa::SigningKey;
+use rand::Rng;
+use reqwest::Method;
+use rust_decimal::{prelude::ToPrimitive, Decimal, RoundingStrategy};
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Sha256;
+use sha3::{Digest, Keccak256};
+use tokio::sync::OnceCell;
+
+use crate::exchange::{ExecutionConfig, Fill, OrderExecutor, Side, Symbol, TimeInForce};
+
+/// How often the status of an open order is polled
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// Open orders not filled within this time are cancelled
+const ORDER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// EIP-712 type of the signed orders
+const ORDER_TYPE: &str = "Order(address maker,bool isBuy,uint256 limitPrice,uint256 amount,uint256 salt,uint256 instrument,uint256 timestamp)";
+/// Prices and amounts of the orders are integers, in millionths
+const ORDER_DECIMALS: Decimal = dec!(1_000_000);
+
+/// Body of `POST /orders`. The prices and amounts are written as integers in
+/// millionths, `signature` signs the other fields, see [`sign_order`].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrderRequest {
+    pub instrument: String,
+    /// Address of the account the order is placed for
+    pub maker: String,
+    pub is_buy: bool,
+    #[serde(serialize_with = "as_string", deserialize_with = "from_string")]
+    pub amount: u64,
+    #[serde(serialize_with = "as_string", deserialize_with = "from_string")]
+    pub limit_price: u64,
+    /// Random number making every order unique
+    #[serde(serialize_with = "as_string", deserialize_with = "from_string")]
+    pub salt: u64,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub time_in_force: TimeInForce,
+    pub signature: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OrderStatus {
+    pub order_id: String,
+    pub order_status: String,
+    pub amount: Decimal,
+    pub filled: Decimal,
+    pub avg_price: Option<Decimal>,
+}
+
+impl OrderStatus {
+    /// The order will not be filled any further
+    pub fn is_closed(&self) -> bool {
+        matches!(
+            self.order_status.as_str(),
+            "filled" | "cancelled" | "rejected" | "expired"
+        )
+    }
+
+    pub fn fill(&self) -> Fill {
+        Fill {
+            amount: self.filled,
+            price: self.avg_price.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Instrument {
+    pub instrument_id: String,
+}
+
+pub struct AevoClient {
+    http: reqwest::Client,
+    config: ExecutionConfig,
+    /// Address of the account, maker of the orders
+    maker: String,
+    signing_key: SigningKey,
+    symbol: Symbol,
+    fee: Decimal,
+    /// Instrument id of the symbol, orders refer to it. Fetched on the first
+    /// order
+    instrument_id: OnceCell<String>,
+}
+
+impl AevoClient {
+    /// Fails without the account and the signing key of the orders
+    pub fn new(config: ExecutionConfig, symbol: Symbol, fee: Decimal) -> anyhow::Result<Self> {
+        let maker = config
+            .account
+            .clone()
+            .context("aevo orders need the account address")?;
+        address(&maker)?;
+        let signing_key = signing_key(
+            config
+                .signing_key
+                .as_deref()
+                .context("aevo orders need the signing key")?,
+        )?;
+
+        Ok(Self {
+            http: reqwest::Client::new(),
+            config,
+            maker,
+            signing_key,
+            symbol,
+            fee,
+            instrument_id: OnceCell::new(),
+        })
+    }
+
+    pub async fn instrument(&self, name: &str) -> anyhow::Result<Instrument> {
+        self.request(Method::GET, &format!("/instrument/{}", name), None)
+            .await
+    }
+
+    pub async fn place_order(&self, order: &OrderRequest) -> anyhow::Result<OrderStatus> {
+        self.request(Method::POST, "/orders", Some(serde_json::to_string(order)?))
+            .await
+    }
+
+    pub async fn order_status(&self, order_id: &str) -> anyhow::Result<OrderStatus> {
+        self.request(Method::GET, &format!("/orders/{}", order_id), None)
+            .await
+    }
+
+    pub async fn cancel_order(&self, order_id: &str) -> anyhow::Result<OrderStatus> {
+        self.request(Method::DELETE, &format!("/orders/{}", order_id), None)
+            .await
+    }
+
+    /// Place a limit order and wait until it is closed. Orders still open
+    /// after [`ORDER_TIMEOUT`] are cancelled. Returns what was filled, the
+    /// last fill seen if the order can not be followed to its end.
+    pub async fn execute(
+        &self,
+        instrument: &str,
+        side: Side,
+        amount: Decimal,
+        price: Decimal,
+        time_in_force: TimeInForce,
+    ) -> anyhow::Result<Fill> {
+        let mut order = OrderRequest {
+            instrument: instrument.to_string(),
+            maker: self.maker.clone(),
+            is_buy: side == Side::Buy,
+            amount: to_millionths(amount, RoundingStrategy::ToZero)?,
+            limit_price: to_millionths(price, limit_rounding(side))?,
+            salt: rand::thread_rng().gen(),
+            timestamp: now_nanos() / 1_000_000_000,
+            time_in_force,
+            signature: String::new(),
+        };
+        order.signature = sign_order(&self.signing_key, &self.config.rest_url, &order)?;
+        let mut status = self.place_order(&order).await?;
+        tracing::debug!("aevo order {} {}", status.order_id, status.order_status);
+
+        let deadline = tokio::time::Instant::now() + ORDER_TIMEOUT;
+        while !status.is_closed() {
+            let timed_out = tokio::time::Instant::now() >= deadline;
+            let next = if timed_out {
+                self.cancel_order(&status.order_id).await
+            } else {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                self.order_status(&status.order_id).await
+            };
+            match next {
+                Ok(next) => status = next,
+                // The order is placed, what it filled so far is not lost with
+                // the request. It may still be open and fill further, out of
+                // sight of the bot
+                Err(err) => {
+                    tracing::error!(
+                        "aevo order {} state unknown, keeping its last fill {} of {}: {}",
+                        status.order_id,
+                        status.filled,
+                        status.amount,
+                        err
+                    );
+                    return Ok(status.fill());
+                }
+            }
+            if timed_out {
+                break;
+            }
+        }
+
+        if status.order_status == "rejected" {
+            bail!("aevo order {} rejected", status.order_id);
+        }
+        tracing::debug!(
+            "aevo order {} closed, filled {} of {} at {:?}",
+            status.order_id,
+            status.filled,
+            status.amount,
+            status.avg_price
+        );
+
+        Ok(status.fill())
+    }
+
+    async fn instrument_id(&self) -> anyhow::Result<&str> {
+        let instrument_id = self
+            .instrument_id
+            .get_or_try_init(|| async {
+                let instrument = self.instrument(&self.symbol.to_string()).await?;
+                anyhow::Ok(instrument.instrument_id)
+            })
+            .await?;
+
+        Ok(instrument_id)
+    }
+
+    async fn request<T: for<'de> Deserialize<'de>>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<String>,
+    ) -> anyhow::Result<T> {
+        let timestamp = now_nanos().to_string();
+        let body = body.unwrap_or_default();
+        let signature = sign(
+            &self.config.key,
+            &self.config.secret,
+            &timestamp,
+            method.as_str(),
+            path,
+            &body,
+        )?;
+
+        let response = self
+            .http
+            .request(method, format!("{}{}", self.config.rest_url, path))
+            .header("AEVO-KEY", &self.config.key)
+            .header("AEVO-TIMESTAMP", timestamp)
+            .header("AEVO-SIGNATURE", signature)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            return Err(anyhow!("aevo request {} failed: {} {}", path, status, text));
+        }
+
+        Ok(serde_json::from_str(&text)?)
+    }
+}
+
+#[async_trait]
+impl OrderExecutor for AevoClient {
+    fn fee(&self) -> Decimal {
+        self.fee
+    }
+
+    async fn execute_order(
+        &self,
+        side: Side,
+        amount: Decimal,
+        price: Decimal,
+    ) -> anyhow::Result<Fill> {
+        let instrument_id = self.instrument_id().await?;
+        self.execute(
+            instrument_id,
+            side,
+            amount,
+            price,
+            self.config.time_in_force,
+        )
+        .await
+    }
+}
+
+impl Display for AevoClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Aevo")
+    }
+}
+
+/// Signature of a request: hex encoded HMAC-SHA256, keyed with the API
+/// secret, of `key,timestamp,METHOD,path,body`
+pub fn sign(
+    key: &str,
+    secret: &str,
+    timestamp: &str,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> anyhow::Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(format!("{},{},{},{},{}", key, timestamp, method, path, body).as_bytes());
+
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+/// EIP-712 signature of `order` by `signing_key`, hex encoded as `r`, `s`
+/// and `v`. The domain is the testnet one if `rest_url` is a testnet
+/// endpoint.
+pub fn sign_order(
+    signing_key: &SigningKey,
+    rest_url: &str,
+    order: &OrderRequest,
+) -> anyhow::Result<String> {
+    let (name, chain_id) = if rest_url.contains("testnet") {
+        ("Aevo Testnet", 11155111)
+    } else {
+        ("Aevo Mainnet", 1)
+    };
+    let domain = keccak(&[
+        &keccak(&[b"EIP712Domain(string name,string version,uint256 chainId)"]),
+        &keccak(&[name.as_bytes()]),
+        &keccak(&[b"1"]),
+        &uint(chain_id),
+    ]);
+
+    let mut maker = [0; 32];
+    maker[12..].copy_from_slice(&address(&order.maker)?);
+    let instrument = order
+        .instrument
+        .parse()
+        .with_context(|| format!("invalid aevo instrument id {}", order.instrument))?;
+    let order_hash = keccak(&[
+        &keccak(&[ORDER_TYPE.as_bytes()]),
+        &maker,
+        &uint(order.is_buy.into()),
+        &uint(order.limit_price),
+        &uint(order.amount),
+        &uint(order.salt),
+        &uint(instrument),
+        &uint(order.timestamp),
+    ]);
+
+    let digest = keccak(&[b"\x19\x01", &domain, &order_hash]);
+    let (signature, recovery_id) = signing_key.sign_prehash_recoverable(&digest)?;
+    let mut bytes = signature.to_bytes().to_vec();
+    bytes.push(27 + recovery_id.to_byte());
+
+    Ok(format!("0x{}", hex::encode(bytes)))
+}
+
+/// Private key of a hex encoded signing key
+pub fn signing_key(key: &str) -> anyhow::Result<SigningKey> {
+    let bytes = hex::decode(key.trim_start_matches("0x")).context("invalid signing key")?;
+    SigningKey::from_slice(&bytes).context("invalid signing key")
+}
+
+/// Bytes of a hex encoded address
+fn address(address: &str) -> anyhow::Result<[u8; 20]> {
+    hex::decode(address.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("invalid address {}", address))
+}
+
+fn keccak(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// `uint256` encoding of `value`
+fn uint(value: u64) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    bytes[24..].copy_from_slice(&value.to_be_bytes());
+    bytes
+}
+
+/// `value` in millionths, rounded with `strategy`. Amounts are rounded down:
+/// an order never spends more than what was reserved for it
+fn to_millionths(value: Decimal, strategy: RoundingStrategy) -> anyhow::Result<u64> {
+    (value * ORDER_DECIMALS)
+        .round_dp_with_strategy(0, strategy)
+        .to_u64()
+        .ok_or_else(|| anyhow!("invalid aevo order value {}", value))
+}
+
+/// Rounding of the limit price of a `side` order, never past the limit the
+/// order was decided on: buys are rounded down, sells up
+fn limit_rounding(side: Side) -> RoundingStrategy {
+    match side {
+        Side::Buy => RoundingStrategy::ToNegativeInfinity,
+        Side::Sell => RoundingStrategy::ToPositiveInfinity,
+    }
+}
+
+fn as_string<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn from_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::mock::MockAevoRest;
+
+    /// Private key 1, whose address is well known
+    const SIGNING_KEY: &str = "0x0000000000000000000000000000000000000000000000000000000000000001";
+    const ACCOUNT: &str = "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf";
+
+    fn config(time_in_force: TimeInForce) -> ExecutionConfig {
+        ExecutionConfig {
+            rest_url: String::new(),
+            key: "key".to_string(),
+            secret: "secret".to_string(),
+            time_in_force,
+            account: Some(ACCOUNT.to_string()),
+            signing_key: Some(SIGNING_KEY.to_string()),
+        }
+    }
+
+    /// Client of a mock started with `config`, sending its requests with
+    /// `client_config`
+    async fn client(
+        config: &ExecutionConfig,
+        client_config: ExecutionConfig,
+        resting: bool,
+    ) -> AevoClient {
+        let mut mock = MockAevoRest::bind("127.0.0.1:0", config).await.unwrap();
+        if resting {
+            mock = mock.with_resting_orders();
+        }
+        let rest_url = mock.url().unwrap();
+        mock.serve();
+
+        AevoClient::new(
+            ExecutionConfig {
+                rest_url,
+                ..client_config
+            },
+            "BTC-PERP".parse().unwrap(),
+            dec!(0),
+        )
+        .unwrap()
+    }
+
+    async fn buy(client: &AevoClient) -> anyhow::Result<Fill> {
+        client
+            .execute(
+                "1",
+                Side::Buy,
+                dec!(0.5),
+                dec!(60000),
+                client.config.time_in_force,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_with_a_wrong_secret() {
+        let config = config(TimeInForce::ImmediateOrCancel);
+        let client = client(
+            &config,
+            ExecutionConfig {
+                secret: "wrong".to_string(),
+                ..config.clone()
+            },
+            false,
+        )
+        .await;
+
+        let err = buy(&client).await.unwrap_err();
+        assert!(err.to_string().contains("INVALID_SIGNATURE"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn rejects_orders_signed_by_another_key() {
+        let config = config(TimeInForce::ImmediateOrCancel);
+        let client = client(
+            &config,
+            ExecutionConfig {
+                signing_key: Some(format!("{:064x}", 2)),
+                ..config.clone()
+            },
+            false,
+        )
+        .await;
+
+        let err = buy(&client).await.unwrap_err();
+        assert!(
+            err.to_string().contains("INVALID_ORDER_SIGNATURE"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn fills_immediate_or_cancel_orders() {
+        let config = config(TimeInForce::ImmediateOrCancel);
+        let client = client(&config, config.clone(), false).await;
+
+        let fill = buy(&client).await.unwrap();
+        assert_eq!(fill.amount, dec!(0.5));
+        assert_eq!(fill.price, dec!(60000));
+    }
+
+    #[tokio::test]
+    async fn polls_open_orders_until_filled() {
+        let config = config(TimeInForce::GoodTillCancel);
+        let client = client(&config, config.clone(), false).await;
+
+        let fill = buy(&client).await.unwrap();
+        assert_eq!(fill.amount, dec!(0.5));
+        assert_eq!(fill.price, dec!(60000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancels_open_orders_after_the_timeout() {
+        let config = config(TimeInForce::GoodTillCancel);
+        let client = client(&config, config.clone(), true).await;
+
+        let start = tokio::time::Instant::now();
+        let fill = buy(&client).await.unwrap();
+        assert!(start.elapsed() >= ORDER_TIMEOUT);
+        assert_eq!(fill.amount, dec!(0));
+    }
+
+    #[test]
+    fn limit_prices_are_rounded_towards_the_limit() {
+        let price = dec!(60000.0000005);
+        let buy = to_millionths(price, limit_rounding(Side::Buy)).unwrap();
+        let sell = to_millionths(price, limit_rounding(Side::Sell)).unwrap();
+        assert_eq!(buy, 60_000_000_000);
+        assert_eq!(sell, 60_000_000_001);
+
+        // Exact prices are left alone
+        let price = dec!(60000.000001);
+        assert_eq!(
+            to_millionths(price, limit_rounding(Side::Sell)).unwrap(),
+            60_000_000_001
+        );
+        assert_eq!(
+            to_millionths(price, limit_rounding(Side::Buy)).unwrap(),
+            60_000_000_001
+        );
+    }
+
+    #[test]
+    fn orders_are_signed_by_the_signing_key() {
+        let order = OrderRequest {
+            instrument: "1".to_string(),
+            maker: ACCOUNT.to_string(),
+            is_buy: true,
+            amount: to_millionths(dec!(0.1234567), RoundingStrategy::ToZero).unwrap(),
+            limit_price: to_millionths(dec!(60000.5), limit_rounding(Side::Buy)).unwrap(),
+            salt: 42,
+            timestamp: 1_700_000_000,
+            time_in_force: TimeInForce::ImmediateOrCancel,
+            signature: String::new(),
+        };
+        assert_eq!(order.amount, 123456);
+        assert_eq!(order.limit_price, 60_000_500_000);
+
+        let key = signing_key(SIGNING_KEY).unwrap();
+        let signature = sign_order(&key, "https://api.aevo.xyz", &order).unwrap();
+        let bytes = hex::decode(signature.trim_start_matches("0x")).unwrap();
+        assert_eq!(bytes.len(), 65);
+
+        // The signer recovered from the signature is the account of the key
+        let mut maker = [0; 32];
+        maker[12..].copy_from_slice(&address(ACCOUNT).unwrap());
+        let digest = keccak(&[
+            b"\x19\x01",
+            &keccak(&[
+                &keccak(&[b"EIP712Domain(string name,string version,uint256 chainId)"]),
+                &keccak(&[b"Aevo Mainnet"]),
+                &keccak(&[b"1"]),
+                &uint(1),
+            ]),
+            &keccak(&[
+                &keccak(&[ORDER_TYPE.as_bytes()]),
+                &maker,
+                &uint(1),
+                &uint(60_000_500_000),
+                &uint(123456),
+                &uint(42),
+                &uint(1),
+                &uint(1_700_000_000),
+            ]),
+        ]);
+        let signer = VerifyingKey::recover_from_prehash(
+            &digest,
+            &Signature::from_slice(&bytes[..64]).unwrap(),
+            RecoveryId::from_byte(bytes[64] - 27).unwrap(),
+        )
+        .unwrap();
+        let public_key = signer.to_encoded_point(false);
+        assert_eq!(
+            keccak(&[&public_key.as_bytes()[1..]])[12..],
+            address(ACCOUNT).unwrap()
+        );
+
+        // Another domain, another signature
+        assert_ne!(
+            sign_order(&key, "https://api-testnet.aevo.xyz", &order).unwrap(),
+            signature
+        );
+    }
+}
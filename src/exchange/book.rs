@@ -0,0 +1,409 @@
+//! Local order book
+//!
+//! Every side is a sorted map from price to amount: setting or removing a
+//! level is O(log n). The maps are persistent, a copy of the book shares
+//! every node not changed since, so the feeds can publish a snapshot of the
+//! book on every frame without copying it. The best bid and ask are cached
+//! after every frame so reading them is O(1). Frames breaking the invariants of the book, no
+//! negative amount, no level repeated and no crossed book, are reported with
+//! a [`BookError`].
+
+use std::{collections::BTreeSet, fmt::Display};
+
+use im::OrdMap;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Deserialize;
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct BookEntry {
+    pub price: Decimal,
+    #[serde(alias = "size")]
+    pub amount: Decimal,
+}
+
+/// Frame of order book data
+#[derive(Clone, Debug)]
+pub(super) enum OrderBookMessage {
+    Snapshot {
+        bids: Vec<BookEntry>,
+        asks: Vec<BookEntry>,
+    },
+    /// Levels changed on both sides. A zero amount removes the level
+    Update {
+        bids: Vec<BookEntry>,
+        asks: Vec<BookEntry>,
+    },
+}
+
+/// Invariant of the book broken by a frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BookError {
+    NegativeAmount {
+        price: Decimal,
+        amount: Decimal,
+    },
+    /// The same price appears twice on a side of a frame
+    RepeatedLevel {
+        price: Decimal,
+    },
+    /// The best bid is not below the best ask
+    Crossed {
+        bid: Decimal,
+        ask: Decimal,
+    },
+}
+
+impl Display for BookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BookError::NegativeAmount { price, amount } => {
+                write!(f, "negative amount {} at {}", amount, price)
+            }
+            BookError::RepeatedLevel { price } => write!(f, "level {} repeated", price),
+            BookError::Crossed { bid, ask } => write!(f, "book crossed, bid {} ask {}", bid, ask),
+        }
+    }
+}
+
+impl std::error::Error for BookError {}
+
+#[derive(Clone, Debug, Default)]
+pub struct OrderBook {
+    /// Amount of every price level
+    bids: OrdMap<Decimal, Decimal>,
+    asks: OrdMap<Decimal, Decimal>,
+    best_bid: Option<BookEntry>,
+    best_ask: Option<BookEntry>,
+}
+
+impl OrderBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Book holding the `(price, amount)` levels of `bids` and `asks`
+    #[cfg(test)]
+    pub fn with_levels(bids: &[(Decimal, Decimal)], asks: &[(Decimal, Decimal)]) -> Self {
+        let mut book = Self::new();
+        book.update(OrderBookMessage::Snapshot {
+            bids: levels(bids),
+            asks: levels(asks),
+        })
+        .expect("invalid levels");
+        book
+    }
+
+    pub fn best_ask(&self) -> Option<&BookEntry> {
+        self.best_ask.as_ref()
+    }
+
+    pub fn best_bid(&self) -> Option<&BookEntry> {
+        self.best_bid.as_ref()
+    }
+
+    /// Bids from the best, the highest price
+    pub fn bids(&self) -> impl Iterator<Item = BookEntry> + '_ {
+        self.bids.iter().rev().map(entry)
+    }
+
+    /// Asks from the best, the lowest price
+    pub fn asks(&self) -> impl Iterator<Item = BookEntry> + '_ {
+        self.asks.iter().map(entry)
+    }
+
+    pub fn mid_price(&self) -> Option<Decimal> {
+        Some((self.best_bid()?.price + self.best_ask()?.price) / dec!(2))
+    }
+
+    /// Volume weighted price paid to buy `amount` walking the asks. `None` if
+    /// the book is not deep enough.
+    pub fn buy_price(&self, amount: Decimal) -> Option<Decimal> {
+        vwap(self.asks(), amount)
+    }
+
+    /// Volume weighted price received to sell `amount` walking the bids.
+    /// `None` if the book is not deep enough.
+    pub fn sell_price(&self, amount: Decimal) -> Option<Decimal> {
+        vwap(self.bids(), amount)
+    }
+
+    /// Maximum amount that can be bought walking the asks spending at most
+    /// `quote`
+    pub fn buy_amount(&self, quote: Decimal) -> Decimal {
+        let mut quote = quote;
+        let mut amount = dec!(0);
+
+        for ask in self.asks() {
+            let level_quote = ask.amount * ask.price;
+            if level_quote >= quote {
+                return amount + quote / ask.price;
+            }
+            quote -= level_quote;
+            amount += ask.amount;
+        }
+
+        amount
+    }
+
+    /// Ask levels consumed when buying `amount`, with the amount left on them
+    pub fn take_asks(&self, amount: Decimal) -> Vec<BookEntry> {
+        take(self.asks(), amount)
+    }
+
+    /// Bid levels consumed when selling `amount`, with the amount left on them
+    pub fn take_bids(&self, amount: Decimal) -> Vec<BookEntry> {
+        take(self.bids(), amount)
+    }
+
+    /// Apply a whole frame. Snapshots replace the book, updates set every
+    /// level they carry. A frame with a negative amount or a repeated level
+    /// is not applied, a frame crossing the book is.
+    pub(super) fn update(&mut self, update: OrderBookMessage) -> Result<(), BookError> {
+        let (bids, asks, snapshot) = match update {
+            OrderBookMessage::Snapshot { bids, asks } => (bids, asks, true),
+            OrderBookMessage::Update { bids, asks } => (bids, asks, false),
+        };
+        check_levels(&bids)?;
+        check_levels(&asks)?;
+
+        if snapshot {
+            self.bids.clear();
+            self.asks.clear();
+        }
+        for level in bids {
+            set_level(&mut self.bids, level);
+        }
+        for level in asks {
+            set_level(&mut self.asks, level);
+        }
+
+        self.best_bid = self
+            .bids
+            .get_max()
+            .map(|(price, amount)| entry((price, amount)));
+        self.best_ask = self
+            .asks
+            .get_min()
+            .map(|(price, amount)| entry((price, amount)));
+
+        match (&self.best_bid, &self.best_ask) {
+            (Some(bid), Some(ask)) if bid.price >= ask.price => Err(BookError::Crossed {
+                bid: bid.price,
+                ask: ask.price,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn check_levels(levels: &[BookEntry]) -> Result<(), BookError> {
+    let mut prices = BTreeSet::new();
+    for level in levels {
+        if level.amount < dec!(0) {
+            return Err(BookError::NegativeAmount {
+                price: level.price,
+                amount: level.amount,
+            });
+        }
+        if !prices.insert(level.price) {
+            return Err(BookError::RepeatedLevel { price: level.price });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+fn levels(levels: &[(Decimal, Decimal)]) -> Vec<BookEntry> {
+    levels
+        .iter()
+        .map(|&(price, amount)| BookEntry { price, amount })
+        .collect()
+}
+
+fn entry((price, amount): (&Decimal, &Decimal)) -> BookEntry {
+    BookEntry {
+        price: *price,
+        amount: *amount,
+    }
+}
+
+/// Set the amount of a level. A zero amount removes the level
+fn set_level(levels: &mut OrdMap<Decimal, Decimal>, level: BookEntry) {
+    if level.amount.is_zero() {
+        levels.remove(&level.price);
+    } else {
+        levels.insert(level.price, level.amount);
+    }
+}
+
+fn vwap(levels: impl Iterator<Item = BookEntry>, amount: Decimal) -> Option<Decimal> {
+    let mut levels = levels.peekable();
+    if amount.is_zero() {
+        return levels.peek().map(|level| level.price);
+    }
+
+    let mut left = amount;
+    let mut cost = dec!(0);
+    for level in levels {
+        let filled = left.min(level.amount);
+        cost += filled * level.price;
+        left -= filled;
+        if left.is_zero() {
+            return Some(cost / amount);
+        }
+    }
+
+    None
+}
+
+fn take(levels: impl Iterator<Item = BookEntry>, amount: Decimal) -> Vec<BookEntry> {
+    let mut left = amount;
+    let mut taken = Vec::new();
+    for level in levels {
+        if left <= dec!(0) {
+            break;
+        }
+        let filled = left.min(level.amount);
+        left -= filled;
+        taken.push(BookEntry {
+            price: level.price,
+            amount: level.amount - filled,
+        });
+    }
+
+    taken
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(bids: &[(Decimal, Decimal)], asks: &[(Decimal, Decimal)]) -> OrderBookMessage {
+        OrderBookMessage::Snapshot {
+            bids: levels(bids),
+            asks: levels(asks),
+        }
+    }
+
+    fn update(bids: &[(Decimal, Decimal)], asks: &[(Decimal, Decimal)]) -> OrderBookMessage {
+        OrderBookMessage::Update {
+            bids: levels(bids),
+            asks: levels(asks),
+        }
+    }
+
+    fn book() -> OrderBook {
+        OrderBook::with_levels(
+            &[(dec!(99), dec!(1)), (dec!(98), dec!(2))],
+            &[(dec!(101), dec!(1)), (dec!(102), dec!(2))],
+        )
+    }
+
+    #[test]
+    fn sides_are_sorted_from_the_best() {
+        let book = book();
+
+        assert_eq!(book.best_bid().unwrap().price, dec!(99));
+        assert_eq!(book.best_ask().unwrap().price, dec!(101));
+        assert_eq!(
+            book.bids().map(|level| level.price).collect::<Vec<_>>(),
+            [dec!(99), dec!(98)]
+        );
+        assert_eq!(
+            book.asks().map(|level| level.price).collect::<Vec<_>>(),
+            [dec!(101), dec!(102)]
+        );
+        assert_eq!(book.mid_price(), Some(dec!(100)));
+    }
+
+    #[test]
+    fn updates_set_and_remove_levels() {
+        let mut book = book();
+        book.update(update(
+            &[(dec!(99), dec!(0)), (dec!(100), dec!(3))],
+            &[(dec!(101), dec!(0))],
+        ))
+        .unwrap();
+
+        assert_eq!(book.best_bid().unwrap().price, dec!(100));
+        assert_eq!(book.best_bid().unwrap().amount, dec!(3));
+        assert_eq!(book.best_ask().unwrap().price, dec!(102));
+        assert_eq!(book.bids().count(), 2);
+        assert_eq!(book.asks().count(), 1);
+    }
+
+    #[test]
+    fn snapshots_replace_the_book() {
+        let mut book = book();
+        book.update(snapshot(&[(dec!(50), dec!(1))], &[])).unwrap();
+
+        assert_eq!(book.bids().count(), 1);
+        assert!(book.best_ask().is_none());
+        assert!(book.mid_price().is_none());
+    }
+
+    #[test]
+    fn negative_amounts_are_not_applied() {
+        let mut book = book();
+        let error = book
+            .update(update(&[(dec!(100), dec!(1))], &[(dec!(103), dec!(-1))]))
+            .unwrap_err();
+
+        assert_eq!(
+            error,
+            BookError::NegativeAmount {
+                price: dec!(103),
+                amount: dec!(-1)
+            }
+        );
+        assert_eq!(book.best_bid().unwrap().price, dec!(99));
+        assert_eq!(book.asks().count(), 2);
+    }
+
+    #[test]
+    fn repeated_levels_are_not_applied() {
+        let mut book = book();
+        let error = book
+            .update(update(&[(dec!(97), dec!(1)), (dec!(97), dec!(2))], &[]))
+            .unwrap_err();
+
+        assert_eq!(error, BookError::RepeatedLevel { price: dec!(97) });
+        assert_eq!(book.bids().count(), 2);
+    }
+
+    #[test]
+    fn crossed_books_are_reported_and_applied() {
+        let mut book = book();
+        let error = book
+            .update(update(&[(dec!(101), dec!(1))], &[]))
+            .unwrap_err();
+
+        assert_eq!(
+            error,
+            BookError::Crossed {
+                bid: dec!(101),
+                ask: dec!(101)
+            }
+        );
+        assert_eq!(book.best_bid().unwrap().price, dec!(101));
+    }
+
+    #[test]
+    fn prices_walk_the_book() {
+        let book = book();
+
+        assert_eq!(book.buy_price(dec!(0)), Some(dec!(101)));
+        assert_eq!(book.buy_price(dec!(2)), Some(dec!(101.5)));
+        assert_eq!(book.sell_price(dec!(3)), Some(dec!(295) / dec!(3)));
+        assert_eq!(book.buy_price(dec!(4)), None);
+        assert_eq!(book.buy_amount(dec!(101) + dec!(102)), dec!(2));
+        assert_eq!(book.buy_amount(dec!(1000)), dec!(3));
+
+        let taken = book.take_bids(dec!(1.5));
+        assert_eq!(taken.len(), 2);
+        assert_eq!(taken[0].amount, dec!(0));
+        assert_eq!(taken[1].amount, dec!(1.5));
+    }
+}
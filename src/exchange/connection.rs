@@ -0,0 +1,540 @@
+//! WebSocket connection to an exchange
+//!
+//! Every exchange keeps one connection for as long as it lives, shared by all
+//! its subscriptions. A lost connection is retried after an exponential
+//! backoff with jitter and the channels are subscribed again once it is back.
+//! A heartbeat is sent periodically, a connection silent for longer than the
+//! idle timeout is considered lost.
+//! Every change of state is sent to the feed in order with the frames.
+
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::anyhow;
+use futures_util::{SinkExt, StreamExt};
+use rand::Rng;
+use tokio::{net::TcpStream, sync::mpsc, time::Instant};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+use crate::capture::Recorder;
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Timing of the connections
+#[derive(Clone, Debug)]
+pub struct ConnectionConfig {
+    /// Period of the heartbeats
+    pub heartbeat: Duration,
+    /// Time without any message after which the connection is dropped
+    pub idle_timeout: Duration,
+    /// Delay before the first reconnection, doubled after every failure
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(30),
+            min_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ConnectionConfig {
+    /// Delay before reconnecting after `failures` failed connections in a
+    /// row, randomized between half and all of the backoff so the clients do
+    /// not reconnect all at once
+    fn backoff(&self, failures: u32) -> Duration {
+        let backoff = self
+            .min_backoff
+            .saturating_mul(2u32.saturating_pow(failures))
+            .min(self.max_backoff);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// State of the connection to an exchange
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Connected and subscribed
+    Connected,
+    /// The connection was lost or could not be opened, the next attempt is
+    /// made in `retry_in`
+    Disconnected { reason: String, retry_in: Duration },
+}
+
+impl Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionState::Connected => write!(f, "connected"),
+            ConnectionState::Disconnected { reason, retry_in } => write!(
+                f,
+                "disconnected ({}), reconnecting in {:.1}s",
+                reason,
+                retry_in.as_secs_f64()
+            ),
+        }
+    }
+}
+
+/// Item sent by a connection to its feed
+#[derive(Debug)]
+pub(super) enum Incoming<T> {
+    /// Frame parsed, with the time it was received
+    Message(T, SystemTime),
+    Connection(ConnectionState),
+}
+
+/// Messages of the protocol of an exchange
+pub(super) trait Protocol: Send + Sync + 'static {
+    /// Subscription to the channel `id`, or unsubscription if `subscribe` is
+    /// false
+    fn subscription(&self, id: &str, subscribe: bool) -> Message;
+    /// Sent every heartbeat period to keep the connection alive
+    fn heartbeat(&self) -> Message;
+}
+
+/// Change of the subscriptions of a connection
+#[derive(Debug)]
+enum Command {
+    Subscribe(String),
+    Unsubscribe(String),
+    /// Subscribe again, for a new snapshot
+    Resync(String),
+}
+
+/// Handle to a running connection. The connection stops once every handle
+/// is dropped.
+#[derive(Clone, Debug)]
+pub(super) struct ConnectionHandle {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl ConnectionHandle {
+    /// Subscribe to the channel `id`, now and after every reconnection
+    pub fn subscribe(&self, id: &str) {
+        self.send(Command::Subscribe(id.to_string()));
+    }
+
+    pub fn unsubscribe(&self, id: &str) {
+        self.send(Command::Unsubscribe(id.to_string()));
+    }
+
+    /// Ask for a new snapshot of the channel `id`
+    pub fn resync(&self, id: &str) {
+        self.send(Command::Resync(id.to_string()));
+    }
+
+    fn send(&self, command: Command) {
+        // The connection only stops once the feed is gone
+        let _ = self.commands.send(command);
+    }
+}
+
+pub(super) struct Connection<P> {
+    /// Name of the exchange, in the captures and logs
+    name: &'static str,
+    url: String,
+    protocol: P,
+    config: ConnectionConfig,
+    recorder: Option<Recorder>,
+    /// Channels subscribed, again after every reconnection
+    subscriptions: BTreeSet<String>,
+}
+
+impl<P: Protocol> Connection<P> {
+    pub fn new(name: &'static str, url: String, protocol: P, config: ConnectionConfig) -> Self {
+        Self {
+            name,
+            url,
+            protocol,
+            config,
+            recorder: None,
+            subscriptions: BTreeSet::new(),
+        }
+    }
+
+    /// Capture every frame received with `recorder`
+    pub fn with_recorder(mut self, recorder: Option<Recorder>) -> Self {
+        self.recorder = recorder;
+        self
+    }
+
+    /// Open the connection in the background. The frames parsed by `parse`
+    /// are sent to `channel` until it is closed.
+    pub fn spawn<T: Send + 'static>(
+        self,
+        parse: fn(&str) -> Option<T>,
+        channel: mpsc::Sender<Incoming<T>>,
+    ) -> ConnectionHandle {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(self.run(parse, channel, receiver));
+        ConnectionHandle { commands }
+    }
+
+    async fn run<T>(
+        mut self,
+        parse: fn(&str) -> Option<T>,
+        channel: mpsc::Sender<Incoming<T>>,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+    ) {
+        let mut failures = 0;
+
+        loop {
+            let reason = match connect_async(&self.url).await {
+                Ok((wss_stream, _)) => {
+                    match self
+                        .serve(wss_stream, parse, &channel, &mut commands, &mut failures)
+                        .await
+                    {
+                        Ok(()) => return,
+                        Err(err) => err,
+                    }
+                }
+                Err(err) => err.into(),
+            };
+
+            let retry_in = self.config.backoff(failures);
+            failures = failures.saturating_add(1);
+            tracing::debug!(
+                "{} connection failed {} times in a row",
+                self.name,
+                failures
+            );
+            let state = ConnectionState::Disconnected {
+                reason: reason.to_string(),
+                retry_in,
+            };
+            if channel.send(Incoming::Connection(state)).await.is_err() {
+                return;
+            }
+            tokio::time::sleep(retry_in).await;
+        }
+    }
+
+    /// Apply `command` to the subscriptions. Returns the messages to send to
+    /// the exchange
+    fn command(&mut self, command: Command) -> Vec<Message> {
+        match command {
+            Command::Subscribe(id) if self.subscriptions.insert(id.clone()) => {
+                vec![self.protocol.subscription(&id, true)]
+            }
+            Command::Unsubscribe(id) if self.subscriptions.remove(&id) => {
+                vec![self.protocol.subscription(&id, false)]
+            }
+            // Subscribing again sends a new snapshot
+            Command::Resync(id) if self.subscriptions.contains(&id) => vec![
+                self.protocol.subscription(&id, false),
+                self.protocol.subscription(&id, true),
+            ],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Subscribe and forward the frames of an open connection. Returns an
+    /// error once the connection is lost, `Ok` if the feed is gone.
+    async fn serve<T>(
+        &mut self,
+        mut wss_stream: WsStream,
+        parse: fn(&str) -> Option<T>,
+        channel: &mpsc::Sender<Incoming<T>>,
+        commands: &mut mpsc::UnboundedReceiver<Command>,
+        failures: &mut u32,
+    ) -> anyhow::Result<()> {
+        // The commands received while disconnected only change the
+        // subscriptions, every one is sent below
+        while let Ok(command) = commands.try_recv() {
+            self.command(command);
+        }
+        for id in &self.subscriptions {
+            wss_stream
+                .send(self.protocol.subscription(id, true))
+                .await?;
+        }
+        if channel
+            .send(Incoming::Connection(ConnectionState::Connected))
+            .await
+            .is_err()
+        {
+            return Ok(());
+        }
+
+        let mut heartbeat = tokio::time::interval_at(
+            Instant::now() + self.config.heartbeat,
+            self.config.heartbeat,
+        );
+        let idle = tokio::time::sleep(self.config.idle_timeout);
+        tokio::pin!(idle);
+
+        loop {
+            let message = tokio::select! {
+                message = wss_stream.next() => match message {
+                    Some(message) => message?,
+                    None => return Err(anyhow!("connection closed by the exchange")),
+                },
+                _ = heartbeat.tick() => {
+                    wss_stream.send(self.protocol.heartbeat()).await?;
+                    continue;
+                }
+                _ = &mut idle => {
+                    return Err(anyhow!(
+                        "no message for {:.1}s",
+                        self.config.idle_timeout.as_secs_f64()
+                    ));
+                }
+                command = commands.recv() => {
+                    let Some(command) = command else {
+                        return Ok(());
+                    };
+                    for message in self.command(command) {
+                        wss_stream.send(message).await?;
+                    }
+                    continue;
+                }
+                _ = channel.closed() => return Ok(()),
+            };
+            // Any message, heartbeat answers included, shows the connection
+            // is alive
+            idle.as_mut()
+                .reset(Instant::now() + self.config.idle_timeout);
+            *failures = 0;
+
+            if !(message.is_text() || message.is_binary()) {
+                continue;
+            }
+            // At the moment if we fail to convert in string ignore the
+            // message
+            let Ok(message) = message.into_text() else {
+                continue;
+            };
+
+            if let Some(recorder) = &self.recorder {
+                recorder.record(self.name, &message);
+            }
+
+            if let Some(msg) = parse(&message) {
+                if channel
+                    .send(Incoming::Message(msg, SystemTime::now()))
+                    .await
+                    .is_err()
+                {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::mock::{MockHandle, MockServer};
+
+    /// Protocol of the mock servers, answering `ping` heartbeats only
+    struct TestProtocol {
+        heartbeat: &'static str,
+    }
+
+    impl Protocol for TestProtocol {
+        fn subscription(&self, id: &str, subscribe: bool) -> Message {
+            let op = if subscribe {
+                "subscribe"
+            } else {
+                "unsubscribe"
+            };
+            Message::Text(json!({"op": op, "data": [id]}).to_string())
+        }
+
+        fn heartbeat(&self) -> Message {
+            Message::Text(json!({"op": self.heartbeat}).to_string())
+        }
+    }
+
+    fn config() -> ConnectionConfig {
+        ConnectionConfig {
+            heartbeat: Duration::from_millis(20),
+            idle_timeout: Duration::from_millis(100),
+            min_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+        }
+    }
+
+    fn parse(frame: &str) -> Option<String> {
+        Some(frame.to_string())
+    }
+
+    /// Connect to a silent mock server, subscribed to `ids`
+    async fn connect(
+        heartbeat: &'static str,
+        config: ConnectionConfig,
+        ids: &[&str],
+    ) -> (
+        MockHandle,
+        ConnectionHandle,
+        mpsc::Receiver<Incoming<String>>,
+    ) {
+        let server = MockServer::bind("127.0.0.1:0").await.unwrap();
+        let url = server.url().unwrap();
+        let server = server.serve(Vec::new(), Duration::from_millis(10));
+
+        let (sender, receiver) = mpsc::channel(100);
+        let handle =
+            Connection::new("test", url, TestProtocol { heartbeat }, config).spawn(parse, sender);
+        for id in ids {
+            handle.subscribe(id);
+        }
+        (server, handle, receiver)
+    }
+
+    /// Next change of the connection, skipping the frames
+    async fn next_state(receiver: &mut mpsc::Receiver<Incoming<String>>) -> ConnectionState {
+        loop {
+            let incoming = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .expect("connection stalled")
+                .expect("connection stopped");
+            if let Incoming::Connection(state) = incoming {
+                return state;
+            }
+        }
+    }
+
+    /// Wait until the messages received by `server` pass `done`
+    async fn wait_for(server: &MockHandle, done: impl Fn(&[String]) -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !done(&server.received()) {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("message not received");
+    }
+
+    fn subscriptions(received: &[String], id: &str) -> usize {
+        let subscription = json!({"op": "subscribe", "data": [id]}).to_string();
+        received
+            .iter()
+            .filter(|message| **message == subscription)
+            .count()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit_with_jitter() {
+        let config = config();
+        for (failures, backoff) in [(0, 10), (1, 20), (2, 40), (3, 50), (40, 50)] {
+            let backoff = Duration::from_millis(backoff);
+            for _ in 0..100 {
+                let delay = config.backoff(failures);
+                assert!(delay >= backoff / 2 && delay <= backoff, "{:?}", delay);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn heartbeats_keep_the_connection_alive() {
+        let (server, _handle, mut receiver) = connect("ping", config(), &["a"]).await;
+        assert_eq!(next_state(&mut receiver).await, ConnectionState::Connected);
+
+        // Only the answers to the heartbeats are received, for three idle
+        // timeouts
+        tokio::time::sleep(config().idle_timeout * 3).await;
+        while let Ok(incoming) = receiver.try_recv() {
+            assert!(matches!(incoming, Incoming::Message(..)));
+        }
+        let heartbeats = server
+            .received()
+            .iter()
+            .filter(|message| message.contains("ping"))
+            .count();
+        assert!(heartbeats >= 10, "{} heartbeats", heartbeats);
+    }
+
+    #[tokio::test]
+    async fn silent_connections_time_out() {
+        // No heartbeat before the idle timeout
+        let config = ConnectionConfig {
+            heartbeat: Duration::from_secs(10),
+            ..config()
+        };
+        let (server, _handle, mut receiver) = connect("ping", config.clone(), &["a"]).await;
+        assert_eq!(next_state(&mut receiver).await, ConnectionState::Connected);
+        let connected_at = Instant::now();
+
+        let state = next_state(&mut receiver).await;
+        assert!(connected_at.elapsed() >= config.idle_timeout);
+        let ConnectionState::Disconnected { reason, retry_in } = state else {
+            panic!("{:?}", state);
+        };
+        assert!(reason.starts_with("no message"), "{}", reason);
+        assert!(retry_in <= config.min_backoff);
+        assert!(!server
+            .received()
+            .iter()
+            .any(|message| message.contains("ping")));
+
+        // Reconnected after the backoff
+        assert_eq!(next_state(&mut receiver).await, ConnectionState::Connected);
+    }
+
+    #[tokio::test]
+    async fn unanswered_heartbeats_time_out() {
+        let (server, _handle, mut receiver) = connect("unanswered", config(), &["a"]).await;
+        assert_eq!(next_state(&mut receiver).await, ConnectionState::Connected);
+
+        let state = next_state(&mut receiver).await;
+        assert!(
+            matches!(&state, ConnectionState::Disconnected { reason, .. } if reason.starts_with("no message")),
+            "{:?}",
+            state
+        );
+        let heartbeats = server
+            .received()
+            .iter()
+            .filter(|message| message.contains("unanswered"))
+            .count();
+        assert!(heartbeats >= 3, "{} heartbeats", heartbeats);
+    }
+
+    #[tokio::test]
+    async fn channels_are_subscribed_again_after_a_reconnection() {
+        let (server, handle, mut receiver) = connect("ping", config(), &["a", "b", "c"]).await;
+        assert_eq!(next_state(&mut receiver).await, ConnectionState::Connected);
+        handle.unsubscribe("c");
+        wait_for(&server, |received| {
+            received
+                .iter()
+                .any(|message| message.contains("unsubscribe"))
+        })
+        .await;
+
+        server.drop_connections();
+        assert!(matches!(
+            next_state(&mut receiver).await,
+            ConnectionState::Disconnected { .. }
+        ));
+        assert_eq!(next_state(&mut receiver).await, ConnectionState::Connected);
+        wait_for(&server, |received| subscriptions(received, "b") == 2).await;
+        // Nothing else is subscribed before the first heartbeat of the new
+        // connection
+        let resubscribed = server.received().len();
+        wait_for(&server, |received| {
+            received[resubscribed..]
+                .iter()
+                .any(|message| message.contains("ping"))
+        })
+        .await;
+
+        let received = server.received();
+        assert_eq!(subscriptions(&received, "a"), 2);
+        assert_eq!(subscriptions(&received, "b"), 2);
+        assert_eq!(subscriptions(&received, "c"), 1);
+    }
+}
@@ -1,174 +1,239 @@
 //! DyDx exchange implementation
 
-use std::{collections::HashMap, fmt::Display, task::Poll};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    task::{ready, Poll},
+    time::SystemTime,
+};
 
-use async_trait::async_trait;
-use futures_util::{SinkExt, Stream, StreamExt};
+use futures_util::Stream;
 use rust_decimal::Decimal;
 use serde::Deserialize;
 use serde_json::json;
-use tokio::sync::mpsc;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::tungstenite::Message;
 
-use super::{BookEntry, Exchange, OrderBook, OrderBookMessage, Symbol};
+use super::{
+    connection::Protocol,
+    registry::{BookRegistry, Polled},
+    BookEntry, BookHandle, BookState, ConnectionState, FeedSource, MarketEvent, MarketFeed,
+    OrderBookMessage, Symbol, Venue, ViolationPolicy,
+};
 
-const WSS_URL: &str = "wss://indexer.dydx.trade/v4/ws";
+#[cfg(feature = "dydx-live")]
+mod trading;
+#[cfg(feature = "dydx-live")]
+pub use trading::{DyDxAccount, DyDxClient};
+
+const NAME: &str = "DyDx";
 
 pub struct DyDx {
-    receiver: mpsc::Receiver<BookRawMessage>,
-    sender: mpsc::Sender<BookRawMessage>,
-    order_book: OrderBook,
-    persistent_trades: bool,
-    fee: Decimal,
+    books: BookRegistry<BookRawMessage, Offsets>,
+    /// Id of the last message of the connection, shared by every channel
+    message_id: Option<usize>,
+}
+
+/// Offsets of the levels of both sides of a book
+#[derive(Default)]
+struct Offsets {
+    bids: LevelOffsets,
+    asks: LevelOffsets,
+}
+
+/// Offset of the last update of every price level of a side. Updates older
+/// than the level are dropped
+#[derive(Default)]
+struct LevelOffsets(HashMap<Decimal, u64>);
+
+impl LevelOffsets {
+    /// Keep the levels newer than the last update of their price. The offset
+    /// of a removed level is forgotten with it
+    fn filter(&mut self, levels: Vec<RawLevel>) -> Vec<BookEntry> {
+        levels
+            .into_iter()
+            .filter_map(|level| {
+                let (entry, offset) = level.parse()?;
+                if let Some(offset) = offset {
+                    if self
+                        .0
+                        .get(&entry.price)
+                        .is_some_and(|last| *last >= offset)
+                    {
+                        tracing::debug!(price = %entry.price, offset, "{} stale level dropped", NAME);
+                        return None;
+                    }
+                    if entry.amount.is_zero() {
+                        self.0.remove(&entry.price);
+                    } else {
+                        self.0.insert(entry.price, offset);
+                    }
+                }
+                Some(entry)
+            })
+            .collect()
+    }
 }
 
 impl DyDx {
-    pub fn new(persistent_trades: bool, fee: Decimal) -> Self {
-        let (sender, receiver) = mpsc::channel(10000);
+    /// Create the exchange, connected at once if `source` is live
+    pub fn new(source: FeedSource, policy: ViolationPolicy) -> Self {
         Self {
-            receiver,
-            sender,
-            order_book: OrderBook::new(),
-            persistent_trades,
-            fee,
+            books: BookRegistry::new(
+                NAME,
+                Venue::DyDx,
+                source,
+                IndexerChannel("v4_orderbook"),
+                parse_message,
+                policy,
+            ),
+            message_id: None,
         }
     }
-}
 
-#[async_trait]
-impl Exchange for DyDx {
-    fn order_book_subscribe(&self, symbol: &Symbol) {
-        // This is very ugly and should not be done. The connection with the
-        // exchange should be created when the object is created. The method
-        // should only send the subscription to the order book channel
-        let symbol = symbol.clone();
-        tokio::spawn(handle_wss(symbol, self.sender.clone()));
-    }
+    /// Apply a message to the book of its market. Returns the event of the
+    /// book, `None` if the message is not of a subscribed market or is
+    /// ignored
+    fn process(&mut self, mut msg: BookRawMessage, received_at: SystemTime) -> Option<MarketEvent> {
+        let message_id = msg.message_id;
+        let last_message_id = self.message_id.replace(message_id);
 
-    async fn handle_persistent_buy(&self, amount: Decimal, price: Decimal) -> anyhow::Result<()> {
-        if !self.persistent_trades {
-            return Ok(());
+        // The ids are shared by every channel of the connection. A missed
+        // message may have been of any book, every one is resynced
+        if msg.msg_type == "channel_data"
+            && last_message_id.is_some_and(|last| message_id != last + 1)
+        {
+            tracing::warn!(
+                message_id,
+                last_message_id,
+                "{} message out of sequence, resubscribing",
+                NAME
+            );
+            self.books.resync_all();
+            self.books.publish_all(received_at, Some(message_id), None);
+            return None;
         }
 
-        let Some(ask) = self.order_book.best_ask() else {
-            return Ok(());
-        };
+        let symbol = Symbol(msg.id.clone());
+        // Connection messages and the markets not subscribed, or not
+        // anymore, only move the ids on
+        let book = self.books.book(&symbol)?;
 
-        // Update the entry
-        let entry = BookEntry {
-            price,
-            amount: ask.amount - amount,
+        let update = match msg.msg_type.as_ref() {
+            _ if book.state == BookState::Halted => return None,
+            // Snapshot, the ids go on from it
+            "subscribed" => {
+                if book.state == BookState::Resyncing {
+                    tracing::info!("{} {} book resynced", NAME, symbol);
+                    book.state = BookState::Live;
+                }
+                book.venue = Offsets::default();
+                let bids = msg.contents.remove("bids").unwrap_or_default();
+                let asks = msg.contents.remove("asks").unwrap_or_default();
+                OrderBookMessage::Snapshot {
+                    bids: book.venue.bids.filter(bids),
+                    asks: book.venue.asks.filter(asks),
+                }
+            }
+            // The book is rebuilt from the next snapshot
+            "channel_data" if book.state == BookState::Resyncing => return None,
+            "channel_data" => {
+                let bids = msg.contents.remove("bids").unwrap_or_default();
+                let asks = msg.contents.remove("asks").unwrap_or_default();
+                OrderBookMessage::Update {
+                    bids: book.venue.bids.filter(bids),
+                    asks: book.venue.asks.filter(asks),
+                }
+            }
+            // Unsubscription messages only move the ids on
+            _ => return None,
         };
-        let mut update = BookRawMessage::default();
-        update.contents.insert("asks".to_string(), vec![entry]);
-        self.sender.send(update).await?;
-        Ok(())
-    }
-
-    async fn handle_persistent_sell(&self, amount: Decimal, price: Decimal) -> anyhow::Result<()> {
-        if !self.persistent_trades {
-            return Ok(());
+        if let Err(err) = book.update(update) {
+            self.books.violation(&symbol, err);
         }
 
-        let Some(bid) = self.order_book.best_bid() else {
-            return Ok(());
-        };
+        let event = self.books.publish(&symbol, received_at)?;
+        Some(MarketEvent {
+            message_id: Some(message_id),
+            ..event
+        })
+    }
+}
 
-        // Update the entry
-        let entry = BookEntry {
-            price,
-            amount: bid.amount - amount,
-        };
-        let mut update = BookRawMessage::default();
-        update.contents.insert("bids".to_string(), vec![entry]);
-        self.sender.send(update).await?;
-        Ok(())
+impl MarketFeed for DyDx {
+    fn order_book_subscribe(&mut self, symbol: &Symbol) -> BookHandle {
+        self.books.subscribe(symbol)
     }
 
-    fn fee(&self) -> Decimal {
-        self.fee
+    fn order_book_unsubscribe(&mut self, symbol: &Symbol) {
+        self.books.unsubscribe(symbol);
+    }
+
+    fn push_frame(&self, frame: &str, received_at: SystemTime) -> anyhow::Result<()> {
+        self.books.push_frame(frame, received_at)
     }
 }
 
 impl Stream for DyDx {
-    type Item = (Option<BookEntry>, Option<BookEntry>);
+    type Item = MarketEvent;
 
     fn poll_next(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        match self.receiver.poll_recv(cx) {
-            Poll::Ready(Some(msg)) => {
-                let update = match (
-                    msg.contents.contains_key("asks"),
-                    msg.contents.contains_key("bids"),
-                ) {
-                    // Snapshot
-                    (true, true) => Some(OrderBookMessage::Snapshot {
-                        bids: msg.contents["bids"].clone(),
-                        asks: msg.contents["asks"].clone(),
-                    }),
-                    // Bid update
-                    (false, true) => {
-                        Some(OrderBookMessage::BidUpdate(msg.contents["bids"][0].clone()))
+        loop {
+            match ready!(self.books.poll_next(cx)) {
+                Some(Polled::Event(event)) => return Poll::Ready(Some(event)),
+                Some(Polled::Message(msg, received_at)) => {
+                    if let Some(event) = self.process(msg, received_at) {
+                        return Poll::Ready(Some(event));
                     }
-                    // Ask update
-                    (true, false) => {
-                        Some(OrderBookMessage::AskUpdate(msg.contents["asks"][0].clone()))
+                }
+                // The snapshots sent after reconnecting start the ids again
+                Some(Polled::Connection(connection)) => {
+                    if matches!(connection, ConnectionState::Disconnected { .. }) {
+                        self.message_id = None;
                     }
-                    // Both are empty, ignore
-                    _ => None,
-                };
-                if let Some(upd) = update {
-                    self.order_book.update(upd);
+                    self.books.connection(connection);
                 }
-                Poll::Ready(Some((
-                    self.order_book.best_bid().cloned(),
-                    self.order_book.best_ask().cloned(),
-                )))
+                None => return Poll::Ready(None),
             }
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => Poll::Pending,
         }
     }
 }
 
 impl Display for DyDx {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "DyDx")
+        write!(f, "{}", NAME)
     }
 }
 
-async fn handle_wss(symbol: Symbol, channel: mpsc::Sender<BookRawMessage>) {
-    loop {
-        //Connect to DyDx
-        let (mut wss_stream, _) = connect_async(WSS_URL).await.expect("Failed to connect");
-        // Send the order book subscription request
-        wss_stream
-            .send(Message::Text(
-                json!({"type":"subscribe", "channel":"v4_orderbook", "id":symbol.0}).to_string(),
-            ))
-            .await
-            .expect("Failed to send orderbook subscription");
-
-        wss_stream
-            .for_each(|message| async {
-                // If we receive an error close the connection and try to reconnect
-                let Ok(message) = message else {
-                    return;
-                };
-                // Again, at the moment if we fail to convert in string close
-                // the connection and try to reconnect
-                let Ok(message) = message.into_text() else {
-                    return;
-                };
-
-                match serde_json::from_str::<BookRawMessage>(&message) {
-                    Ok(msg) => channel.send(msg).await.unwrap(),
-                    Err(_) => tracing::debug!("received unknown message {:?}", message),
-                }
-            })
-            .await;
+/// Channel of the indexer, `v4_orderbook` subscribed by market or
+/// `v4_subaccounts` by account
+struct IndexerChannel(&'static str);
+
+impl Protocol for IndexerChannel {
+    fn subscription(&self, id: &str, subscribe: bool) -> Message {
+        let msg_type = if subscribe {
+            "subscribe"
+        } else {
+            "unsubscribe"
+        };
+        Message::Text(json!({"type": msg_type, "channel": self.0, "id": id}).to_string())
+    }
+
+    /// The indexer answers WebSocket pings
+    fn heartbeat(&self) -> Message {
+        Message::Ping(Vec::new())
+    }
+}
+
+fn parse_message(message: &str) -> Option<BookRawMessage> {
+    match serde_json::from_str::<BookRawMessage>(message) {
+        Ok(msg) => Some(msg),
+        Err(_) => {
+            tracing::debug!("received unknown message {:?}", message);
+            None
+        }
     }
 }
 
@@ -177,9 +242,193 @@ async fn handle_wss(symbol: Symbol, channel: mpsc::Sender<BookRawMessage>) {
 pub struct BookRawMessage {
     #[serde(alias = "type")]
     msg_type: String,
+    #[serde(default)]
     connection_id: String,
     message_id: usize,
+    #[serde(default)]
     channel: String,
+    #[serde(default)]
     id: String,
-    contents: HashMap<String, Vec<BookEntry>>,
+    #[serde(default)]
+    contents: HashMap<String, Vec<RawLevel>>,
+}
+
+/// Price level, `{"price", "size", "offset"}` in the snapshots and
+/// `[price, size, offset]` in the updates. The offset orders the updates of a
+/// level
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum RawLevel {
+    Object {
+        price: Decimal,
+        size: Decimal,
+        offset: Option<String>,
+    },
+    Array(Vec<String>),
+}
+
+impl RawLevel {
+    fn parse(self) -> Option<(BookEntry, Option<u64>)> {
+        let (price, amount, offset) = match self {
+            RawLevel::Object {
+                price,
+                size,
+                offset,
+            } => (price, size, offset),
+            RawLevel::Array(fields) => {
+                let mut fields = fields.into_iter();
+                (
+                    fields.next()?.parse().ok()?,
+                    fields.next()?.parse().ok()?,
+                    fields.next(),
+                )
+            }
+        };
+        let offset = match offset {
+            Some(offset) => Some(offset.parse().ok()?),
+            None => None,
+        };
+
+        Some((BookEntry { price, amount }, offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{FutureExt, StreamExt};
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    const BTC: &str = "BTC-USD";
+    const ETH: &str = "ETH-USD";
+
+    fn feed(markets: &[&str]) -> DyDx {
+        let mut feed = DyDx::new(FeedSource::Manual, ViolationPolicy::Resync);
+        for market in markets {
+            feed.order_book_subscribe(&Symbol(market.to_string()));
+        }
+        feed
+    }
+
+    /// Push `frame` and return the events of the feed
+    fn push(feed: &mut DyDx, frame: serde_json::Value) -> Vec<MarketEvent> {
+        feed.push_frame(&frame.to_string(), SystemTime::now())
+            .unwrap();
+        std::iter::from_fn(|| feed.next().now_or_never().flatten()).collect()
+    }
+
+    /// Snapshot of `market` with a bid at 99 and an ask at 101, both at offset
+    /// 10
+    fn snapshot(message_id: usize, market: &str) -> serde_json::Value {
+        json!({
+            "type": "subscribed",
+            "connection_id": "test",
+            "message_id": message_id,
+            "channel": "v4_orderbook",
+            "id": market,
+            "contents": {
+                "bids": [{"price": "99", "size": "1", "offset": "10"}],
+                "asks": [{"price": "101", "size": "1", "offset": "10"}],
+            },
+        })
+    }
+
+    fn update(message_id: usize, market: &str, bids: serde_json::Value) -> serde_json::Value {
+        json!({
+            "type": "channel_data",
+            "connection_id": "test",
+            "message_id": message_id,
+            "channel": "v4_orderbook",
+            "id": market,
+            "contents": {"bids": bids},
+        })
+    }
+
+    #[tokio::test]
+    async fn messages_in_sequence_update_the_book() {
+        let mut feed = feed(&[BTC]);
+        let events = push(&mut feed, snapshot(1, BTC));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].message_id, Some(1));
+        assert_eq!(events[0].state, BookState::Live);
+
+        let events = push(&mut feed, update(2, BTC, json!([["100", "2", "11"]])));
+        assert_eq!(events[0].message_id, Some(2));
+        assert_eq!(events[0].best_bid().unwrap().price, dec!(100));
+        assert_eq!(events[0].order_book.bids().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn sequence_gap_resyncs_every_book() {
+        let mut feed = feed(&[BTC, ETH]);
+        push(&mut feed, snapshot(1, BTC));
+        push(&mut feed, snapshot(2, ETH));
+
+        // Message 3 is missed, it could be of any market
+        let events = push(&mut feed, update(4, BTC, json!([["100", "2", "11"]])));
+        assert_eq!(events.len(), 2);
+        for event in &events {
+            assert_eq!(event.state, BookState::Resyncing);
+            assert_eq!(event.message_id, Some(4));
+            assert!(event.best_bid().is_none());
+        }
+
+        // The updates wait for the snapshots, which start the ids again
+        assert!(push(&mut feed, update(5, ETH, json!([["98", "1", "11"]]))).is_empty());
+        let events = push(&mut feed, snapshot(6, ETH));
+        assert_eq!(events[0].state, BookState::Live);
+        let events = push(&mut feed, update(7, ETH, json!([["98", "1", "11"]])));
+        assert_eq!(events[0].order_book.bids().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn sequence_gaps_do_not_halt_the_books() {
+        let mut feed = feed(&[BTC, ETH]);
+        push(&mut feed, snapshot(1, BTC));
+
+        // ETH never gets a snapshot, BTC does every time. Both stay resyncing
+        // past the resyncs allowed to a book at fault
+        let mut message_id = 1;
+        for _ in 0..10 {
+            message_id += 2;
+            let events = push(&mut feed, update(message_id, BTC, json!([])));
+            assert!(events
+                .iter()
+                .all(|event| event.state == BookState::Resyncing));
+            message_id += 1;
+            let events = push(&mut feed, snapshot(message_id, BTC));
+            assert_eq!(events[0].state, BookState::Live);
+        }
+        let events = push(&mut feed, snapshot(message_id + 1, ETH));
+        assert_eq!(events[0].state, BookState::Live);
+    }
+
+    #[tokio::test]
+    async fn stale_levels_are_dropped() {
+        let mut feed = feed(&[BTC]);
+        push(&mut feed, snapshot(1, BTC));
+
+        // Older than the level of the snapshot
+        let events = push(&mut feed, update(2, BTC, json!([["99", "5", "9"]])));
+        assert_eq!(events[0].best_bid().unwrap().amount, dec!(1));
+
+        let events = push(&mut feed, update(3, BTC, json!([["99", "5", "12"]])));
+        assert_eq!(events[0].best_bid().unwrap().amount, dec!(5));
+
+        // A removed level takes any offset again
+        push(&mut feed, update(4, BTC, json!([["99", "0", "13"]])));
+        let events = push(&mut feed, update(5, BTC, json!([["99", "3", "1"]])));
+        assert_eq!(events[0].best_bid().unwrap().amount, dec!(3));
+    }
+
+    #[tokio::test]
+    async fn crossed_book_is_resynced() {
+        let mut feed = feed(&[BTC]);
+        push(&mut feed, snapshot(1, BTC));
+
+        let events = push(&mut feed, update(2, BTC, json!([["101", "1", "11"]])));
+        assert_eq!(events[0].state, BookState::Resyncing);
+        assert!(events[0].best_bid().is_none());
+    }
 }
@@ -0,0 +1,575 @@
+//! dYdX v4 live execution
+//!
+//! dYdX v4 orders are Cosmos transactions signed with the account key. The
+//! bot places short-term orders: they live in the memory of the validators
+//! until filled, cancelled or past their `goodTilBlock`. Signing and
+//! broadcasting is delegated to a sidecar holding the key and connected to a
+//! validator. Its requests are authenticated with the API secret as bearer
+//! token:
+//!
+//! - `GET /height`: latest block height of the chain, `{"height": 123}`
+//! - `POST /orders`: broadcast a `MsgPlaceOrder`, see [`PlaceOrder`]
+//! - `DELETE /orders/{client_id}`: broadcast a `MsgCancelOrder` of a
+//!   short-term order, see [`CancelOrder`]
+//!
+//! The orders are answered with `{"clientId": 1, "txHash": "..."}` once the
+//! validator accepts their transaction. Errors are answered with any other
+//! status than 2xx and `{"error": "..."}`. Order status and fills are read
+//! from the `v4_subaccounts` channel of the indexer WebSocket.
+
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use rand::Rng;
+use reqwest::Method;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+
+use super::{
+    super::connection::{Connection, ConnectionHandle, Incoming},
+    IndexerChannel,
+};
+use crate::exchange::{
+    ConnectionConfig, ExecutionConfig, Fill, OrderExecutor, Side, Symbol, TimeInForce,
+};
+
+/// Orders not closed within this time are cancelled
+const ORDER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time to wait for the final status of a cancelled order
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(2);
+/// Blocks a short-term order stays valid, the most the protocol allows. Longer
+/// than [`ORDER_TIMEOUT`] and [`CANCEL_TIMEOUT`] together
+const GOOD_TIL_BLOCKS: u32 = 20;
+
+/// Body of `POST /orders`. The sidecar converts the size and the price, in
+/// base and quote token, to the quantums and subticks of the market.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaceOrder<'a> {
+    /// Id of the order chosen by the bot, unique among its open orders
+    client_id: u32,
+    /// Subaccount placing the order, as `address/number`
+    subaccount: &'a str,
+    market: &'a str,
+    /// `BUY` or `SELL`
+    side: &'a str,
+    size: Decimal,
+    price: Decimal,
+    /// `IOC`, or `GTC` for an order resting until its `good_til_block`
+    time_in_force: TimeInForce,
+    /// Last block the order can be filled in
+    good_til_block: u32,
+}
+
+/// Body of `DELETE /orders/{client_id}`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CancelOrder<'a> {
+    subaccount: &'a str,
+    /// Last block the cancellation can be included in, the protocol rejects
+    /// it after the `good_til_block` of the order
+    good_til_block: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OrderAccepted {
+    tx_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Height {
+    height: u32,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SubaccountContents {
+    #[serde(default)]
+    pub orders: Vec<OrderUpdate>,
+    #[serde(default)]
+    pub fills: Vec<FillUpdate>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderUpdate {
+    pub id: String,
+    pub client_id: String,
+    pub status: String,
+}
+
+impl OrderUpdate {
+    /// The order will not be filled any further
+    fn is_closed(&self) -> bool {
+        matches!(
+            self.status.as_str(),
+            "FILLED" | "CANCELED" | "BEST_EFFORT_CANCELED"
+        )
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FillUpdate {
+    pub order_id: String,
+    pub size: Decimal,
+    pub price: Decimal,
+}
+
+// Same envelope as the order book messages
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+struct SubaccountMessage {
+    #[serde(alias = "type")]
+    msg_type: String,
+    connection_id: String,
+    message_id: usize,
+    channel: String,
+    id: String,
+    contents: SubaccountContents,
+}
+
+/// Subaccount followed on the indexer. Its clones share the connection and
+/// the client ids, one is given to the client of every market
+#[derive(Clone)]
+pub struct DyDxAccount {
+    updates: broadcast::Sender<SubaccountContents>,
+    next_client_id: Arc<AtomicU32>,
+}
+
+impl DyDxAccount {
+    /// Start following the subaccount `subaccount` (`address/number`) on the
+    /// indexer at `indexer_url`. It has a connection of its own, not the one
+    /// of the order books: the `message_id` of the indexer is counted over
+    /// every channel of a connection, the subaccount updates would look like
+    /// gaps to the book feed and have it resync
+    pub fn follow(subaccount: &str, indexer_url: String, connection: ConnectionConfig) -> Self {
+        let (updates, _) = broadcast::channel(1000);
+        let (sender, receiver) = mpsc::channel(1000);
+        let connection = Connection::new(
+            "DyDx subaccount",
+            indexer_url,
+            IndexerChannel("v4_subaccounts"),
+            connection,
+        )
+        .spawn(parse_message, sender);
+        connection.subscribe(subaccount);
+        tokio::spawn(handle_subaccount(connection, receiver, updates.clone()));
+
+        // Client ids must not collide with the orders of previous runs
+        let first_client_id = rand::thread_rng().gen();
+
+        Self {
+            updates,
+            next_client_id: Arc::new(AtomicU32::new(first_client_id)),
+        }
+    }
+}
+
+pub struct DyDxClient {
+    http: reqwest::Client,
+    config: ExecutionConfig,
+    market: Symbol,
+    fee: Decimal,
+    /// Subaccount `config.key`, followed for the fills
+    account: DyDxAccount,
+}
+
+impl DyDxClient {
+    pub fn new(
+        config: ExecutionConfig,
+        account: DyDxAccount,
+        market: Symbol,
+        fee: Decimal,
+    ) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            config,
+            market,
+            fee,
+            account,
+        }
+    }
+
+    /// Place an order and wait until it is closed. Orders still open after
+    /// [`ORDER_TIMEOUT`] are cancelled. Returns what was filled, even if the
+    /// order could not be cancelled.
+    pub async fn execute(
+        &self,
+        side: Side,
+        amount: Decimal,
+        price: Decimal,
+    ) -> anyhow::Result<Fill> {
+        let client_id = self.account.next_client_id.fetch_add(1, Ordering::Relaxed);
+        // Subscribe before placing the order to not miss any update
+        let mut updates = self.account.updates.subscribe();
+
+        let height = self.request::<Height>(Method::GET, "/height", None).await?;
+        let good_til_block = height.height + GOOD_TIL_BLOCKS;
+        let market = self.market.to_string();
+        let order = PlaceOrder {
+            client_id,
+            subaccount: &self.config.key,
+            market: &market,
+            side: match side {
+                Side::Buy => "BUY",
+                Side::Sell => "SELL",
+            },
+            size: amount,
+            price,
+            time_in_force: self.config.time_in_force,
+            good_til_block,
+        };
+        let accepted = self
+            .request::<OrderAccepted>(
+                Method::POST,
+                "/orders",
+                Some(serde_json::to_string(&order)?),
+            )
+            .await?;
+        tracing::debug!("dydx order {} sent in {}", client_id, accepted.tx_hash);
+
+        let mut tracker = OrderTracker::new(client_id.to_string());
+        let closed = tokio::time::timeout(ORDER_TIMEOUT, tracker.wait_closed(&mut updates)).await;
+        if closed.is_err() {
+            // The order is placed, what it filled so far is not lost with the
+            // cancel request. Its updates are still followed, it may close
+            // on its own
+            let cancel = CancelOrder {
+                subaccount: &self.config.key,
+                good_til_block,
+            };
+            if let Err(err) = self
+                .request::<OrderAccepted>(
+                    Method::DELETE,
+                    &format!("/orders/{}", client_id),
+                    Some(serde_json::to_string(&cancel)?),
+                )
+                .await
+            {
+                tracing::error!("dydx order {} cancel failed: {}", client_id, err);
+            }
+            if tokio::time::timeout(CANCEL_TIMEOUT, tracker.wait_closed(&mut updates))
+                .await
+                .is_err()
+            {
+                tracing::warn!("dydx order {} status unknown after cancel", client_id);
+            }
+        }
+        tracing::debug!(
+            "dydx order {} {:?}, filled {}",
+            client_id,
+            tracker.status,
+            tracker.filled
+        );
+
+        Ok(tracker.fill())
+    }
+
+    async fn request<T: for<'de> Deserialize<'de>>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<String>,
+    ) -> anyhow::Result<T> {
+        let response = self
+            .http
+            .request(method, format!("{}{}", self.config.rest_url, path))
+            .bearer_auth(&self.config.secret)
+            .header("Content-Type", "application/json")
+            .body(body.unwrap_or_default())
+            .send()
+            .await?;
+
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            return Err(anyhow!("dydx request {} failed: {} {}", path, status, text));
+        }
+
+        Ok(serde_json::from_str(&text)?)
+    }
+}
+
+#[async_trait]
+impl OrderExecutor for DyDxClient {
+    fn fee(&self) -> Decimal {
+        self.fee
+    }
+
+    async fn execute_order(
+        &self,
+        side: Side,
+        amount: Decimal,
+        price: Decimal,
+    ) -> anyhow::Result<Fill> {
+        self.execute(side, amount, price).await
+    }
+}
+
+impl Display for DyDxClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DyDx")
+    }
+}
+
+/// Status and fills of one order, built from the subaccount updates
+struct OrderTracker {
+    client_id: String,
+    order_id: Option<String>,
+    status: Option<String>,
+    filled: Decimal,
+    cost: Decimal,
+}
+
+impl OrderTracker {
+    fn new(client_id: String) -> Self {
+        Self {
+            client_id,
+            order_id: None,
+            status: None,
+            filled: dec!(0),
+            cost: dec!(0),
+        }
+    }
+
+    async fn wait_closed(&mut self, updates: &mut broadcast::Receiver<SubaccountContents>) {
+        loop {
+            let contents = match updates.recv().await {
+                Ok(contents) => contents,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("missed {} dydx subaccount updates", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            if self.update(&contents) {
+                return;
+            }
+        }
+    }
+
+    /// Apply the updates of the order. Returns true once it is closed
+    fn update(&mut self, contents: &SubaccountContents) -> bool {
+        let mut closed = false;
+        // Orders first, fills refer to the indexer order id
+        for order in &contents.orders {
+            if order.client_id == self.client_id {
+                self.order_id = Some(order.id.clone());
+                self.status = Some(order.status.clone());
+                closed = order.is_closed();
+            }
+        }
+        for fill in &contents.fills {
+            if self.order_id.as_ref() == Some(&fill.order_id) {
+                self.filled += fill.size;
+                self.cost += fill.size * fill.price;
+            }
+        }
+
+        closed
+    }
+
+    fn fill(&self) -> Fill {
+        Fill {
+            amount: self.filled,
+            price: if self.filled.is_zero() {
+                dec!(0)
+            } else {
+                self.cost / self.filled
+            },
+        }
+    }
+}
+
+/// Publish the subaccount updates. The connection is kept open as long as
+/// this runs
+async fn handle_subaccount(
+    _connection: ConnectionHandle,
+    mut receiver: mpsc::Receiver<Incoming<SubaccountMessage>>,
+    updates: broadcast::Sender<SubaccountContents>,
+) {
+    while let Some(incoming) = receiver.recv().await {
+        let msg = match incoming {
+            Incoming::Message(msg, _) => msg,
+            Incoming::Connection(connection) => {
+                tracing::info!("dydx subaccount channel {}", connection);
+                continue;
+            }
+        };
+        for fill in &msg.contents.fills {
+            tracing::info!(
+                "dydx fill order {} size {} price {}",
+                fill.order_id,
+                fill.size,
+                fill.price
+            );
+        }
+        // Nobody waiting for updates is fine
+        let _ = updates.send(msg.contents);
+    }
+}
+
+fn parse_message(message: &str) -> Option<SubaccountMessage> {
+    match serde_json::from_str::<SubaccountMessage>(message) {
+        Ok(msg) => Some(msg),
+        Err(_) => {
+            tracing::debug!("received unknown message {:?}", message);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::mock::{MockDyDxSidecar, MockServer};
+
+    const SUBACCOUNT: &str = "dydx1mock/0";
+
+    fn config(time_in_force: TimeInForce) -> ExecutionConfig {
+        ExecutionConfig {
+            rest_url: String::new(),
+            key: SUBACCOUNT.to_string(),
+            secret: "secret".to_string(),
+            time_in_force,
+            #[cfg(feature = "aevo-live")]
+            account: None,
+            #[cfg(feature = "aevo-live")]
+            signing_key: None,
+        }
+    }
+
+    /// Client of a mock sidecar started with `config`, sending its requests
+    /// with `client_config`. Returns once the subaccount is followed
+    async fn client(config: &ExecutionConfig, client_config: ExecutionConfig) -> DyDxClient {
+        let (account, _) = broadcast::channel(100);
+        let indexer = MockServer::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_account(account.clone());
+        let indexer_url = indexer.url().unwrap();
+        indexer.serve(Vec::new(), Duration::from_millis(100));
+        let sidecar = MockDyDxSidecar::bind("127.0.0.1:0", config, account.clone())
+            .await
+            .unwrap();
+        let rest_url = sidecar.url().unwrap();
+        sidecar.serve();
+
+        let client = DyDxClient::new(
+            ExecutionConfig {
+                rest_url,
+                ..client_config
+            },
+            DyDxAccount::follow(SUBACCOUNT, indexer_url, ConnectionConfig::default()),
+            "BTC-USD".parse().unwrap(),
+            dec!(0),
+        );
+        // Updates published before the subscription are lost
+        while account.receiver_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        client
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_with_a_wrong_token() {
+        let config = config(TimeInForce::ImmediateOrCancel);
+        let client = client(
+            &config,
+            ExecutionConfig {
+                secret: "wrong".to_string(),
+                ..config.clone()
+            },
+        )
+        .await;
+
+        let err = client
+            .execute(Side::Buy, dec!(0.5), dec!(60000))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("INVALID_TOKEN"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn fills_immediate_or_cancel_orders() {
+        let config = config(TimeInForce::ImmediateOrCancel);
+        let client = client(&config, config.clone()).await;
+
+        let fill = client
+            .execute(Side::Sell, dec!(0.5), dec!(60000))
+            .await
+            .unwrap();
+        assert_eq!(fill.amount, dec!(0.5));
+        assert_eq!(fill.price, dec!(60000));
+    }
+
+    #[tokio::test]
+    async fn cancels_open_orders_after_the_timeout() {
+        let config = config(TimeInForce::GoodTillCancel);
+        let client = client(&config, config.clone()).await;
+
+        let start = tokio::time::Instant::now();
+        let fill = client
+            .execute(Side::Buy, dec!(0.5), dec!(60000))
+            .await
+            .unwrap();
+        assert_eq!(fill.amount, dec!(0));
+        // Closed by the cancellation, not by the cancel timeout
+        assert!(start.elapsed() >= ORDER_TIMEOUT);
+        assert!(start.elapsed() < ORDER_TIMEOUT + CANCEL_TIMEOUT);
+    }
+
+    #[test]
+    fn tracks_the_fills_of_its_order() {
+        let contents = |orders: Vec<(&str, &str, &str)>, fills: Vec<(&str, Decimal, Decimal)>| {
+            SubaccountContents {
+                orders: orders
+                    .into_iter()
+                    .map(|(id, client_id, status)| OrderUpdate {
+                        id: id.to_string(),
+                        client_id: client_id.to_string(),
+                        status: status.to_string(),
+                    })
+                    .collect(),
+                fills: fills
+                    .into_iter()
+                    .map(|(order_id, size, price)| FillUpdate {
+                        order_id: order_id.to_string(),
+                        size,
+                        price,
+                    })
+                    .collect(),
+            }
+        };
+        let mut tracker = OrderTracker::new("7".to_string());
+
+        assert!(!tracker.update(&contents(
+            vec![("a", "7", "OPEN"), ("b", "8", "OPEN")],
+            vec![("a", dec!(1), dec!(100)), ("b", dec!(5), dec!(90))],
+        )));
+        assert!(tracker.update(&contents(
+            vec![("a", "7", "FILLED")],
+            vec![("a", dec!(3), dec!(104))],
+        )));
+
+        let fill = tracker.fill();
+        assert_eq!(fill.amount, dec!(4));
+        assert_eq!(fill.price, dec!(103));
+    }
+}
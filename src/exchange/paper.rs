@@ -0,0 +1,430 @@
+//! Simulated order execution
+//!
+//! Orders are sent to an engine task standing in for the exchange. After the
+//! configured latency each order is matched against the order book of the
+//! feed at that time, not the one the decision was taken on: the price can
+//! have moved and the liquidity can be gone. Fills are reported back on a
+//! channel, like the order updates of a real exchange.
+
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::bail;
+use async_trait::async_trait;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+
+use super::{BookHandle, Fill, OrderBookMessage, OrderExecutor, Side, Venue};
+
+/// Fill model of the paper trading engine
+#[derive(Clone, Debug, Default)]
+pub struct PaperConfig {
+    /// Time between the order and its fill
+    pub latency: Duration,
+    /// Probability of an order to be rejected, between 0 and 1
+    pub reject_rate: f64,
+    /// Seed of the rejections, drawn from the system if not set
+    pub seed: Option<u64>,
+    /// Remove the liquidity taken by the fills from the order book of the
+    /// feed
+    pub persistent_trades: bool,
+}
+
+#[derive(Clone, Debug)]
+struct PaperOrder {
+    id: u64,
+    side: Side,
+    amount: Decimal,
+    /// Limit price, levels beyond it are not filled
+    price: Decimal,
+}
+
+#[derive(Clone, Debug)]
+enum PaperReport {
+    /// The order is closed. The fill can be partial or empty if the book did
+    /// not have enough liquidity within the limit price
+    Filled {
+        order_id: u64,
+        fill: Fill,
+    },
+    Rejected {
+        order_id: u64,
+    },
+    /// The engine could not process the order
+    Failed {
+        order_id: u64,
+        reason: String,
+    },
+}
+
+impl PaperReport {
+    fn order_id(&self) -> u64 {
+        match self {
+            PaperReport::Filled { order_id, .. }
+            | PaperReport::Rejected { order_id }
+            | PaperReport::Failed { order_id, .. } => *order_id,
+        }
+    }
+}
+
+/// Immediate or cancel limit orders filled by a simulated exchange
+pub struct PaperExecutor {
+    venue: Venue,
+    fee: Decimal,
+    orders: mpsc::UnboundedSender<PaperOrder>,
+    reports: broadcast::Sender<PaperReport>,
+    next_order_id: AtomicU64,
+}
+
+impl PaperExecutor {
+    /// Start the engine filling the orders against the book of `book`
+    pub fn new(venue: Venue, fee: Decimal, book: BookHandle, config: PaperConfig) -> Self {
+        let (orders, receiver) = mpsc::unbounded_channel();
+        let (reports, _) = broadcast::channel(1000);
+        tokio::spawn(run_engine(book, config, receiver, reports.clone()));
+
+        Self {
+            venue,
+            fee,
+            orders,
+            reports,
+            next_order_id: AtomicU64::new(1),
+        }
+    }
+}
+
+#[async_trait]
+impl OrderExecutor for PaperExecutor {
+    fn fee(&self) -> Decimal {
+        self.fee
+    }
+
+    async fn execute_order(
+        &self,
+        side: Side,
+        amount: Decimal,
+        price: Decimal,
+    ) -> anyhow::Result<Fill> {
+        let id = self.next_order_id.fetch_add(1, Ordering::Relaxed);
+        // Subscribe before sending the order to not miss the report
+        let mut reports = self.reports.subscribe();
+        self.orders.send(PaperOrder {
+            id,
+            side,
+            amount,
+            price,
+        })?;
+
+        loop {
+            let report = match reports.recv().await {
+                Ok(report) => report,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("missed {} paper order reports", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => bail!("paper engine of {} stopped", self),
+            };
+            if report.order_id() != id {
+                continue;
+            }
+
+            return match report {
+                PaperReport::Filled { fill, .. } => Ok(fill),
+                PaperReport::Rejected { .. } => bail!("{} paper order {} rejected", self, id),
+                PaperReport::Failed { reason, .. } => {
+                    bail!("{} paper order {} failed: {}", self, id, reason)
+                }
+            };
+        }
+    }
+}
+
+impl Display for PaperExecutor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.venue)
+    }
+}
+
+async fn run_engine(
+    book: BookHandle,
+    config: PaperConfig,
+    mut orders: mpsc::UnboundedReceiver<PaperOrder>,
+    reports: broadcast::Sender<PaperReport>,
+) {
+    let mut rng = config
+        .seed
+        .map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+
+    while let Some(order) = orders.recv().await {
+        let rejected = rng.gen_bool(config.reject_rate.clamp(0.0, 1.0));
+        let book = book.clone();
+        let reports = reports.clone();
+        let config = config.clone();
+
+        // Orders are independent, one waiting does not delay the others
+        tokio::spawn(async move {
+            tokio::time::sleep(config.latency).await;
+
+            // Every order gets a report, the executor waits for it
+            let report = if rejected {
+                PaperReport::Rejected { order_id: order.id }
+            } else {
+                match fill_order(&book, &order, config.persistent_trades).await {
+                    Ok(fill) => PaperReport::Filled {
+                        order_id: order.id,
+                        fill,
+                    },
+                    Err(err) => {
+                        tracing::error!("failed to update the paper order book: {}", err);
+                        PaperReport::Failed {
+                            order_id: order.id,
+                            reason: err.to_string(),
+                        }
+                    }
+                }
+            };
+            tracing::debug!("paper {:?} {:?}", order, report);
+            let _ = reports.send(report);
+        });
+    }
+}
+
+/// Match `order` against the current book, taking the levels within its
+/// limit price
+async fn fill_order(
+    book: &BookHandle,
+    order: &PaperOrder,
+    persistent_trades: bool,
+) -> anyhow::Result<Fill> {
+    let current = book.current();
+    // Depth within the limit price
+    let available = match order.side {
+        Side::Buy => current
+            .asks()
+            .take_while(|level| level.price <= order.price)
+            .map(|level| level.amount)
+            .sum::<Decimal>(),
+        Side::Sell => current
+            .bids()
+            .take_while(|level| level.price >= order.price)
+            .map(|level| level.amount)
+            .sum::<Decimal>(),
+    };
+    let amount = order.amount.min(available);
+
+    let price = match order.side {
+        Side::Buy => current.buy_price(amount),
+        Side::Sell => current.sell_price(amount),
+    };
+    let Some(price) = price.filter(|_| !amount.is_zero()) else {
+        return Ok(Fill {
+            amount: dec!(0),
+            price: dec!(0),
+        });
+    };
+
+    if persistent_trades {
+        // Update every level the trade went through at once
+        let update = match order.side {
+            Side::Buy => OrderBookMessage::Update {
+                bids: Vec::new(),
+                asks: current.take_asks(amount),
+            },
+            Side::Sell => OrderBookMessage::Update {
+                bids: current.take_bids(amount),
+                asks: Vec::new(),
+            },
+        };
+        book.send(update).await?;
+    }
+
+    Ok(Fill { amount, price })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{FutureExt, StreamExt};
+    use serde_json::json;
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::exchange::{FeedSource, FeedStream, Symbol, ViolationPolicy};
+
+    const MARKET: &str = "ETH-USD";
+
+    /// DyDx feed of a book with a bid at 99 and asks of 1 at 101 and 2 at 102
+    fn feed() -> (FeedStream, BookHandle) {
+        let mut feed = Venue::DyDx.feed(FeedSource::Manual, ViolationPolicy::Resync);
+        let handle = feed.order_book_subscribe(&MARKET.parse::<Symbol>().unwrap());
+        let snapshot = json!({
+            "type": "subscribed",
+            "connection_id": "test",
+            "message_id": 1,
+            "channel": "v4_orderbook",
+            "id": MARKET,
+            "contents": {
+                "bids": [{"price": "99", "size": "1"}],
+                "asks": [{"price": "101", "size": "1"}, {"price": "102", "size": "2"}],
+            },
+        });
+        push(&mut feed, &snapshot.to_string());
+        (feed, handle)
+    }
+
+    /// Push `frame` to the feed and apply it
+    fn push(feed: &mut FeedStream, frame: &str) {
+        feed.push_frame(frame, std::time::SystemTime::now())
+            .unwrap();
+        feed.next().now_or_never().flatten().unwrap();
+    }
+
+    fn executor(handle: &BookHandle, config: PaperConfig) -> PaperExecutor {
+        PaperExecutor::new(Venue::DyDx, dec!(0.001), handle.clone(), config)
+    }
+
+    #[tokio::test]
+    async fn orders_take_the_levels_within_their_limit() {
+        let (_feed, handle) = feed();
+        let executor = executor(&handle, PaperConfig::default());
+
+        let fill = executor
+            .execute_order(Side::Buy, dec!(3), dec!(101.5))
+            .await
+            .unwrap();
+        assert_eq!((fill.amount, fill.price), (dec!(1), dec!(101)));
+
+        let fill = executor
+            .execute_order(Side::Buy, dec!(5), dec!(102))
+            .await
+            .unwrap();
+        assert_eq!(fill.amount, dec!(3));
+        assert_eq!(fill.price, dec!(305) / dec!(3));
+
+        let fill = executor
+            .execute_order(Side::Sell, dec!(1), dec!(99.5))
+            .await
+            .unwrap();
+        assert_eq!(fill.amount, dec!(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn orders_fill_against_the_book_after_the_latency() {
+        let (mut feed, handle) = feed();
+        let config = PaperConfig {
+            latency: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let executor = executor(&handle, config);
+        let sent_at = Instant::now();
+
+        // The ask at 101 is taken by someone else while the order is on its
+        // way
+        let (fill, _) = tokio::join!(
+            executor.execute_order(Side::Buy, dec!(1), dec!(101)),
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let update = json!({
+                    "type": "channel_data",
+                    "connection_id": "test",
+                    "message_id": 2,
+                    "channel": "v4_orderbook",
+                    "id": MARKET,
+                    "contents": {"asks": [["101", "0"]]},
+                });
+                push(&mut feed, &update.to_string());
+            }
+        );
+
+        assert_eq!(sent_at.elapsed(), Duration::from_millis(100));
+        assert_eq!(fill.unwrap().amount, dec!(0));
+    }
+
+    /// Whether each of `orders` orders of an engine seeded with `seed` is
+    /// rejected
+    async fn rejections(seed: u64, reject_rate: f64, orders: usize) -> Vec<bool> {
+        let (_feed, handle) = feed();
+        let config = PaperConfig {
+            reject_rate,
+            seed: Some(seed),
+            ..Default::default()
+        };
+        let executor = executor(&handle, config);
+
+        let mut rejected = Vec::new();
+        for _ in 0..orders {
+            let result = executor
+                .execute_order(Side::Buy, dec!(0.01), dec!(101))
+                .await;
+            rejected.push(result.is_err());
+        }
+        rejected
+    }
+
+    #[tokio::test]
+    async fn seeded_rejections_repeat() {
+        let rejected = rejections(7, 0.3, 200).await;
+        assert_eq!(rejected, rejections(7, 0.3, 200).await);
+        assert_ne!(rejected, rejections(8, 0.3, 200).await);
+
+        let count = rejected.iter().filter(|rejected| **rejected).count();
+        assert!((40..=80).contains(&count), "{} rejected", count);
+        assert!(rejections(7, 0.0, 20)
+            .await
+            .iter()
+            .all(|rejected| !rejected));
+        assert!(rejections(7, 1.0, 20)
+            .await
+            .iter()
+            .all(|rejected| *rejected));
+    }
+
+    #[tokio::test]
+    async fn persistent_trades_deplete_the_book() {
+        let (mut feed, handle) = feed();
+        let config = PaperConfig {
+            persistent_trades: true,
+            ..Default::default()
+        };
+        let executor = executor(&handle, config);
+
+        let fill = executor
+            .execute_order(Side::Buy, dec!(0.6), dec!(101))
+            .await
+            .unwrap();
+        assert_eq!(fill.amount, dec!(0.6));
+        // The liquidity taken is applied with the next event of the feed
+        feed.next().now_or_never().flatten().unwrap();
+        assert_eq!(handle.current().best_ask().unwrap().amount, dec!(0.4));
+
+        let fill = executor
+            .execute_order(Side::Buy, dec!(1), dec!(101))
+            .await
+            .unwrap();
+        assert_eq!(fill.amount, dec!(0.4));
+        feed.next().now_or_never().flatten().unwrap();
+        assert_eq!(handle.current().best_ask().unwrap().price, dec!(102));
+    }
+
+    #[tokio::test]
+    async fn fills_leave_the_book_without_persistent_trades() {
+        let (_feed, handle) = feed();
+        let executor = executor(&handle, PaperConfig::default());
+
+        for _ in 0..2 {
+            let fill = executor
+                .execute_order(Side::Buy, dec!(1), dec!(101))
+                .await
+                .unwrap();
+            assert_eq!(fill.amount, dec!(1));
+        }
+        assert_eq!(handle.current().best_ask().unwrap().amount, dec!(1));
+    }
+}
@@ -0,0 +1,378 @@
+//! Local order books of a feed
+//!
+//! Every exchange keeps the books of its subscribed symbols in a
+//! [`BookRegistry`], with its connection, the updates made by the bot and the
+//! events waiting to be returned. The exchanges only parse their frames and
+//! apply them to the books.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::{mpsc, watch};
+
+use super::{
+    connection::{Connection, ConnectionHandle, Incoming, Protocol},
+    BookError, BookHandle, BookState, ConnectionState, FeedSource, MarketEvent, OrderBook,
+    OrderBookMessage, Symbol, Venue, ViolationPolicy,
+};
+use crate::capture;
+
+/// Resyncs of a book in a row, without a valid frame in between, after which
+/// the book is halted. Stops an exchange sending the same invalid book from
+/// being resubscribed in a loop
+const MAX_RESYNCS: u32 = 5;
+/// Time after the last resync of a book from which its resyncs are counted
+/// from zero again
+const RESYNC_WINDOW: Duration = Duration::from_secs(60);
+
+/// Local order book of a symbol. `V` is the state the exchange keeps with
+/// the book, reset with it
+pub(super) struct SymbolBook<V> {
+    pub order_book: Arc<OrderBook>,
+    /// Updates are ignored while the book is not live
+    pub state: BookState,
+    pub venue: V,
+    /// Resyncs in a row caused by the book itself, reset by the first valid
+    /// frame, the snapshot rebuilding the book, or after `RESYNC_WINDOW`
+    resyncs: u32,
+    last_resync: Option<SystemTime>,
+    /// Publishes every new version of `order_book`
+    book_sender: watch::Sender<Arc<OrderBook>>,
+}
+
+impl<V: Default> SymbolBook<V> {
+    fn new() -> Self {
+        Self {
+            order_book: Arc::new(OrderBook::new()),
+            state: BookState::Live,
+            venue: V::default(),
+            resyncs: 0,
+            last_resync: None,
+            book_sender: watch::Sender::new(Arc::new(OrderBook::new())),
+        }
+    }
+
+    /// Empty the book, it can not be traded on in `state`
+    pub fn clear(&mut self, state: BookState) {
+        self.state = state;
+        self.order_book = Arc::new(OrderBook::new());
+        self.venue = V::default();
+    }
+
+    /// Apply a frame to the local book. The published snapshots keep their
+    /// levels, the book copies the changed ones only
+    pub fn update(&mut self, update: OrderBookMessage) -> Result<(), BookError> {
+        Arc::make_mut(&mut self.order_book).update(update)?;
+        self.resyncs = 0;
+        Ok(())
+    }
+}
+
+/// Next item of a feed
+pub(super) enum Polled<M> {
+    /// Event ready to be returned by the feed
+    Event(MarketEvent),
+    /// Frame of the exchange, to apply to the books, with the time it was
+    /// received
+    Message(M, SystemTime),
+    /// Change of the connection, see [`BookRegistry::connection`]
+    Connection(ConnectionState),
+}
+
+/// Books of every subscribed symbol of an exchange, fed with the messages `M`
+/// of its frames
+pub(super) struct BookRegistry<M, V> {
+    /// Name of the exchange, in the captures and logs
+    name: &'static str,
+    venue: Venue,
+    receiver: mpsc::Receiver<Incoming<M>>,
+    sender: mpsc::Sender<Incoming<M>>,
+    parse: fn(&str) -> Option<M>,
+    /// Updates of the local order books, see [`BookHandle`]
+    local_receiver: mpsc::Receiver<(Symbol, OrderBookMessage)>,
+    local_sender: mpsc::Sender<(Symbol, OrderBookMessage)>,
+    /// Connection to the exchange, `None` if the frames are replayed or
+    /// pushed
+    connection: Option<ConnectionHandle>,
+    policy: ViolationPolicy,
+    /// Time of the last frame pushed with [`Self::push_frame`]. Pushed frames
+    /// carry the time they were captured, it is the clock of the feed
+    pushed_at: Mutex<Option<SystemTime>>,
+    books: HashMap<Symbol, SymbolBook<V>>,
+    /// Events waiting to be returned by the feed
+    pending: VecDeque<MarketEvent>,
+}
+
+impl<M: Send + Sync + 'static, V: Default> BookRegistry<M, V> {
+    /// Books of the exchange `name`, fed from `source` with the frames parsed
+    /// by `parse`. A live source connects at once with `protocol`.
+    pub fn new(
+        name: &'static str,
+        venue: Venue,
+        source: FeedSource,
+        protocol: impl Protocol,
+        parse: fn(&str) -> Option<M>,
+        policy: ViolationPolicy,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(10000);
+        let (local_sender, local_receiver) = mpsc::channel(10000);
+
+        let connection = match source {
+            FeedSource::Live {
+                url,
+                recorder,
+                connection,
+            } => Some(
+                Connection::new(name, url, protocol, connection)
+                    .with_recorder(recorder)
+                    .spawn(parse, sender.clone()),
+            ),
+            FeedSource::Replay(path) => {
+                tokio::spawn(capture::replay(
+                    path,
+                    name,
+                    move |frame| parse(frame).map(|msg| Incoming::Message(msg, SystemTime::now())),
+                    sender.clone(),
+                ));
+                None
+            }
+            FeedSource::Manual => None,
+        };
+
+        Self {
+            name,
+            venue,
+            receiver,
+            sender,
+            parse,
+            local_receiver,
+            local_sender,
+            connection,
+            policy,
+            pushed_at: Mutex::new(None),
+            books: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self, symbol: &Symbol) -> BookHandle {
+        let book = self
+            .books
+            .entry(symbol.clone())
+            .or_insert_with(SymbolBook::new);
+        if let Some(connection) = &self.connection {
+            connection.subscribe(&symbol.0);
+        }
+
+        BookHandle {
+            symbol: symbol.clone(),
+            updates: self.local_sender.clone(),
+            book: book.book_sender.subscribe(),
+        }
+    }
+
+    pub fn unsubscribe(&mut self, symbol: &Symbol) {
+        self.books.remove(symbol);
+        if let Some(connection) = &self.connection {
+            connection.unsubscribe(&symbol.0);
+        }
+    }
+
+    /// Process a raw frame as if it was received from the exchange at
+    /// `received_at`
+    pub fn push_frame(&self, frame: &str, received_at: SystemTime) -> anyhow::Result<()> {
+        *self.pushed_at.lock().unwrap() = Some(received_at);
+        if let Some(msg) = (self.parse)(frame) {
+            self.sender.try_send(Incoming::Message(msg, received_at))?;
+        }
+        Ok(())
+    }
+
+    /// Current time of the feed, the time of the last pushed frame if the
+    /// frames are pushed
+    fn now(&self) -> SystemTime {
+        self.pushed_at
+            .lock()
+            .unwrap()
+            .unwrap_or_else(SystemTime::now)
+    }
+
+    /// Book of `symbol`, `None` if it is not subscribed
+    pub fn book(&mut self, symbol: &Symbol) -> Option<&mut SymbolBook<V>> {
+        self.books.get_mut(symbol)
+    }
+
+    /// Publish the book of `symbol` and build its event
+    pub fn publish(&self, symbol: &Symbol, received_at: SystemTime) -> Option<MarketEvent> {
+        let book = self.books.get(symbol)?;
+        book.book_sender.send_replace(book.order_book.clone());
+
+        Some(MarketEvent {
+            venue: self.venue,
+            symbol: symbol.clone(),
+            order_book: book.order_book.clone(),
+            last_updated: None,
+            message_id: None,
+            received_at,
+            state: book.state,
+            connection: None,
+        })
+    }
+
+    /// Queue an event of every book
+    pub fn publish_all(
+        &mut self,
+        received_at: SystemTime,
+        message_id: Option<usize>,
+        connection: Option<ConnectionState>,
+    ) {
+        let symbols = self.books.keys().cloned().collect::<Vec<_>>();
+        for symbol in symbols {
+            if let Some(event) = self.publish(&symbol, received_at) {
+                self.pending.push_back(MarketEvent {
+                    message_id,
+                    connection: connection.clone(),
+                    ..event
+                });
+            }
+        }
+    }
+
+    /// Report a change of the connection for every book. The books of a
+    /// lost connection are dropped, the snapshots sent after reconnecting
+    /// rebuild them
+    pub fn connection(&mut self, connection: ConnectionState) {
+        if matches!(connection, ConnectionState::Disconnected { .. }) {
+            for book in self.books.values_mut() {
+                if book.state != BookState::Halted {
+                    book.clear(BookState::Resyncing);
+                }
+            }
+        }
+        self.publish_all(self.now(), None, Some(connection));
+    }
+
+    /// Drop the book of `symbol`, found invalid, and ask for a new snapshot.
+    /// The book is halted once it was resynced too many times in a row.
+    pub fn resync(&mut self, symbol: &Symbol) {
+        let now = self.now();
+        let Some(book) = self.books.get_mut(symbol) else {
+            return;
+        };
+        if book.last_resync.is_some_and(|last_resync| {
+            now.duration_since(last_resync).unwrap_or_default() > RESYNC_WINDOW
+        }) {
+            book.resyncs = 0;
+        }
+        book.resyncs += 1;
+        book.last_resync = Some(now);
+        if book.resyncs > MAX_RESYNCS {
+            tracing::error!(
+                "{} {} book resynced {} times in a row, book halted",
+                self.name,
+                symbol,
+                MAX_RESYNCS
+            );
+            self.halt(symbol);
+            return;
+        }
+
+        self.resubscribe(symbol);
+    }
+
+    /// Drop the book of `symbol` and ask for a new snapshot. Replayed frames
+    /// can not be asked again, the book waits for the next snapshot of the
+    /// capture.
+    fn resubscribe(&mut self, symbol: &Symbol) {
+        if let Some(book) = self.books.get_mut(symbol) {
+            book.clear(BookState::Resyncing);
+        }
+        if let Some(connection) = &self.connection {
+            connection.resync(&symbol.0);
+        }
+    }
+
+    /// Drop the book of `symbol` for good
+    fn halt(&mut self, symbol: &Symbol) {
+        if let Some(book) = self.books.get_mut(symbol) {
+            book.clear(BookState::Halted);
+        }
+        // The book stays halted, its frames are not needed anymore
+        if let Some(connection) = &self.connection {
+            connection.unsubscribe(&symbol.0);
+        }
+    }
+
+    /// Resync every book not halted, after a frame of the connection was
+    /// missed. The books are not at fault, their resyncs are not counted
+    pub fn resync_all(&mut self) {
+        let symbols = self
+            .books
+            .iter()
+            .filter(|(_, book)| book.state != BookState::Halted)
+            .map(|(symbol, _)| symbol.clone())
+            .collect::<Vec<_>>();
+        for symbol in &symbols {
+            self.resubscribe(symbol);
+        }
+    }
+
+    /// React to the book of `symbol` breaking one of its invariants
+    pub fn violation(&mut self, symbol: &Symbol, err: BookError) {
+        match self.policy {
+            ViolationPolicy::Resync => {
+                tracing::warn!(
+                    "{} {} invalid book, resubscribing: {}",
+                    self.name,
+                    symbol,
+                    err
+                );
+                self.resync(symbol);
+            }
+            ViolationPolicy::Halt => {
+                tracing::error!(
+                    "{} {} invalid book, book halted: {}",
+                    self.name,
+                    symbol,
+                    err
+                );
+                self.halt(symbol);
+            }
+        }
+    }
+
+    /// Next event waiting, book updated by the bot, or frame of the exchange
+    pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Polled<M>>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Poll::Ready(Some(Polled::Event(event)));
+        }
+
+        // Updates made by the bot come before the frames of the exchange.
+        // Return a snapshot of the whole book
+        while let Poll::Ready(Some((symbol, update))) = self.local_receiver.poll_recv(cx) {
+            let Some(book) = self.books.get_mut(&symbol) else {
+                continue;
+            };
+            // Only a live book can be updated locally
+            if book.state == BookState::Live {
+                if let Err(err) = book.update(update) {
+                    self.violation(&symbol, err);
+                }
+            }
+            if let Some(event) = self.publish(&symbol, self.now()) {
+                return Poll::Ready(Some(Polled::Event(event)));
+            }
+        }
+
+        self.receiver.poll_recv(cx).map(|incoming| {
+            incoming.map(|incoming| match incoming {
+                Incoming::Message(msg, received_at) => Polled::Message(msg, received_at),
+                Incoming::Connection(connection) => Polled::Connection(connection),
+            })
+        })
+    }
+}
@@ -0,0 +1,901 @@
+//! Execution of the two legs of an arbitrage
+//!
+//! Both legs are sent at the same time. When they do not fill the same amount
+//! the difference is an open position, corrected according to the
+//! [`LegPolicy`]. Every leg outcome is logged with structured fields.
+
+use std::{fmt::Display, str::FromStr, time::SystemTime};
+
+use anyhow::anyhow;
+use rust_decimal::{Decimal, RoundingStrategy};
+use rust_decimal_macros::dec;
+
+use crate::{
+    bot::Trade,
+    exchange::{Fill, OrderBook, OrderExecutor, Side},
+    pnl::LegFill,
+    portfolio::{Asset, Portfolio, Reservation},
+    risk::{RiskManager, TradeIntent},
+};
+
+/// Decimals of the amounts reduced to the available balance
+const AMOUNT_DECIMALS: u32 = 12;
+
+/// What to do with the position left when the legs fill different amounts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LegPolicy {
+    /// Close the excess on the venue it was filled on
+    #[default]
+    Unwind,
+    /// Complete the missing amount on the venue of the other leg
+    Hedge,
+    /// Keep the position
+    None,
+}
+
+impl FromStr for LegPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "unwind" => Ok(LegPolicy::Unwind),
+            "hedge" => Ok(LegPolicy::Hedge),
+            "none" => Ok(LegPolicy::None),
+            _ => Err(anyhow!("unknown leg policy {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum LegState {
+    Pending,
+    Filled(Fill),
+    PartiallyFilled(Fill),
+    /// Closed without any fill
+    Unfilled,
+    Failed(String),
+}
+
+impl Display for LegState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LegState::Pending => write!(f, "pending"),
+            LegState::Filled(_) => write!(f, "filled"),
+            LegState::PartiallyFilled(_) => write!(f, "partially filled"),
+            LegState::Unfilled => write!(f, "unfilled"),
+            LegState::Failed(_) => write!(f, "failed"),
+        }
+    }
+}
+
+/// Order sent to a venue as part of an arbitrage
+#[derive(Clone, Debug)]
+pub struct Leg {
+    pub side: Side,
+    pub amount: Decimal,
+    pub limit: Decimal,
+    pub state: LegState,
+}
+
+impl Leg {
+    fn new(side: Side, amount: Decimal, limit: Decimal) -> Self {
+        Self {
+            side,
+            amount,
+            limit,
+            state: LegState::Pending,
+        }
+    }
+
+    /// Set the state from the result of the order
+    fn close(&mut self, result: anyhow::Result<Fill>) {
+        self.state = match result {
+            Ok(fill) if fill.amount.is_zero() => LegState::Unfilled,
+            Ok(fill) if fill.amount < self.amount => LegState::PartiallyFilled(fill),
+            Ok(fill) => LegState::Filled(fill),
+            Err(err) => LegState::Failed(err.to_string()),
+        };
+    }
+
+    fn fill(&self) -> Option<&Fill> {
+        match &self.state {
+            LegState::Filled(fill) | LegState::PartiallyFilled(fill) => Some(fill),
+            _ => None,
+        }
+    }
+
+    fn filled(&self) -> Decimal {
+        self.fill().map_or(dec!(0), |fill| fill.amount)
+    }
+}
+
+/// Venue of a leg, identified by its index in the portfolio
+#[derive(Clone, Copy)]
+pub struct LegVenue<'a> {
+    pub index: usize,
+    pub executor: &'a dyn OrderExecutor,
+    /// Order book the decision was taken on
+    pub book: &'a OrderBook,
+}
+
+impl LegVenue<'_> {
+    /// Balance spent by an order of `amount` at the limit `limit`
+    fn cost(&self, side: Side, amount: Decimal, limit: Decimal) -> (Asset, Decimal) {
+        match side {
+            Side::Buy => (
+                Asset::Quote,
+                amount * limit * (dec!(1) + self.executor.fee()),
+            ),
+            Side::Sell => (Asset::Base, amount),
+        }
+    }
+
+    /// Largest amount, up to `amount`, the available balance can pay for at
+    /// the limit `limit`
+    fn affordable(
+        &self,
+        portfolio: &Portfolio,
+        side: Side,
+        amount: Decimal,
+        limit: Decimal,
+    ) -> Decimal {
+        let (asset, cost) = self.cost(side, amount, limit);
+        let available = portfolio.balance(self.index, asset).available();
+        if cost <= available {
+            return amount;
+        }
+        // Rounded down so the reservation of the amount fits the balance
+        (amount * available.max(dec!(0)) / cost)
+            .round_dp_with_strategy(AMOUNT_DECIMALS, RoundingStrategy::ToZero)
+    }
+
+    /// Hold the balance the leg can spend, `None` if it is not available
+    fn reserve(&self, portfolio: &mut Portfolio, leg: &Leg) -> Option<Reservation> {
+        let (asset, cost) = self.cost(leg.side, leg.amount, leg.limit);
+        portfolio.reserve(self.index, asset, cost)
+    }
+
+    /// Fill of `leg` with the fee paid, `None` if nothing was filled
+    pub fn leg_fill(&self, leg: &Leg) -> Option<LegFill> {
+        let fill = leg.fill().filter(|fill| !fill.amount.is_zero())?;
+        Some(LegFill {
+            venue: self.index,
+            side: leg.side,
+            amount: fill.amount,
+            price: fill.price,
+            fee: fill.amount * fill.price * self.executor.fee(),
+        })
+    }
+
+    fn apply(&self, portfolio: &mut Portfolio, leg: &Leg) {
+        if let Some(fill) = leg.fill() {
+            portfolio.apply_fill(self.index, leg.side, fill, self.executor.fee());
+        }
+    }
+
+    /// Send a single order, holding the balance it can spend until it is
+    /// closed, and apply its fill. A buy is reduced to the position limit of
+    /// the venue and the order to what the available balance can pay for, it
+    /// is not sent if nothing is available or if it breaks the risk limits
+    pub async fn execute(
+        &self,
+        portfolio: &mut Portfolio,
+        risk: &mut RiskManager,
+        now: SystemTime,
+        side: Side,
+        amount: Decimal,
+        limit: Decimal,
+    ) -> Leg {
+        let allowed = match (side, portfolio.position_capacity(self.index)) {
+            (Side::Buy, Some(capacity)) => amount.min(capacity),
+            _ => amount,
+        };
+        let affordable = self.affordable(portfolio, side, allowed, limit);
+        if affordable < amount {
+            tracing::warn!(
+                venue = %self.executor,
+                side = ?side,
+                amount = %amount,
+                affordable = %affordable,
+                "leg reduced to the position limit and the available balance"
+            );
+        }
+        let mut leg = Leg::new(side, affordable, limit);
+        if affordable.is_zero() {
+            leg.state = LegState::Failed("balance not available".to_string());
+            return leg;
+        }
+
+        let intent = TradeIntent {
+            venue: self.index,
+            side,
+            amount: affordable,
+            price: limit,
+            position: portfolio.wallet(self.index).base,
+            mid: self.book.mid_price().unwrap_or(limit),
+        };
+        if let Err(breach) = risk.check(&intent, now) {
+            tracing::warn!(
+                venue = %self.executor,
+                side = ?side,
+                breach = %breach,
+                "leg blocked by the risk limits"
+            );
+            leg.state = LegState::Failed(breach.to_string());
+            return leg;
+        }
+
+        let Some(reservation) = self.reserve(portfolio, &leg) else {
+            leg.state = LegState::Failed("balance not available".to_string());
+            return leg;
+        };
+        self.send(&mut leg).await;
+        portfolio.release(reservation);
+        self.apply(portfolio, &leg);
+        leg
+    }
+
+    async fn send(&self, leg: &mut Leg) {
+        tracing::info!(
+            venue = %self.executor,
+            side = ?leg.side,
+            amount = %leg.amount,
+            limit = %leg.limit,
+            "leg sent"
+        );
+        let result = self
+            .executor
+            .execute_order(leg.side, leg.amount, leg.limit)
+            .await;
+        leg.close(result);
+
+        match &leg.state {
+            LegState::Failed(err) => tracing::warn!(
+                venue = %self.executor,
+                side = ?leg.side,
+                amount = %leg.amount,
+                error = %err,
+                "leg failed"
+            ),
+            state => tracing::info!(
+                venue = %self.executor,
+                side = ?leg.side,
+                amount = %leg.amount,
+                filled = %leg.filled(),
+                price = %leg.fill().map_or(dec!(0), |fill| fill.price),
+                state = %state,
+                "leg closed"
+            ),
+        }
+    }
+}
+
+pub struct Coordinator {
+    policy: LegPolicy,
+    /// Price tolerance of the correcting orders, as a fraction of the
+    /// reference price
+    max_slippage: Decimal,
+}
+
+impl Coordinator {
+    pub fn new(policy: LegPolicy, max_slippage: Decimal) -> Self {
+        Self {
+            policy,
+            max_slippage,
+        }
+    }
+
+    /// Buy `amount` on `buy` up to `buy_limit` and sell it on `sell` down to
+    /// `sell_limit`, then correct the difference between the fills. The
+    /// fills are applied to `portfolio`. The arbitrage was checked by the
+    /// caller, the correcting order is checked against `risk` at `now`.
+    /// `None` if nothing was filled.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn execute(
+        &self,
+        portfolio: &mut Portfolio,
+        risk: &mut RiskManager,
+        now: SystemTime,
+        buy: LegVenue<'_>,
+        sell: LegVenue<'_>,
+        amount: Decimal,
+        buy_limit: Decimal,
+        sell_limit: Decimal,
+    ) -> Option<Trade> {
+        // Both legs are reduced to what both venues can pay for, the limit
+        // price of the buy can be above the price the amount was sized on
+        let affordable = buy
+            .affordable(portfolio, Side::Buy, amount, buy_limit)
+            .min(sell.affordable(portfolio, Side::Sell, amount, sell_limit));
+        if affordable < amount {
+            tracing::warn!(
+                amount = %amount,
+                affordable = %affordable,
+                "legs reduced to the available balances"
+            );
+        }
+        let mut buy_leg = Leg::new(Side::Buy, affordable, buy_limit);
+        let mut sell_leg = Leg::new(Side::Sell, affordable, sell_limit);
+
+        let reservations = (
+            buy.reserve(portfolio, &buy_leg),
+            sell.reserve(portfolio, &sell_leg),
+        );
+        let (buy_reservation, sell_reservation) = match reservations {
+            (Some(buy_reservation), Some(sell_reservation)) if !affordable.is_zero() => {
+                (buy_reservation, sell_reservation)
+            }
+            (buy_reservation, sell_reservation) => {
+                tracing::warn!("balances not available, legs not sent");
+                for reservation in buy_reservation.into_iter().chain(sell_reservation) {
+                    portfolio.release(reservation);
+                }
+                return None;
+            }
+        };
+        tracing::debug!("portfolio {}", portfolio.snapshot());
+        tokio::join!(buy.send(&mut buy_leg), sell.send(&mut sell_leg));
+        portfolio.release(buy_reservation);
+        portfolio.release(sell_reservation);
+        buy.apply(portfolio, &buy_leg);
+        sell.apply(portfolio, &sell_leg);
+
+        let mut fills = buy
+            .leg_fill(&buy_leg)
+            .into_iter()
+            .chain(sell.leg_fill(&sell_leg))
+            .collect::<Vec<_>>();
+        // Positive when long, negative when short
+        let exposure = buy_leg.filled() - sell_leg.filled();
+
+        if !exposure.is_zero() {
+            let long = exposure > dec!(0);
+            let correction = match (self.policy, long) {
+                (LegPolicy::None, _) => None,
+                // Sell back what was bought in excess
+                (LegPolicy::Unwind, true) => {
+                    let price = buy_leg.fill().map_or(buy_limit, |fill| fill.price);
+                    Some((buy, Side::Sell, price))
+                }
+                // Buy back what was sold in excess
+                (LegPolicy::Unwind, false) => {
+                    let price = sell_leg.fill().map_or(sell_limit, |fill| fill.price);
+                    Some((sell, Side::Buy, price))
+                }
+                // Sell the rest of the sell leg
+                (LegPolicy::Hedge, true) => Some((sell, Side::Sell, sell_limit)),
+                // Buy the rest of the buy leg
+                (LegPolicy::Hedge, false) => Some((buy, Side::Buy, buy_limit)),
+            };
+
+            match correction {
+                Some((venue, side, price)) => {
+                    let limit = match side {
+                        Side::Buy => price * (dec!(1) + self.max_slippage),
+                        Side::Sell => price * (dec!(1) - self.max_slippage),
+                    };
+                    tracing::info!(
+                        policy = ?self.policy,
+                        exposure = %exposure,
+                        "correcting legs"
+                    );
+                    let leg = venue
+                        .execute(portfolio, risk, now, side, exposure.abs(), limit)
+                        .await;
+                    fills.extend(venue.leg_fill(&leg));
+                }
+                None => tracing::warn!(exposure = %exposure, "legs left unbalanced"),
+            }
+        }
+
+        let trade = Trade::from_fills(fills)?;
+        if !trade.exposure.is_zero() {
+            tracing::warn!(exposure = %trade.exposure, "position left open");
+        }
+
+        Some(trade)
+    }
+}
+
+impl Trade {
+    /// Aggregate the fills of the legs. `None` if nothing was filled.
+    fn from_fills(fills: Vec<LegFill>) -> Option<Self> {
+        if fills.is_empty() {
+            return None;
+        }
+        let mut bought = dec!(0);
+        let mut sold = dec!(0);
+        let mut cost = dec!(0);
+        let mut proceeds = dec!(0);
+
+        for fill in &fills {
+            let notional = fill.amount * fill.price;
+            match fill.side {
+                Side::Buy => {
+                    bought += fill.amount;
+                    cost += notional;
+                }
+                Side::Sell => {
+                    sold += fill.amount;
+                    proceeds += notional;
+                }
+            }
+        }
+        let average = |notional: Decimal, amount: Decimal| {
+            if amount.is_zero() {
+                dec!(0)
+            } else {
+                notional / amount
+            }
+        };
+
+        Some(Trade {
+            amount: bought.min(sold),
+            buy_price: average(cost, bought),
+            sell_price: average(proceeds, sold),
+            fees: fills.iter().map(|fill| fill.fee).sum(),
+            exposure: bought - sold,
+            fills,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex, time::UNIX_EPOCH};
+
+    use anyhow::bail;
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::risk::RiskConfig;
+
+    /// Outcome of an order of the fake executor
+    #[derive(Clone, Copy, Debug)]
+    enum Outcome {
+        Filled,
+        /// Fill of at most this amount
+        Partial(Decimal),
+        Failed,
+    }
+
+    /// Executor filling its orders at their limit price with the queued
+    /// outcomes, fully once they run out, and recording them
+    struct FakeExecutor {
+        fee: Decimal,
+        outcomes: Mutex<VecDeque<Outcome>>,
+        orders: Mutex<Vec<(Side, Decimal, Decimal)>>,
+    }
+
+    impl FakeExecutor {
+        fn new(outcomes: &[Outcome]) -> Self {
+            Self {
+                fee: dec!(0.001),
+                outcomes: Mutex::new(outcomes.iter().copied().collect()),
+                orders: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn orders(&self) -> Vec<(Side, Decimal, Decimal)> {
+            self.orders.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl OrderExecutor for FakeExecutor {
+        fn fee(&self) -> Decimal {
+            self.fee
+        }
+
+        async fn execute_order(
+            &self,
+            side: Side,
+            amount: Decimal,
+            price: Decimal,
+        ) -> anyhow::Result<Fill> {
+            self.orders.lock().unwrap().push((side, amount, price));
+            let outcome = self.outcomes.lock().unwrap().pop_front();
+            let amount = match outcome.unwrap_or(Outcome::Filled) {
+                Outcome::Filled => amount,
+                Outcome::Partial(filled) => amount.min(filled),
+                Outcome::Failed => bail!("order failed"),
+            };
+            Ok(Fill { amount, price })
+        }
+    }
+
+    impl Display for FakeExecutor {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "fake")
+        }
+    }
+
+    /// Two venues holding 5 base token and 500 quote token each
+    fn portfolio() -> Portfolio {
+        let mut portfolio = Portfolio::new(
+            vec![("a".to_string(), None), ("b".to_string(), None)],
+            dec!(1000),
+        );
+        portfolio.rebalance(dec!(100));
+        portfolio
+    }
+
+    fn venue<'a>(index: usize, executor: &'a FakeExecutor, book: &'a OrderBook) -> LegVenue<'a> {
+        LegVenue {
+            index,
+            executor,
+            book,
+        }
+    }
+
+    #[tokio::test]
+    async fn legs_are_reduced_to_the_balances() {
+        let (buyer, seller) = (FakeExecutor::new(&[]), FakeExecutor::new(&[]));
+        let book = OrderBook::new();
+        let mut portfolio = portfolio();
+        let mut risk = RiskManager::new(RiskConfig::default());
+        let coordinator = Coordinator::new(LegPolicy::Unwind, dec!(0.01));
+
+        // 500 of quote token pays for a bit less than 5 at 100 with the fee
+        let trade = coordinator
+            .execute(
+                &mut portfolio,
+                &mut risk,
+                UNIX_EPOCH,
+                venue(0, &buyer, &book),
+                venue(1, &seller, &book),
+                dec!(10),
+                dec!(100),
+                dec!(101),
+            )
+            .await
+            .unwrap();
+
+        let amount = buyer.orders()[0].1;
+        assert!(amount < dec!(5) && amount > dec!(4.99));
+        assert_eq!(seller.orders()[0].1, amount);
+        assert_eq!(trade.amount, amount);
+        assert_eq!(trade.exposure, dec!(0));
+        assert!(portfolio.wallet(0).quote >= dec!(0));
+        assert_eq!(portfolio.balance(0, Asset::Quote).reserved, dec!(0));
+        assert_eq!(portfolio.balance(1, Asset::Base).reserved, dec!(0));
+    }
+
+    #[tokio::test]
+    async fn legs_are_not_sent_without_balance() {
+        let (buyer, seller) = (FakeExecutor::new(&[]), FakeExecutor::new(&[]));
+        let book = OrderBook::new();
+        let mut portfolio = portfolio();
+        let mut risk = RiskManager::new(RiskConfig::default());
+        // Nothing left to sell on the second venue
+        portfolio.transfer(1, 0, Asset::Base, dec!(5), dec!(0));
+        let coordinator = Coordinator::new(LegPolicy::Unwind, dec!(0.01));
+
+        let trade = coordinator
+            .execute(
+                &mut portfolio,
+                &mut risk,
+                UNIX_EPOCH,
+                venue(0, &buyer, &book),
+                venue(1, &seller, &book),
+                dec!(1),
+                dec!(100),
+                dec!(101),
+            )
+            .await;
+
+        assert!(trade.is_none());
+        assert!(buyer.orders().is_empty());
+        assert!(seller.orders().is_empty());
+        assert_eq!(portfolio.balance(0, Asset::Quote).reserved, dec!(0));
+    }
+
+    #[tokio::test]
+    async fn single_legs_are_reduced_to_the_balance() {
+        let executor = FakeExecutor::new(&[]);
+        let book = OrderBook::new();
+        let mut portfolio = portfolio();
+        let mut risk = RiskManager::new(RiskConfig::default());
+        let venue = venue(0, &executor, &book);
+
+        let leg = venue
+            .execute(
+                &mut portfolio,
+                &mut risk,
+                UNIX_EPOCH,
+                Side::Sell,
+                dec!(8),
+                dec!(100),
+            )
+            .await;
+        assert_eq!(leg.amount, dec!(5));
+        assert_eq!(leg.filled(), dec!(5));
+        assert_eq!(portfolio.wallet(0).base, dec!(0));
+
+        let leg = venue
+            .execute(
+                &mut portfolio,
+                &mut risk,
+                UNIX_EPOCH,
+                Side::Sell,
+                dec!(1),
+                dec!(100),
+            )
+            .await;
+        assert!(matches!(leg.state, LegState::Failed(_)));
+        assert_eq!(executor.orders().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn closed_legs_release_their_reservation() {
+        let executor = FakeExecutor::new(&[Outcome::Failed, Outcome::Partial(dec!(2))]);
+        let book = OrderBook::new();
+        let mut portfolio = portfolio();
+        let mut risk = RiskManager::new(RiskConfig::default());
+        let venue = venue(0, &executor, &book);
+
+        let leg = venue
+            .execute(
+                &mut portfolio,
+                &mut risk,
+                UNIX_EPOCH,
+                Side::Buy,
+                dec!(3),
+                dec!(100),
+            )
+            .await;
+        assert!(matches!(leg.state, LegState::Failed(_)));
+        assert_eq!(portfolio.wallet(0).quote, dec!(500));
+        assert_eq!(portfolio.balance(0, Asset::Quote).reserved, dec!(0));
+
+        let leg = venue
+            .execute(
+                &mut portfolio,
+                &mut risk,
+                UNIX_EPOCH,
+                Side::Buy,
+                dec!(3),
+                dec!(100),
+            )
+            .await;
+        assert!(matches!(leg.state, LegState::PartiallyFilled(_)));
+        assert_eq!(portfolio.wallet(0).base, dec!(7));
+        assert_eq!(portfolio.wallet(0).quote, dec!(299.8));
+        assert_eq!(portfolio.balance(0, Asset::Quote).reserved, dec!(0));
+    }
+
+    #[tokio::test]
+    async fn correcting_legs_are_checked_against_the_risk_limits() {
+        let (buyer, seller) = (
+            FakeExecutor::new(&[]),
+            FakeExecutor::new(&[Outcome::Partial(dec!(2))]),
+        );
+        let book = OrderBook::new();
+        let mut portfolio = portfolio();
+        let mut risk = RiskManager::new(RiskConfig {
+            max_notional: Some(dec!(50)),
+            ..Default::default()
+        });
+        let coordinator = Coordinator::new(LegPolicy::Unwind, dec!(0.01));
+
+        let trade = coordinator
+            .execute(
+                &mut portfolio,
+                &mut risk,
+                UNIX_EPOCH,
+                venue(0, &buyer, &book),
+                venue(1, &seller, &book),
+                dec!(3),
+                dec!(100),
+                dec!(101),
+            )
+            .await
+            .unwrap();
+
+        // Selling back 1 at 99 is worth more than the limit
+        assert_eq!(buyer.orders().len(), 1);
+        assert_eq!(trade.exposure, dec!(1));
+        assert!(risk.killed().is_some());
+        assert_eq!(portfolio.balance(0, Asset::Base).reserved, dec!(0));
+    }
+
+    #[tokio::test]
+    async fn correcting_buys_are_reduced_to_the_position_limit() {
+        let (buyer, seller) = (
+            FakeExecutor::new(&[Outcome::Partial(dec!(1))]),
+            FakeExecutor::new(&[]),
+        );
+        let book = OrderBook::new();
+        let mut portfolio = Portfolio::new(
+            vec![("a".to_string(), Some(dec!(6.5))), ("b".to_string(), None)],
+            dec!(1000),
+        );
+        portfolio.rebalance(dec!(100));
+        let mut risk = RiskManager::new(RiskConfig::default());
+        let coordinator = Coordinator::new(LegPolicy::Hedge, dec!(0.01));
+
+        let trade = coordinator
+            .execute(
+                &mut portfolio,
+                &mut risk,
+                UNIX_EPOCH,
+                venue(0, &buyer, &book),
+                venue(1, &seller, &book),
+                dec!(3),
+                dec!(100),
+                dec!(101),
+            )
+            .await
+            .unwrap();
+
+        // Holding 6 after the partial fill, the hedge buys the 0.5 left below
+        // the limit instead of the missing 2
+        assert_eq!(buyer.orders()[1].1, dec!(0.5));
+        assert_eq!(portfolio.wallet(0).base, dec!(6.5));
+        assert_eq!(trade.exposure, dec!(-1.5));
+    }
+
+    /// Buy `amount` on the first venue up to 100 and sell it on the second
+    /// down to 101 with the policy `policy`, without risk limits
+    async fn arbitrage(
+        policy: LegPolicy,
+        buyer: &FakeExecutor,
+        seller: &FakeExecutor,
+        amount: Decimal,
+    ) -> (Option<Trade>, Portfolio) {
+        let book = OrderBook::new();
+        let mut portfolio = portfolio();
+        let mut risk = RiskManager::new(RiskConfig::default());
+        let trade = Coordinator::new(policy, dec!(0.01))
+            .execute(
+                &mut portfolio,
+                &mut risk,
+                UNIX_EPOCH,
+                venue(0, buyer, &book),
+                venue(1, seller, &book),
+                amount,
+                dec!(100),
+                dec!(101),
+            )
+            .await;
+        (trade, portfolio)
+    }
+
+    #[tokio::test]
+    async fn filled_legs_are_not_corrected() {
+        let (buyer, seller) = (FakeExecutor::new(&[]), FakeExecutor::new(&[]));
+        let (trade, portfolio) = arbitrage(LegPolicy::Unwind, &buyer, &seller, dec!(2)).await;
+        let trade = trade.unwrap();
+
+        assert_eq!(buyer.orders(), vec![(Side::Buy, dec!(2), dec!(100))]);
+        assert_eq!(seller.orders(), vec![(Side::Sell, dec!(2), dec!(101))]);
+        assert_eq!(trade.amount, dec!(2));
+        assert_eq!(trade.fees, dec!(0.402));
+        assert_eq!(portfolio.wallet(0).base, dec!(7));
+        assert_eq!(portfolio.wallet(1).base, dec!(3));
+    }
+
+    #[tokio::test]
+    async fn unwind_sells_back_the_excess_bought() {
+        let buyer = FakeExecutor::new(&[]);
+        let seller = FakeExecutor::new(&[Outcome::Partial(dec!(1))]);
+        let (trade, portfolio) = arbitrage(LegPolicy::Unwind, &buyer, &seller, dec!(3)).await;
+        let trade = trade.unwrap();
+
+        // Sold back on the buy venue, below the price it was bought at
+        assert_eq!(buyer.orders()[1], (Side::Sell, dec!(2), dec!(99)));
+        assert_eq!(seller.orders().len(), 1);
+        assert_eq!(trade.fills.len(), 3);
+        // The excess sold back counts as bought and sold
+        assert_eq!(trade.amount, dec!(3));
+        assert_eq!(trade.exposure, dec!(0));
+        assert_eq!(portfolio.wallet(0).base, dec!(6));
+        assert_eq!(portfolio.wallet(1).base, dec!(4));
+    }
+
+    #[tokio::test]
+    async fn unwind_buys_back_what_a_failed_buy_left_sold() {
+        let buyer = FakeExecutor::new(&[Outcome::Failed]);
+        let seller = FakeExecutor::new(&[]);
+        let (trade, portfolio) = arbitrage(LegPolicy::Unwind, &buyer, &seller, dec!(3)).await;
+        let trade = trade.unwrap();
+
+        assert_eq!(buyer.orders().len(), 1);
+        assert_eq!(seller.orders()[1], (Side::Buy, dec!(3), dec!(102.01)));
+        assert_eq!(trade.exposure, dec!(0));
+        assert_eq!(portfolio.wallet(0).base, dec!(5));
+        assert_eq!(portfolio.wallet(1).base, dec!(5));
+    }
+
+    #[tokio::test]
+    async fn hedge_completes_the_partial_leg() {
+        let buyer = FakeExecutor::new(&[]);
+        let seller = FakeExecutor::new(&[Outcome::Partial(dec!(1))]);
+        let (trade, portfolio) = arbitrage(LegPolicy::Hedge, &buyer, &seller, dec!(3)).await;
+        let trade = trade.unwrap();
+
+        // The rest is sold on the sell venue, below its limit
+        assert_eq!(buyer.orders().len(), 1);
+        assert_eq!(seller.orders()[1], (Side::Sell, dec!(2), dec!(99.99)));
+        assert_eq!(trade.amount, dec!(3));
+        assert_eq!(trade.exposure, dec!(0));
+        assert_eq!(portfolio.wallet(0).base, dec!(8));
+        assert_eq!(portfolio.wallet(1).base, dec!(2));
+    }
+
+    #[tokio::test]
+    async fn hedge_buys_what_a_failed_buy_missed() {
+        let buyer = FakeExecutor::new(&[Outcome::Failed]);
+        let seller = FakeExecutor::new(&[]);
+        let (trade, _) = arbitrage(LegPolicy::Hedge, &buyer, &seller, dec!(3)).await;
+        let trade = trade.unwrap();
+
+        assert_eq!(buyer.orders()[1], (Side::Buy, dec!(3), dec!(101)));
+        assert_eq!(trade.amount, dec!(3));
+        assert_eq!(trade.exposure, dec!(0));
+    }
+
+    #[tokio::test]
+    async fn no_policy_keeps_the_position() {
+        let buyer = FakeExecutor::new(&[Outcome::Partial(dec!(1))]);
+        let seller = FakeExecutor::new(&[Outcome::Failed]);
+        let (trade, portfolio) = arbitrage(LegPolicy::None, &buyer, &seller, dec!(3)).await;
+        let trade = trade.unwrap();
+
+        assert_eq!(buyer.orders().len(), 1);
+        assert_eq!(seller.orders().len(), 1);
+        assert_eq!(trade.amount, dec!(0));
+        assert_eq!(trade.exposure, dec!(1));
+        assert_eq!(portfolio.wallet(0).base, dec!(6));
+        assert_eq!(portfolio.balance(1, Asset::Base).reserved, dec!(0));
+    }
+
+    #[tokio::test]
+    async fn failed_legs_make_no_trade() {
+        let buyer = FakeExecutor::new(&[Outcome::Failed]);
+        let seller = FakeExecutor::new(&[Outcome::Failed]);
+        let (trade, portfolio) = arbitrage(LegPolicy::Unwind, &buyer, &seller, dec!(3)).await;
+
+        assert!(trade.is_none());
+        assert_eq!(portfolio.wallet(0).quote, dec!(500));
+        assert_eq!(portfolio.wallet(1).base, dec!(5));
+    }
+
+    fn leg_fill(side: Side, amount: Decimal, price: Decimal) -> LegFill {
+        LegFill {
+            venue: 0,
+            side,
+            amount,
+            price,
+            fee: amount * price * dec!(0.001),
+        }
+    }
+
+    #[test]
+    fn trades_average_their_fills() {
+        let trade = Trade::from_fills(vec![
+            leg_fill(Side::Buy, dec!(1), dec!(100)),
+            leg_fill(Side::Buy, dec!(1), dec!(102)),
+            leg_fill(Side::Sell, dec!(1.5), dec!(104)),
+        ])
+        .unwrap();
+
+        assert_eq!(trade.amount, dec!(1.5));
+        assert_eq!(trade.buy_price, dec!(101));
+        assert_eq!(trade.sell_price, dec!(104));
+        assert_eq!(trade.fees, dec!(0.358));
+        assert_eq!(trade.exposure, dec!(0.5));
+        assert_eq!(trade.fills.len(), 3);
+    }
+
+    #[test]
+    fn one_sided_trades_have_no_price_on_the_other_side() {
+        let trade = Trade::from_fills(vec![leg_fill(Side::Sell, dec!(2), dec!(100))]).unwrap();
+        assert_eq!(trade.amount, dec!(0));
+        assert_eq!(trade.buy_price, dec!(0));
+        assert_eq!(trade.sell_price, dec!(100));
+        assert_eq!(trade.exposure, dec!(-2));
+
+        assert!(Trade::from_fills(Vec::new()).is_none());
+    }
+}
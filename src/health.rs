@@ -0,0 +1,321 @@
+//! Health of the market data feeds
+//!
+//! Every update received from an exchange is recorded. A feed that sent
+//! nothing for longer than the stale threshold is not traded on until it
+//! updates again. Gaps are detected with the DyDx `message_id`, which grows
+//! with every message of a connection, and the Aevo `last_updated` timestamp,
+//! which never goes back. The ids are shared by every book of a connection, the
+//! exchange resyncs its books on a missed one: a book dropped by the exchange
+//! counts as a gap. Lost connections are counted.
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, SystemTime},
+};
+
+use crate::exchange::{BookState, ConnectionState, MarketEvent};
+
+/// Period the message rate is measured over
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Default)]
+struct FeedHealth {
+    /// Receive time of the last update
+    last_update: Option<SystemTime>,
+    /// Receive times of the updates of the last `RATE_WINDOW`
+    recent: VecDeque<SystemTime>,
+    message_id: Option<usize>,
+    last_updated: Option<u64>,
+    messages: usize,
+    gaps: usize,
+    disconnects: usize,
+    stale: bool,
+    /// The book of the feed was dropped, the sequence starts again
+    dropped: bool,
+}
+
+impl FeedHealth {
+    fn age(&self, now: SystemTime) -> Option<Duration> {
+        self.last_update
+            .map(|last_update| now.duration_since(last_update).unwrap_or_default())
+    }
+
+    fn prune(&mut self, now: SystemTime) {
+        while self
+            .recent
+            .front()
+            .is_some_and(|time| now.duration_since(*time).unwrap_or_default() > RATE_WINDOW)
+        {
+            self.recent.pop_front();
+        }
+    }
+}
+
+/// Health of the feed of every exchange, by index
+pub struct FeedMonitor {
+    venues: Vec<String>,
+    stale_after: Duration,
+    feeds: Vec<FeedHealth>,
+}
+
+impl FeedMonitor {
+    /// `venues` are the names of the exchanges
+    pub fn new(venues: Vec<String>, stale_after: Duration) -> Self {
+        Self {
+            feeds: vec![FeedHealth::default(); venues.len()],
+            venues,
+            stale_after,
+        }
+    }
+
+    /// Record an update of the feed `key`. The updates made by the bot
+    /// itself, with neither a message id nor a timestamp of the exchange, are
+    /// ignored. Changes of the connection only count the lost connections.
+    pub fn record(&mut self, key: usize, event: &MarketEvent) {
+        if let Some(connection) = &event.connection {
+            let feed = &mut self.feeds[key];
+            if matches!(connection, ConnectionState::Disconnected { .. }) {
+                feed.disconnects += 1;
+            }
+            feed.dropped = event.state != BookState::Live;
+            return;
+        }
+        if event.message_id.is_none() && event.last_updated.is_none() {
+            return;
+        }
+        let venue = &self.venues[key];
+        let feed = &mut self.feeds[key];
+
+        // A dropped book starts its sequence again
+        if !feed.dropped {
+            if let (Some(message_id), Some(last)) = (event.message_id, feed.message_id) {
+                if message_id == last {
+                    feed.gaps += 1;
+                    tracing::warn!(venue = %venue, message_id, "message repeated in the feed");
+                }
+            }
+            if let (Some(last_updated), Some(last)) = (event.last_updated, feed.last_updated) {
+                if last_updated < last {
+                    feed.gaps += 1;
+                    tracing::warn!(
+                        venue = %venue,
+                        last_updated,
+                        previous = last,
+                        "update out of order in the feed"
+                    );
+                }
+            }
+        }
+
+        if event.state != BookState::Live && !feed.dropped {
+            feed.gaps += 1;
+        }
+        feed.dropped = event.state != BookState::Live;
+        feed.message_id = event.message_id.or(feed.message_id);
+        feed.last_updated = event.last_updated.or(feed.last_updated);
+        feed.last_update = Some(event.received_at);
+        feed.recent.push_back(event.received_at);
+        feed.prune(event.received_at);
+        feed.messages += 1;
+    }
+
+    /// Whether the feed `key` updated within the stale threshold at `now`.
+    /// Logs when the feed turns stale and when it recovers.
+    pub fn is_fresh(&mut self, key: usize, now: SystemTime) -> bool {
+        let feed = &mut self.feeds[key];
+        let age = feed.age(now);
+        let stale = age.is_none_or(|age| age > self.stale_after);
+
+        if stale && !feed.stale {
+            tracing::warn!(
+                venue = %self.venues[key],
+                age = ?age,
+                "feed stale, arbitrage suspended on the exchange"
+            );
+        } else if !stale && feed.stale {
+            tracing::info!(venue = %self.venues[key], "feed recovered");
+        }
+        feed.stale = stale;
+
+        !stale
+    }
+
+    pub fn report(&mut self, now: SystemTime) -> HealthReport {
+        let venues = (0..self.feeds.len())
+            .map(|key| {
+                let fresh = self.is_fresh(key, now);
+                let feed = &mut self.feeds[key];
+                feed.prune(now);
+                VenueHealth {
+                    venue: self.venues[key].clone(),
+                    age: feed.age(now),
+                    rate: feed.recent.len() as f64 / RATE_WINDOW.as_secs_f64(),
+                    messages: feed.messages,
+                    gaps: feed.gaps,
+                    disconnects: feed.disconnects,
+                    stale: !fresh,
+                }
+            })
+            .collect();
+
+        HealthReport { venues }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VenueHealth {
+    pub venue: String,
+    /// Time since the last update, `None` before the first one
+    pub age: Option<Duration>,
+    /// Updates per second over the last `RATE_WINDOW`
+    pub rate: f64,
+    pub messages: usize,
+    pub gaps: usize,
+    /// Connections lost or failed
+    pub disconnects: usize,
+    pub stale: bool,
+}
+
+/// Health of every feed at one point in time
+#[derive(Clone, Debug)]
+pub struct HealthReport {
+    pub venues: Vec<VenueHealth>,
+}
+
+impl Display for HealthReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, venue) in self.venues.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{} ", venue.venue)?;
+            match venue.age {
+                Some(age) => write!(f, "last update {:.1}s ago", age.as_secs_f64())?,
+                None => write!(f, "no update")?,
+            }
+            write!(
+                f,
+                ", {:.1} updates/s, {} updates, {} gaps, {} disconnects",
+                venue.rate, venue.messages, venue.gaps, venue.disconnects
+            )?;
+            if venue.stale {
+                write!(f, ", stale")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::UNIX_EPOCH};
+
+    use super::*;
+    use crate::exchange::{OrderBook, Venue};
+
+    fn at(millis: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(millis)
+    }
+
+    fn event(message_id: Option<usize>, last_updated: Option<u64>, millis: u64) -> MarketEvent {
+        MarketEvent {
+            venue: Venue::DyDx,
+            symbol: "ETH-USD".parse().unwrap(),
+            order_book: Arc::new(OrderBook::new()),
+            last_updated,
+            message_id,
+            received_at: at(millis),
+            state: BookState::Live,
+            connection: None,
+        }
+    }
+
+    fn monitor() -> FeedMonitor {
+        FeedMonitor::new(vec!["DyDx".to_string()], Duration::from_secs(1))
+    }
+
+    #[test]
+    fn feeds_turn_stale_without_updates() {
+        let mut monitor = monitor();
+        assert!(!monitor.is_fresh(0, at(0)), "no update yet");
+
+        monitor.record(0, &event(Some(1), None, 0));
+        assert!(monitor.is_fresh(0, at(1000)));
+        assert!(!monitor.is_fresh(0, at(1001)));
+
+        monitor.record(0, &event(Some(2), None, 2000));
+        assert!(monitor.is_fresh(0, at(2000)));
+        let report = monitor.report(at(2500));
+        assert!(!report.venues[0].stale);
+        assert_eq!(report.venues[0].age, Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn message_rate_covers_the_last_ten_seconds() {
+        let mut monitor = monitor();
+        for index in 0..30 {
+            monitor.record(0, &event(Some(index + 1), None, index as u64 * 100));
+        }
+
+        assert_eq!(monitor.report(at(3000)).venues[0].rate, 3.0);
+        // The updates from 2.5s on are still in the window
+        let report = monitor.report(at(12500));
+        assert_eq!(report.venues[0].rate, 0.5);
+        assert_eq!(report.venues[0].messages, 30);
+    }
+
+    #[test]
+    fn repeated_messages_count_as_gaps() {
+        let mut monitor = monitor();
+        for message_id in [1, 2, 2, 3] {
+            monitor.record(0, &event(Some(message_id), None, 0));
+        }
+        for last_updated in [5, 7, 6] {
+            monitor.record(0, &event(None, Some(last_updated), 0));
+        }
+        // Updates of the bot itself
+        monitor.record(0, &event(None, None, 0));
+
+        let report = monitor.report(at(0));
+        assert_eq!(report.venues[0].gaps, 2);
+        assert_eq!(report.venues[0].messages, 7);
+    }
+
+    #[test]
+    fn dropped_books_start_their_sequence_again() {
+        let mut monitor = monitor();
+        monitor.record(0, &event(Some(1), None, 0));
+        monitor.record(0, &event(Some(2), None, 0));
+
+        // Counted once however long the book resyncs
+        for _ in 0..2 {
+            monitor.record(
+                0,
+                &MarketEvent {
+                    state: BookState::Resyncing,
+                    ..event(Some(3), None, 0)
+                },
+            );
+        }
+        monitor.record(
+            0,
+            &MarketEvent {
+                state: BookState::Resyncing,
+                connection: Some(ConnectionState::Disconnected {
+                    reason: "closed".to_string(),
+                    retry_in: Duration::from_secs(1),
+                }),
+                ..event(None, None, 0)
+            },
+        );
+        // A new connection starts from the first id
+        monitor.record(0, &event(Some(1), None, 0));
+        assert_eq!(monitor.report(at(0)).venues[0].gaps, 1);
+
+        monitor.record(0, &event(Some(1), None, 0));
+        let report = monitor.report(at(0));
+        assert_eq!(report.venues[0].gaps, 2);
+        assert_eq!(report.venues[0].disconnects, 1);
+    }
+}
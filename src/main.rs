@@ -1,19 +1,124 @@
-use std::str::FromStr;
+use std::{collections::HashSet, path::PathBuf, str::FromStr, time::Duration};
 
-use exchange::Symbol;
+use anyhow::anyhow;
+
+#[cfg(any(feature = "aevo-live", feature = "dydx-live"))]
+use exchange::ExecutionConfig;
+use exchange::{ConnectionConfig, PaperConfig, Symbol, Venue, ViolationPolicy};
+use execution::LegPolicy;
+use rebalance::RebalanceConfig;
+use risk::RiskConfig;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 
+mod backtest;
 mod bot;
+mod capture;
 mod exchange;
+mod execution;
+mod health;
+mod mock;
+mod pnl;
+mod portfolio;
+mod rebalance;
+mod risk;
+#[cfg(feature = "sqlite")]
+mod storage;
 
-struct Config {
-    aevo_symbol: Symbol,
-    aevo_fee: Decimal,
-    dydx_symbol: Symbol,
-    dydx_fee: Decimal,
+struct ExchangeConfig {
+    venue: Venue,
+    url: String,
+    fee: Decimal,
+    #[cfg(any(feature = "aevo-live", feature = "dydx-live"))]
+    execution: Option<ExecutionConfig>,
+}
+
+/// Pair traded on every exchange, with its own books, wallets and limits
+struct MarketConfig {
+    /// Prefix of the variables of the market, `None` for the single market
+    /// configured without `MARKETS`
+    name: Option<String>,
+    /// Symbol of the pair on every exchange, by index
+    symbols: Vec<Symbol>,
+    /// Maximum base amount held on every exchange, by index
+    max_positions: Vec<Option<Decimal>>,
     starting_value: Decimal,
-    persistent_trades: bool,
+    risk: RiskConfig,
+}
+
+impl MarketConfig {
+    /// Span of the logs of the market. The single unnamed market logs
+    /// without one
+    fn span(&self) -> tracing::Span {
+        match &self.name {
+            Some(name) => tracing::info_span!("market", name = %name),
+            None => tracing::Span::none(),
+        }
+    }
+
+    /// Name of a venue of the market in the reports and the database
+    fn venue_name(&self, venue: &str) -> String {
+        match &self.name {
+            Some(name) => format!("{} {}", name, venue),
+            None => venue.to_string(),
+        }
+    }
+}
+
+struct Config {
+    exchanges: Vec<ExchangeConfig>,
+    markets: Vec<MarketConfig>,
+    paper: PaperConfig,
+    leg_policy: LegPolicy,
+    /// Price tolerance of the orders correcting unbalanced legs
+    leg_slippage: Decimal,
+    rebalance: RebalanceConfig,
+    /// Age of the last update after which a feed is not traded on
+    feed_stale_after: Duration,
+    /// Reaction of the feeds to an invalid order book
+    book_policy: ViolationPolicy,
+    /// Heartbeats, idle timeout and reconnection backoff of the WebSockets
+    connection: ConnectionConfig,
+    record_file: Option<PathBuf>,
+    replay_file: Option<PathBuf>,
+    #[cfg(feature = "sqlite")]
+    database_file: Option<PathBuf>,
+}
+
+#[cfg(test)]
+impl Config {
+    /// Paper trading of BTC between Aevo and DyDx, without fees. The urls of
+    /// the exchanges are left empty
+    fn test() -> Self {
+        let exchange = |venue| ExchangeConfig {
+            venue,
+            url: String::new(),
+            fee: dec!(0),
+            #[cfg(any(feature = "aevo-live", feature = "dydx-live"))]
+            execution: None,
+        };
+        Config {
+            exchanges: vec![exchange(Venue::Aevo), exchange(Venue::DyDx)],
+            markets: vec![MarketConfig {
+                name: None,
+                symbols: vec!["BTC-PERP".parse().unwrap(), "BTC-USD".parse().unwrap()],
+                max_positions: vec![None, None],
+                starting_value: dec!(1000),
+                risk: RiskConfig::default(),
+            }],
+            paper: PaperConfig::default(),
+            leg_policy: LegPolicy::Unwind,
+            leg_slippage: dec!(0),
+            rebalance: RebalanceConfig::default(),
+            feed_stale_after: Duration::from_secs(5),
+            book_policy: ViolationPolicy::Resync,
+            connection: ConnectionConfig::default(),
+            record_file: None,
+            replay_file: None,
+            #[cfg(feature = "sqlite")]
+            database_file: None,
+        }
+    }
 }
 
 #[tokio::main]
@@ -25,23 +130,254 @@ async fn main() -> anyhow::Result<()> {
 
     tracing::info!("initializing...");
     // Configuration
-    let aevo_symbol = Symbol::from_str(&std::env::var("AEVO_SYMBOL")?)?;
-    let aevo_fee = std::env::var("AEVO_FEE")?.parse::<Decimal>()? / dec!(100);
-    let dydx_symbol = Symbol::from_str(&std::env::var("DYDX_SYMBOL")?)?;
-    let dydx_fee = std::env::var("DYDX_FEE")?.parse::<Decimal>()? / dec!(100);
-    let starting_value = std::env::var("STARTING_VALUE")?.parse()?;
-    let persistent_trades = std::env::var("PERSISTENT_TRADES")?.parse()?;
+    let mut exchanges = Vec::<ExchangeConfig>::new();
+    let names = std::env::var("EXCHANGES")?
+        .split(',')
+        .map(|name| name.trim().to_uppercase())
+        .collect::<Vec<_>>();
+    for name in &names {
+        #[cfg(not(any(feature = "aevo-live", feature = "dydx-live")))]
+        if optional_var(&format!("{}_API_KEY", name)).is_some() {
+            tracing::warn!(
+                "{}_API_KEY ignored, the aevo-live and dydx-live features are not enabled",
+                name
+            );
+        }
+        // Both books of a pair would be the same, and so would their orders
+        let venue = Venue::from_str(name)?;
+        if exchanges.iter().any(|exchange| exchange.venue == venue) {
+            anyhow::bail!("exchange {} is listed twice in EXCHANGES", name);
+        }
+        exchanges.push(ExchangeConfig {
+            venue,
+            url: std::env::var(format!("{}_URL", name))?,
+            fee: std::env::var(format!("{}_FEE", name))?.parse::<Decimal>()? / dec!(100),
+            #[cfg(any(feature = "aevo-live", feature = "dydx-live"))]
+            execution: match optional_var(&format!("{}_API_KEY", name)) {
+                Some(key) => Some(ExecutionConfig {
+                    rest_url: std::env::var(format!("{}_REST_URL", name))?,
+                    key,
+                    secret: std::env::var(format!("{}_API_SECRET", name))?,
+                    time_in_force: optional_var(&format!("{}_TIME_IN_FORCE", name))
+                        .map(|value| value.parse())
+                        .transpose()?
+                        .unwrap_or_default(),
+                    #[cfg(feature = "aevo-live")]
+                    account: optional_var(&format!("{}_ACCOUNT", name)),
+                    #[cfg(feature = "aevo-live")]
+                    signing_key: optional_var(&format!("{}_SIGNING_KEY", name)),
+                }),
+                None => None,
+            },
+        });
+    }
+    let markets = match optional_var("MARKETS") {
+        Some(markets) => markets
+            .split(',')
+            .map(|market| market_config(Some(market.trim().to_uppercase()), &names))
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        None => vec![market_config(None, &names)?],
+    };
+    // A book feeds a single market
+    for (index, name) in names.iter().enumerate() {
+        let mut symbols = HashSet::new();
+        for market in &markets {
+            if !symbols.insert(&market.symbols[index]) {
+                anyhow::bail!(
+                    "{} symbol {} is traded by two markets",
+                    name,
+                    market.symbols[index]
+                );
+            }
+        }
+    }
+    let paper = PaperConfig {
+        latency: Duration::from_millis(std::env::var("PAPER_LATENCY")?.parse()?),
+        reject_rate: std::env::var("PAPER_REJECT_RATE")?.parse::<f64>()? / 100.0,
+        seed: optional_var("PAPER_SEED")
+            .map(|seed| seed.parse())
+            .transpose()?,
+        persistent_trades: std::env::var("PERSISTENT_TRADES")?.parse()?,
+    };
+    let leg_policy = std::env::var("LEG_POLICY")?.parse()?;
+    let leg_slippage = std::env::var("LEG_SLIPPAGE")?.parse::<Decimal>()? / dec!(100);
+    let rebalance = RebalanceConfig {
+        method: std::env::var("REBALANCE_METHOD")?.parse()?,
+        threshold: std::env::var("REBALANCE_THRESHOLD")?.parse::<Decimal>()? / dec!(100),
+        transfer_fee: std::env::var("REBALANCE_TRANSFER_FEE")?.parse()?,
+        interval: Duration::from_secs(std::env::var("REBALANCE_INTERVAL")?.parse()?),
+        slippage: leg_slippage,
+    };
+    let feed_stale_after = Duration::from_millis(std::env::var("FEED_STALE_AFTER")?.parse()?);
+    let book_policy = std::env::var("BOOK_VIOLATION_POLICY")?.parse()?;
+    let connection = ConnectionConfig {
+        heartbeat: Duration::from_millis(std::env::var("WS_HEARTBEAT_INTERVAL")?.parse()?),
+        idle_timeout: Duration::from_millis(std::env::var("WS_IDLE_TIMEOUT")?.parse()?),
+        min_backoff: Duration::from_millis(std::env::var("WS_MIN_BACKOFF")?.parse()?),
+        max_backoff: Duration::from_millis(std::env::var("WS_MAX_BACKOFF")?.parse()?),
+    };
+    let record_file = optional_path("RECORD_FILE");
+    let replay_file = optional_path("REPLAY_FILE");
+    let backtest_file = optional_path("BACKTEST_FILE");
+    let database_file = optional_path("DATABASE_FILE");
+    #[cfg(not(feature = "sqlite"))]
+    if database_file.is_some() {
+        tracing::warn!("DATABASE_FILE ignored, the sqlite feature is not enabled");
+    }
+
+    if std::env::var("MOCK_EXCHANGES")?.parse()? {
+        // Replace the exchanges with local servers replaying a synthetic feed
+        // of every market
+        let mut mids = Vec::new();
+        for market in &markets {
+            let mid = market_var(market.name.as_deref(), "MOCK_PRICE")
+                .ok_or_else(|| anyhow!("MOCK_PRICE not set"))?;
+            mids.push(mid.parse()?);
+        }
+        let interval = Duration::from_millis(100);
+
+        for (index, exchange) in exchanges.iter_mut().enumerate() {
+            #[cfg_attr(not(feature = "dydx-live"), allow(unused_mut))]
+            let mut server = mock::MockServer::bind("127.0.0.1:0").await?;
+            exchange.url = server.url()?;
+
+            #[cfg(feature = "dydx-live")]
+            if let (Venue::DyDx, Some(execution)) = (exchange.venue, &mut exchange.execution) {
+                // The sidecar publishes the fills on the subaccount channel
+                let (account, _) = tokio::sync::broadcast::channel(1000);
+                server = server.with_account(account.clone());
+                let sidecar =
+                    mock::MockDyDxSidecar::bind("127.0.0.1:0", execution, account).await?;
+                execution.rest_url = sidecar.url()?;
+                sidecar.serve();
+                tracing::info!("using mock DyDx sidecar at {}", execution.rest_url);
+            }
+
+            // Shift the feeds so the exchanges diverge
+            let books = markets
+                .iter()
+                .zip(&mids)
+                .map(|(market, mid)| (market.symbols[index].clone(), *mid))
+                .collect::<Vec<_>>();
+            let frames = mock::frames(exchange.venue, &books, index * 4, 1000);
+            server.serve(frames, interval);
+            tracing::info!("using mock {:?} at {}", exchange.venue, exchange.url);
+
+            #[cfg(feature = "aevo-live")]
+            if let (Venue::Aevo, Some(execution)) = (exchange.venue, &mut exchange.execution) {
+                let server = mock::MockAevoRest::bind("127.0.0.1:0", execution).await?;
+                execution.rest_url = server.url()?;
+                server.serve();
+                tracing::info!("using mock Aevo REST API at {}", execution.rest_url);
+            }
+        }
+    }
 
     let config = Config {
-        aevo_symbol,
-        aevo_fee,
-        dydx_symbol,
-        dydx_fee,
-        starting_value,
-        persistent_trades,
+        exchanges,
+        markets,
+        paper,
+        leg_policy,
+        leg_slippage,
+        rebalance,
+        feed_stale_after,
+        book_policy,
+        connection,
+        record_file,
+        replay_file,
+        #[cfg(feature = "sqlite")]
+        database_file,
     };
 
-    bot::run_bot(&config).await?;
+    match backtest_file {
+        Some(path) => {
+            tracing::info!("backtesting {:?}", path);
+            // On a runtime of its own, its clock moves from one frame or
+            // order to the next instead of waiting
+            let report = tokio::task::block_in_place(|| {
+                backtest::runtime()?.block_on(backtest::run_backtest(&config, &path))
+            })?;
+            for line in report.to_string().lines() {
+                tracing::info!("{}", line);
+            }
+        }
+        None => {
+            let shutdown = async {
+                if let Err(err) = tokio::signal::ctrl_c().await {
+                    tracing::error!("failed to listen for ctrl-c: {}", err);
+                    std::future::pending().await
+                }
+            };
+            bot::run_bot(&config, shutdown).await?;
+        }
+    }
 
     Ok(())
 }
+
+/// Read a variable from the environment. Missing or empty variables disable
+/// the feature
+fn optional_var(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+/// Variable `KEY` of `market`, read from `MARKET_KEY` and falling back to the
+/// `KEY` shared by every market
+fn market_var(market: Option<&str>, key: &str) -> Option<String> {
+    market
+        .and_then(|market| optional_var(&format!("{}_{}", market, key)))
+        .or_else(|| optional_var(key))
+}
+
+/// Configuration of the market `name` traded on the exchanges `exchanges`
+fn market_config(name: Option<String>, exchanges: &[String]) -> anyhow::Result<MarketConfig> {
+    let market = name.as_deref();
+    let var = |key: &str| market_var(market, key);
+    let required = |key: &str| {
+        var(key).ok_or_else(|| match market {
+            Some(market) => anyhow!("{}_{} not set", market, key),
+            None => anyhow!("{} not set", key),
+        })
+    };
+
+    let mut symbols = Vec::new();
+    let mut max_positions = Vec::new();
+    for exchange in exchanges {
+        symbols.push(Symbol::from_str(&required(&format!(
+            "{}_SYMBOL",
+            exchange
+        ))?)?);
+        max_positions.push(
+            var(&format!("{}_MAX_POSITION", exchange))
+                .map(|value| value.parse())
+                .transpose()?,
+        );
+    }
+    let risk = RiskConfig {
+        max_notional: var("RISK_MAX_NOTIONAL")
+            .map(|value| value.parse())
+            .transpose()?,
+        max_exposure: var("RISK_MAX_EXPOSURE")
+            .map(|value| value.parse())
+            .transpose()?,
+        max_trades_per_minute: var("RISK_MAX_TRADES_PER_MINUTE")
+            .map(|value| value.parse())
+            .transpose()?,
+        max_daily_drawdown: var("RISK_MAX_DAILY_DRAWDOWN")
+            .map(|value| value.parse::<Decimal>())
+            .transpose()?
+            .map(|value| value / dec!(100)),
+    };
+
+    Ok(MarketConfig {
+        starting_value: required("STARTING_VALUE")?.parse()?,
+        name,
+        symbols,
+        max_positions,
+        risk,
+    })
+}
+
+fn optional_path(key: &str) -> Option<PathBuf> {
+    optional_var(key).map(PathBuf::from)
+}
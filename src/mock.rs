@@ -0,0 +1,592 @@
+//! Mock exchange WebSocket servers
+//!
+//! The servers replay a fixed list of text frames to every client that
+//! subscribes. They let the bot run end to end without reaching the real
+//! exchanges. The messages of the clients are kept and the connections can be
+//! dropped, to test the clients.
+
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde_json::json;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+};
+use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
+
+use crate::exchange::{aevo_checksum, BookEntry, Symbol, Venue};
+
+#[cfg(any(feature = "aevo-live", feature = "dydx-live"))]
+mod rest;
+#[cfg(feature = "aevo-live")]
+pub use rest::MockAevoRest;
+#[cfg(feature = "dydx-live")]
+pub use rest::MockDyDxSidecar;
+
+pub struct MockServer {
+    listener: TcpListener,
+    /// Contents of the `v4_subaccounts` channel updates
+    account: Option<AccountSender>,
+}
+
+impl MockServer {
+    pub async fn bind(addr: &str) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self {
+            listener,
+            account: None,
+        })
+    }
+
+    /// Serve DyDx `v4_subaccounts` subscriptions with the updates sent on
+    /// `account`
+    #[cfg(feature = "dydx-live")]
+    pub fn with_account(mut self, account: AccountSender) -> Self {
+        self.account = Some(account);
+        self
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// WebSocket url the exchanges should connect to
+    pub fn url(&self) -> anyhow::Result<String> {
+        Ok(format!("ws://{}", self.local_addr()?))
+    }
+
+    /// Accept connections in the background. Every client receives `frames`
+    /// after its first (subscription) message, one every `interval`.
+    pub fn serve(self, frames: Vec<String>, interval: Duration) -> MockHandle {
+        let handle = MockHandle {
+            received: Arc::new(Mutex::new(Vec::new())),
+            drops: broadcast::channel(1).0,
+        };
+        let server = handle.clone();
+        tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = self.listener.accept().await else {
+                    continue;
+                };
+                tokio::spawn(handle_client(
+                    stream,
+                    frames.clone(),
+                    interval,
+                    self.account.clone(),
+                    server.clone(),
+                ));
+            }
+        });
+        handle
+    }
+}
+
+pub type AccountSender = broadcast::Sender<serde_json::Value>;
+
+/// Handle to a running server
+#[derive(Clone)]
+pub struct MockHandle {
+    /// Text messages of every client, in the order they were received
+    received: Arc<Mutex<Vec<String>>>,
+    drops: broadcast::Sender<()>,
+}
+
+impl MockHandle {
+    fn receive(&self, message: &str) {
+        self.received.lock().unwrap().push(message.to_string());
+    }
+
+    #[cfg(test)]
+    pub fn received(&self) -> Vec<String> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Close every open connection without a close frame, like a lost
+    /// network
+    #[cfg(test)]
+    pub fn drop_connections(&self) {
+        let _ = self.drops.send(());
+    }
+}
+
+async fn handle_client(
+    stream: TcpStream,
+    frames: Vec<String>,
+    interval: Duration,
+    account: Option<AccountSender>,
+    server: MockHandle,
+) {
+    let Ok(mut wss_stream) = accept_async(stream).await else {
+        return;
+    };
+    let mut drops = server.drops.subscribe();
+
+    // Wait for the subscription before replaying anything
+    let Some(Ok(subscription)) = wss_stream.next().await else {
+        return;
+    };
+
+    let subscription = subscription.into_text().unwrap_or_default();
+    server.receive(&subscription);
+    if let Some(account) = account {
+        if subscription.contains("v4_subaccounts") {
+            handle_account(wss_stream, account).await;
+            return;
+        }
+    }
+    let mut subscriptions = HashSet::from([subscription]);
+
+    // Once the frames are sent keep the connection open, otherwise the client
+    // reconnects and the feed starts again
+    let mut next = 0;
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticks.tick(), if next < frames.len() => {
+                if wss_stream.send(Message::Text(frames[next].clone())).await.is_err() {
+                    return;
+                }
+                next += 1;
+            }
+            _ = drops.recv() => return,
+            incoming = wss_stream.next() => {
+                let text = match incoming {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(_)) => continue,
+                    _ => return,
+                };
+                server.receive(&text);
+
+                // The frames carry every book, the first subscription of the
+                // others goes on. A repeated one, to resync, starts again from
+                // the snapshot
+                if text.contains("\"subscribe\"") {
+                    if !subscriptions.insert(text) {
+                        next = 0;
+                    }
+                // Aevo heartbeat, the WebSocket pings are answered by the
+                // library
+                } else if text.contains("\"ping\"") {
+                    let pong = json!({"id": 1, "data": {"success": true}});
+                    if wss_stream.send(Message::Text(pong.to_string())).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Send the `v4_subaccounts` updates published on `account`
+async fn handle_account(mut wss_stream: WebSocketStream<TcpStream>, account: AccountSender) {
+    let mut updates = account.subscribe();
+    let mut message_id = 1;
+    let mut message = |msg_type: &str, contents: serde_json::Value| {
+        message_id += 1;
+        json!({
+            "type": msg_type,
+            "connection_id": "mock",
+            "message_id": message_id,
+            "channel": "v4_subaccounts",
+            "id": "mock",
+            "contents": contents,
+        })
+        .to_string()
+    };
+
+    let subscribed = message("subscribed", json!({"subaccount": {}}));
+    if wss_stream.send(Message::Text(subscribed)).await.is_err() {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            update = updates.recv() => {
+                let Ok(update) = update else {
+                    return;
+                };
+                let frame = message("channel_data", update);
+                if wss_stream.send(Message::Text(frame)).await.is_err() {
+                    return;
+                }
+            }
+            incoming = wss_stream.next() => {
+                if !matches!(incoming, Some(Ok(_))) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Relative moves of the mid price at every step of the synthetic feed
+const OFFSETS: [Decimal; 8] = [
+    dec!(0),
+    dec!(0.0005),
+    dec!(0.001),
+    dec!(0.0005),
+    dec!(0),
+    dec!(-0.0005),
+    dec!(-0.001),
+    dec!(-0.0005),
+];
+
+fn phase_mid(mid: Decimal, phase: usize) -> Decimal {
+    mid + mid * OFFSETS[phase % OFFSETS.len()]
+}
+
+/// Top of book of the synthetic feed as `(side, price, amount)` levels. The
+/// first `steps * 4` entries after the snapshot move the best bid and ask
+/// following `OFFSETS`, removing the previous level each time.
+fn synthetic_levels(
+    mid: Decimal,
+    phase: usize,
+    steps: usize,
+) -> Vec<(&'static str, Decimal, Decimal)> {
+    let half_spread = dec!(0.5);
+    let amount = dec!(0.1);
+    let mut levels = Vec::with_capacity(steps * 4);
+    let mut curr = phase_mid(mid, phase);
+
+    for step in 1..=steps {
+        let next = phase_mid(mid, phase + step);
+        let ask = [
+            ("asks", curr + half_spread, dec!(0)),
+            ("asks", next + half_spread, amount),
+        ];
+        let bid = [
+            ("bids", curr - half_spread, dec!(0)),
+            ("bids", next - half_spread, amount),
+        ];
+        // Move the side in the direction of the price first so the book is
+        // never crossed
+        if next >= curr {
+            levels.extend(ask.into_iter().chain(bid));
+        } else {
+            levels.extend(bid.into_iter().chain(ask));
+        }
+        curr = next;
+    }
+
+    levels
+}
+
+/// Frames in the format of `venue`: for every `(symbol, mid)` book a
+/// snapshot around `mid` followed by `steps` moves of the top of book, the
+/// books taking turns. Feeds with a different `phase` diverge from each other.
+pub fn frames(
+    venue: Venue,
+    books: &[(Symbol, Decimal)],
+    phase: usize,
+    steps: usize,
+) -> Vec<String> {
+    let mut books = books
+        .iter()
+        .map(|(symbol, mid)| {
+            match venue {
+                Venue::Aevo => aevo_frames(symbol, *mid, phase, steps),
+                Venue::DyDx => dydx_frames(symbol, *mid, phase, steps),
+            }
+            .into_iter()
+        })
+        .collect::<Vec<_>>();
+
+    let mut frames = Vec::new();
+    loop {
+        let round = books
+            .iter_mut()
+            .filter_map(|book| book.next())
+            .collect::<Vec<_>>();
+        if round.is_empty() {
+            break;
+        }
+        frames.extend(round);
+    }
+
+    // The DyDx ids are shared by every channel of the connection
+    if venue == Venue::DyDx {
+        for (index, frame) in frames.iter_mut().enumerate() {
+            frame["message_id"] = json!(index + 1);
+        }
+    }
+    frames.iter().map(|frame| frame.to_string()).collect()
+}
+
+fn aevo_frames(
+    symbol: &Symbol,
+    mid: Decimal,
+    phase: usize,
+    steps: usize,
+) -> Vec<serde_json::Value> {
+    let book = |msg_type: &str, bids: Vec<[String; 2]>, asks: Vec<[String; 2]>, checksum: u32| {
+        json!({
+            "channel": format!("orderbook:{}", symbol),
+            "data": {
+                "type": msg_type,
+                "instrument_id": "1",
+                "instrument_name": symbol.to_string(),
+                "instrument_type": "PERPETUAL",
+                "bids": bids,
+                "asks": asks,
+                "last_updated": "0",
+                "checksum": checksum.to_string(),
+            },
+            "write_ts": "0",
+        })
+    };
+
+    let start = phase_mid(mid, phase);
+    // Book of the clients, to compute the checksums
+    let mut bids = vec![BookEntry {
+        price: start - dec!(0.5),
+        amount: dec!(0.1),
+    }];
+    let mut asks = vec![BookEntry {
+        price: start + dec!(0.5),
+        amount: dec!(0.1),
+    }];
+    let mut frames = vec![book(
+        "snapshot",
+        vec![[bids[0].price.to_string(), bids[0].amount.to_string()]],
+        vec![[asks[0].price.to_string(), asks[0].amount.to_string()]],
+        aevo_checksum(&bids, &asks),
+    )];
+    for (side, price, amount) in synthetic_levels(mid, phase, steps) {
+        let level = vec![[price.to_string(), amount.to_string()]];
+        frames.push(match side {
+            "bids" => {
+                set_level(&mut bids, price, amount, |a, b| b.cmp(a));
+                book("update", level, Vec::new(), aevo_checksum(&bids, &asks))
+            }
+            _ => {
+                set_level(&mut asks, price, amount, |a, b| a.cmp(b));
+                book("update", Vec::new(), level, aevo_checksum(&bids, &asks))
+            }
+        });
+    }
+
+    frames
+}
+
+/// Set the amount of the level at `price` of a side sorted with `order`,
+/// removing it if `amount` is zero
+fn set_level(
+    levels: &mut Vec<BookEntry>,
+    price: Decimal,
+    amount: Decimal,
+    order: fn(&Decimal, &Decimal) -> std::cmp::Ordering,
+) {
+    levels.retain(|level| level.price != price);
+    if !amount.is_zero() {
+        let index = levels.partition_point(|level| order(&level.price, &price).is_lt());
+        levels.insert(index, BookEntry { price, amount });
+    }
+}
+
+fn dydx_frames(
+    symbol: &Symbol,
+    mid: Decimal,
+    phase: usize,
+    steps: usize,
+) -> Vec<serde_json::Value> {
+    let start = phase_mid(mid, phase);
+    let mut frames = vec![json!({
+        "type": "subscribed",
+        "connection_id": "mock",
+        "channel": "v4_orderbook",
+        "id": symbol.to_string(),
+        "contents": {
+            "bids": [{"price": (start - dec!(0.5)).to_string(), "size": "0.1"}],
+            "asks": [{"price": (start + dec!(0.5)).to_string(), "size": "0.1"}],
+        },
+    })];
+
+    // The message ids are set once the books are merged
+    for (side, price, amount) in synthetic_levels(mid, phase, steps) {
+        frames.push(json!({
+            "type": "channel_data",
+            "connection_id": "mock",
+            "channel": "v4_orderbook",
+            "id": symbol.to_string(),
+            "contents": { side: [[price.to_string(), amount.to_string()]] },
+        }));
+    }
+
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::exchange::{
+        BookState, ConnectionConfig, ConnectionState, FeedSource, MarketEvent, ViolationPolicy,
+    };
+
+    const STEPS: usize = 10;
+
+    /// Run the feed of `venue` against a mock server replaying the frames of
+    /// `symbol`, until its book has received every frame. Returns the events
+    /// of the book and whether the connection was reported.
+    async fn run_feed(venue: Venue, symbol: &str, phase: usize) -> (Vec<MarketEvent>, bool) {
+        let symbol = symbol.parse::<Symbol>().unwrap();
+        let server = MockServer::bind("127.0.0.1:0").await.unwrap();
+        let url = server.url().unwrap();
+        server.serve(
+            frames(venue, &[(symbol.clone(), dec!(100))], phase, STEPS),
+            Duration::from_millis(1),
+        );
+
+        let source = FeedSource::Live {
+            url,
+            recorder: None,
+            connection: ConnectionConfig::default(),
+        };
+        let mut feed = venue.feed(source, ViolationPolicy::Resync);
+        let handle = feed.order_book_subscribe(&symbol);
+
+        // The snapshot and four levels every step
+        let mut events = Vec::new();
+        let mut connected = false;
+        while events.len() < 1 + STEPS * 4 {
+            let event = tokio::time::timeout(Duration::from_secs(10), feed.next())
+                .await
+                .expect("feed stalled")
+                .expect("feed ended");
+            assert_eq!(event.venue, venue);
+            assert_eq!(event.symbol, symbol);
+            match event.connection {
+                Some(ConnectionState::Connected) => connected = true,
+                Some(connection) => panic!("{:?} {}", venue, connection),
+                None => events.push(event),
+            }
+        }
+
+        // The handle reads the book of the last event
+        let last = events.last().unwrap();
+        assert!(Arc::ptr_eq(&handle.current(), &last.order_book));
+        (events, connected)
+    }
+
+    fn check_books(events: &[MarketEvent], phase: usize) {
+        // A level is removed before the next one is set, a side can be empty
+        // for one frame
+        for event in events {
+            assert_eq!(event.state, BookState::Live);
+            let book = &event.order_book;
+            assert!(book.bids().count() + book.asks().count() >= 1);
+            assert!(book
+                .bids()
+                .chain(book.asks())
+                .all(|level| level.amount == dec!(0.1)));
+            if let (Some(bid), Some(ask)) = (book.best_bid(), book.best_ask()) {
+                assert!(bid.price < ask.price);
+            }
+        }
+
+        // The top of book ends around the mid of the last step
+        let book = &events.last().unwrap().order_book;
+        assert_eq!(book.bids().count(), 1);
+        assert_eq!(book.asks().count(), 1);
+        assert_eq!(book.mid_price(), Some(phase_mid(dec!(100), phase + STEPS)));
+    }
+
+    #[tokio::test]
+    async fn feeds_follow_the_mock_servers() {
+        let ((aevo, aevo_connected), (dydx, dydx_connected)) = tokio::join!(
+            run_feed(Venue::Aevo, "ETH-PERP", 0),
+            run_feed(Venue::DyDx, "ETH-USD", 4),
+        );
+
+        assert!(aevo_connected);
+        check_books(&aevo, 0);
+        assert!(aevo.iter().all(|event| event.last_updated == Some(0)));
+
+        assert!(dydx_connected);
+        check_books(&dydx, 4);
+        let ids = dydx
+            .iter()
+            .map(|event| event.message_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, (1..=1 + STEPS * 4).map(Some).collect::<Vec<_>>());
+    }
+
+    /// Follow two books of `venue`, drop `dropped` once it has an event and
+    /// return the events received until the server is done
+    async fn unsubscribe(
+        venue: Venue,
+        kept: &str,
+        dropped: &str,
+    ) -> (Vec<MarketEvent>, MockHandle) {
+        let (kept, dropped) = (
+            kept.parse::<Symbol>().unwrap(),
+            dropped.parse::<Symbol>().unwrap(),
+        );
+        let server = MockServer::bind("127.0.0.1:0").await.unwrap();
+        let url = server.url().unwrap();
+        let handle = server.serve(
+            frames(
+                venue,
+                &[(kept.clone(), dec!(100)), (dropped.clone(), dec!(200))],
+                0,
+                STEPS,
+            ),
+            Duration::from_millis(5),
+        );
+
+        let source = FeedSource::Live {
+            url,
+            recorder: None,
+            connection: ConnectionConfig::default(),
+        };
+        let mut feed = venue.feed(source, ViolationPolicy::Resync);
+        feed.order_book_subscribe(&kept);
+        feed.order_book_subscribe(&dropped);
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(10), feed.next())
+                .await
+                .expect("feed stalled")
+                .expect("feed ended");
+            if event.symbol == dropped && event.connection.is_none() {
+                break;
+            }
+        }
+        feed.order_book_unsubscribe(&dropped);
+
+        let mut events = Vec::new();
+        while let Ok(event) = tokio::time::timeout(Duration::from_millis(500), feed.next()).await {
+            events.push(event.expect("feed ended"));
+        }
+        (events, handle)
+    }
+
+    #[tokio::test]
+    async fn unsubscribed_books_are_removed() {
+        let ((aevo, aevo_server), (dydx, dydx_server)) = tokio::join!(
+            unsubscribe(Venue::Aevo, "ETH-PERP", "BTC-PERP"),
+            unsubscribe(Venue::DyDx, "ETH-USD", "BTC-USD"),
+        );
+
+        // The other book goes on to the end of the frames
+        for (events, kept) in [(aevo, "ETH-PERP"), (dydx, "ETH-USD")] {
+            assert!(events.iter().all(|event| event.symbol.to_string() == kept));
+            let book = &events.last().unwrap().order_book;
+            assert_eq!(book.mid_price(), Some(phase_mid(dec!(100), STEPS)));
+        }
+        assert!(aevo_server
+            .received()
+            .contains(&json!({"op": "unsubscribe", "data": ["orderbook:BTC-PERP"]}).to_string()));
+        assert!(dydx_server.received().contains(
+            &json!({"type": "unsubscribe", "channel": "v4_orderbook", "id": "BTC-USD"}).to_string()
+        ));
+    }
+}
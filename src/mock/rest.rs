@@ -0,0 +1,363 @@
+//! Stand-ins for the HTTP APIs used by the live execution backends
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+#[cfg(feature = "aevo-live")]
+use rust_decimal::Decimal;
+#[cfg(feature = "aevo-live")]
+use rust_decimal_macros::dec;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+#[cfg(feature = "aevo-live")]
+use crate::exchange::AevoOrderRequest;
+use crate::exchange::ExecutionConfig;
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: String,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> &str {
+        self.headers
+            .get(&name.to_lowercase())
+            .map_or("", |value| value.as_str())
+    }
+}
+
+/// Read a request: the head, then the body announced by its length
+async fn read_request(stream: &mut TcpStream) -> Option<HttpRequest> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+    let head_end = loop {
+        if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => request.extend_from_slice(&buffer[..read]),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&request[..head_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_lowercase(), value.trim().to_string()))
+        .collect::<HashMap<_, _>>();
+
+    let length = headers
+        .get("content-length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or(0);
+    while request.len() < head_end + length {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => request.extend_from_slice(&buffer[..read]),
+        }
+    }
+    let body = String::from_utf8_lossy(&request[head_end..head_end + length]).to_string();
+
+    Some(HttpRequest {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+async fn write_response(stream: &mut TcpStream, status: &str, body: Value) {
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/// Accept connections in the background, answering every request with
+/// `handler`
+fn serve_http<F>(listener: TcpListener, handler: F)
+where
+    F: Fn(HttpRequest) -> (&'static str, Value) + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    tokio::spawn(async move {
+        loop {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                continue;
+            };
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                if let Some(request) = read_request(&mut stream).await {
+                    let (status, body) = handler(request);
+                    write_response(&mut stream, status, body).await;
+                }
+            });
+        }
+    });
+}
+
+/// Stand-in for the Aevo REST API. Requests must be signed with the given
+/// credentials, and orders with its signing key. Immediate or cancel orders
+/// are filled at their limit price, the other orders are filled the first
+/// time their status is requested.
+#[cfg(feature = "aevo-live")]
+pub struct MockAevoRest {
+    listener: TcpListener,
+    config: ExecutionConfig,
+    /// Orders that are not immediate or cancel stay open until cancelled
+    resting: bool,
+}
+
+#[cfg(feature = "aevo-live")]
+impl MockAevoRest {
+    pub async fn bind(addr: &str, config: &ExecutionConfig) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self {
+            listener,
+            config: config.clone(),
+            resting: false,
+        })
+    }
+
+    /// Never fill the orders that are not immediate or cancel
+    #[cfg(test)]
+    pub fn with_resting_orders(mut self) -> Self {
+        self.resting = true;
+        self
+    }
+
+    pub fn url(&self) -> anyhow::Result<String> {
+        Ok(format!("http://{}", self.listener.local_addr()?))
+    }
+
+    pub fn serve(self) {
+        let orders = Mutex::new(HashMap::new());
+        let url = self.url().unwrap_or_default();
+        let resting = self.resting;
+        let config = self.config;
+        let signing_key = config
+            .signing_key
+            .as_deref()
+            .and_then(|key| crate::exchange::aevo_signing_key(key).ok());
+
+        serve_http(self.listener, move |request| {
+            let signature = crate::exchange::aevo_sign(
+                &config.key,
+                &config.secret,
+                request.header("aevo-timestamp"),
+                &request.method,
+                &request.path,
+                &request.body,
+            )
+            .unwrap_or_default();
+
+            if request.header("aevo-key") != config.key
+                || request.header("aevo-signature") != signature
+            {
+                return ("401 Unauthorized", json!({"error": "INVALID_SIGNATURE"}));
+            }
+            if request.method == "POST" && request.path == "/orders" {
+                let Ok(order) = serde_json::from_str::<AevoOrderRequest>(&request.body) else {
+                    return ("400 Bad Request", json!({"error": "INVALID_BODY"}));
+                };
+                let signed = signing_key
+                    .as_ref()
+                    .and_then(|key| crate::exchange::aevo_sign_order(key, &url, &order).ok());
+                if signed.as_ref() != Some(&order.signature) {
+                    return (
+                        "400 Bad Request",
+                        json!({"error": "INVALID_ORDER_SIGNATURE"}),
+                    );
+                }
+            }
+            aevo_response(&request, &mut orders.lock().unwrap(), resting)
+        });
+    }
+}
+
+#[cfg(feature = "aevo-live")]
+fn aevo_response(
+    request: &HttpRequest,
+    orders: &mut HashMap<String, Value>,
+    resting: bool,
+) -> (&'static str, Value) {
+    if let Some(name) = request.path.strip_prefix("/instrument/") {
+        return (
+            "200 OK",
+            json!({"instrument_id": "1", "instrument_name": name}),
+        );
+    }
+
+    match (
+        request.method.as_str(),
+        request.path.strip_prefix("/orders"),
+    ) {
+        ("POST", Some("")) => {
+            let Ok(order) = serde_json::from_str::<Value>(&request.body) else {
+                return ("400 Bad Request", json!({"error": "INVALID_BODY"}));
+            };
+            let order_id = format!("0x{:x}", orders.len() + 1);
+            // Amounts and prices are sent in millionths
+            let decimal = |value: &Value| {
+                value
+                    .as_str()
+                    .and_then(|value| value.parse::<Decimal>().ok())
+                    .map(|value| (value / dec!(1_000_000)).to_string())
+            };
+            let (Some(amount), Some(price)) =
+                (decimal(&order["amount"]), decimal(&order["limit_price"]))
+            else {
+                return ("400 Bad Request", json!({"error": "INVALID_BODY"}));
+            };
+            let status = if order["time_in_force"] == "IOC" {
+                json!({"order_id": order_id, "order_status": "filled", "amount": amount, "filled": amount, "avg_price": price})
+            } else {
+                json!({"order_id": order_id, "order_status": "opened", "amount": amount, "filled": "0", "avg_price": null, "price": price})
+            };
+            orders.insert(order_id, status.clone());
+            ("200 OK", status)
+        }
+        (method, Some(order_id)) => {
+            let Some(order) = orders.get_mut(order_id.trim_start_matches('/')) else {
+                return ("404 Not Found", json!({"error": "ORDER_DOES_NOT_EXIST"}));
+            };
+            if order["order_status"] == "opened" {
+                if method == "DELETE" {
+                    order["order_status"] = json!("cancelled");
+                } else if !resting {
+                    order["order_status"] = json!("filled");
+                    order["filled"] = order["amount"].clone();
+                    order["avg_price"] = order["price"].clone();
+                }
+            }
+            ("200 OK", order.clone())
+        }
+        _ => ("404 Not Found", json!({"error": "NOT_FOUND"})),
+    }
+}
+
+/// Stand-in for the dYdX order signing sidecar, following the contract of
+/// `exchange/dydx/trading.rs`. Requests must carry the secret as bearer token
+/// and trade the subaccount of the API key. The chain makes one block a
+/// second. The resulting order and fill updates are published on `account`,
+/// which feeds the `v4_subaccounts` channel of a [`super::MockServer`].
+/// Immediate or cancel orders are filled at once, the other orders stay open
+/// until cancelled.
+#[cfg(feature = "dydx-live")]
+pub struct MockDyDxSidecar {
+    listener: TcpListener,
+    config: ExecutionConfig,
+    account: tokio::sync::broadcast::Sender<Value>,
+}
+
+#[cfg(feature = "dydx-live")]
+impl MockDyDxSidecar {
+    pub async fn bind(
+        addr: &str,
+        config: &ExecutionConfig,
+        account: tokio::sync::broadcast::Sender<Value>,
+    ) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self {
+            listener,
+            config: config.clone(),
+            account,
+        })
+    }
+
+    pub fn url(&self) -> anyhow::Result<String> {
+        Ok(format!("http://{}", self.listener.local_addr()?))
+    }
+
+    pub fn serve(self) {
+        // Open orders by client id, with their indexer id
+        let orders = Mutex::new(HashMap::<String, (String, Value)>::new());
+        let config = self.config;
+        let account = self.account;
+        let genesis = tokio::time::Instant::now();
+
+        serve_http(self.listener, move |request| {
+            if request.header("authorization") != format!("Bearer {}", config.secret) {
+                return ("401 Unauthorized", json!({"error": "INVALID_TOKEN"}));
+            }
+            let height = 1000 + genesis.elapsed().as_secs();
+            if request.method == "GET" && request.path == "/height" {
+                return ("200 OK", json!({"height": height}));
+            }
+
+            let Ok(body) = serde_json::from_str::<Value>(&request.body) else {
+                return ("400 Bad Request", json!({"error": "INVALID_BODY"}));
+            };
+            if body["subaccount"] != config.key.as_str() {
+                return ("400 Bad Request", json!({"error": "INVALID_SUBACCOUNT"}));
+            }
+            // Short-term orders and their cancellations are valid up to 20
+            // blocks ahead
+            let good_til_block = body["goodTilBlock"].as_u64().unwrap_or_default();
+            if good_til_block <= height || good_til_block > height + 20 {
+                return (
+                    "400 Bad Request",
+                    json!({"error": "INVALID_GOOD_TIL_BLOCK"}),
+                );
+            }
+            let mut orders = orders.lock().unwrap();
+
+            match (
+                request.method.as_str(),
+                request.path.strip_prefix("/orders"),
+            ) {
+                ("POST", Some("")) => {
+                    let order = body;
+                    let client_id = order["clientId"].to_string();
+                    let id = format!("mock-{}", client_id);
+
+                    if order["timeInForce"] == "IOC" {
+                        let _ = account.send(json!({
+                            "orders": [{"id": id, "clientId": client_id, "status": "FILLED", "size": order["size"], "totalFilled": order["size"]}],
+                            "fills": [{"id": format!("{}-fill", id), "orderId": id, "side": order["side"], "size": order["size"], "price": order["price"], "fee": "0"}],
+                        }));
+                    } else {
+                        let _ = account.send(json!({
+                            "orders": [{"id": id, "clientId": client_id, "status": "OPEN", "size": order["size"], "totalFilled": "0"}],
+                        }));
+                        orders.insert(client_id.clone(), (id.clone(), order));
+                    }
+                    (
+                        "200 OK",
+                        json!({"clientId": client_id, "txHash": format!("{}-place", id)}),
+                    )
+                }
+                ("DELETE", Some(client_id)) => {
+                    let client_id = client_id.trim_start_matches('/');
+                    let Some((id, order)) = orders.remove(client_id) else {
+                        return ("404 Not Found", json!({"error": "ORDER_NOT_FOUND"}));
+                    };
+                    let _ = account.send(json!({
+                        "orders": [{"id": id, "clientId": client_id, "status": "CANCELED", "size": order["size"], "totalFilled": "0"}],
+                    }));
+                    (
+                        "200 OK",
+                        json!({"clientId": client_id, "txHash": format!("{}-cancel", id)}),
+                    )
+                }
+                _ => ("404 Not Found", json!({"error": "NOT_FOUND"})),
+            }
+        });
+    }
+}
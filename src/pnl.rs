@@ -0,0 +1,440 @@
+//! Profit and loss accounting
+//!
+//! Every fill is kept in a ledger. The two legs of an arbitrage, with their
+//! corrections, form a round trip: the amount both bought and sold is realized
+//! at once, what is left open joins the inventory. The base token held over
+//! all the venues is valued at its average cost, the unrealized P&L of a venue
+//! is its base balance marked from that cost to the mid price of the venue.
+
+use std::fmt::Display;
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::{
+    bot::Trade,
+    exchange::Side,
+    portfolio::{Asset, Portfolio},
+};
+
+/// Fill of one order on the venue of index `venue`
+#[derive(Clone, Debug)]
+pub struct LegFill {
+    pub venue: usize,
+    pub side: Side,
+    pub amount: Decimal,
+    pub price: Decimal,
+    /// Fee paid, in quote token
+    pub fee: Decimal,
+}
+
+#[derive(Clone, Debug)]
+pub struct LedgerEntry {
+    /// Index of the round trip of the fill, `None` for rebalancing trades
+    pub round_trip: Option<usize>,
+    pub fill: LegFill,
+}
+
+#[derive(Clone, Debug)]
+pub struct RoundTrip {
+    /// Amount both bought and sold
+    pub amount: Decimal,
+    pub buy_price: Decimal,
+    pub sell_price: Decimal,
+    pub fees: Decimal,
+    /// Amount left open, negative if more was sold
+    pub exposure: Decimal,
+    /// Profit net of the fees
+    pub realized: Decimal,
+}
+
+pub struct PnlEngine {
+    venues: Vec<String>,
+    starting_equity: Decimal,
+    /// Base token held over all the venues and what it cost
+    position: Decimal,
+    cost: Decimal,
+    /// Profit of the fills closing part of the position, before fees
+    closed: Decimal,
+    transfer_costs: Decimal,
+    ledger: Vec<LedgerEntry>,
+    round_trips: Vec<RoundTrip>,
+}
+
+impl PnlEngine {
+    /// `venues` are the names of the venues, by index
+    pub fn new(venues: Vec<String>) -> Self {
+        Self {
+            venues,
+            starting_equity: dec!(0),
+            position: dec!(0),
+            cost: dec!(0),
+            closed: dec!(0),
+            transfer_costs: dec!(0),
+            ledger: Vec::new(),
+            round_trips: Vec::new(),
+        }
+    }
+
+    /// Start from the balances of `portfolio`, with the base token bought at
+    /// `price`
+    pub fn open(&mut self, portfolio: &Portfolio, price: Decimal) {
+        let wallets = (0..portfolio.venues()).map(|venue| portfolio.wallet(venue));
+        self.position = wallets.clone().map(|wallet| wallet.base).sum();
+        self.cost = self.position * price;
+        self.starting_equity = wallets.map(|wallet| wallet.quote).sum::<Decimal>() + self.cost;
+    }
+
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    pub fn ledger(&self) -> &[LedgerEntry] {
+        &self.ledger
+    }
+
+    fn average_cost(&self) -> Decimal {
+        if self.position.is_zero() {
+            dec!(0)
+        } else {
+            self.cost / self.position
+        }
+    }
+
+    /// Add a buy to the position or close part of it with a sell. Returns the
+    /// profit of the sell against the average cost.
+    fn apply(&mut self, side: Side, amount: Decimal, price: Decimal) -> Decimal {
+        match side {
+            Side::Buy => {
+                self.position += amount;
+                self.cost += amount * price;
+                dec!(0)
+            }
+            Side::Sell => {
+                let average_cost = self.average_cost();
+                self.position -= amount;
+                self.cost -= amount * average_cost;
+                amount * (price - average_cost)
+            }
+        }
+    }
+
+    pub fn record_round_trip(&mut self, trade: &Trade) -> &RoundTrip {
+        let index = self.round_trips.len();
+        self.ledger
+            .extend(trade.fills.iter().map(|fill| LedgerEntry {
+                round_trip: Some(index),
+                fill: fill.clone(),
+            }));
+
+        // The amount both bought and sold does not change the position
+        let mut profit = trade.gross();
+        if trade.exposure > dec!(0) {
+            profit += self.apply(Side::Buy, trade.exposure, trade.buy_price);
+        } else if trade.exposure < dec!(0) {
+            profit += self.apply(Side::Sell, -trade.exposure, trade.sell_price);
+        }
+        self.closed += profit;
+
+        self.round_trips.push(RoundTrip {
+            amount: trade.amount,
+            buy_price: trade.buy_price,
+            sell_price: trade.sell_price,
+            fees: trade.fees,
+            exposure: trade.exposure,
+            realized: profit - trade.fees,
+        });
+        &self.round_trips[index]
+    }
+
+    /// Record a fill outside of any round trip
+    pub fn record_fill(&mut self, fill: LegFill) {
+        let profit = self.apply(fill.side, fill.amount, fill.price);
+        self.closed += profit;
+        self.ledger.push(LedgerEntry {
+            round_trip: None,
+            fill,
+        });
+    }
+
+    /// Record the fee of a transfer, paid in the transferred `asset`
+    pub fn record_transfer(&mut self, asset: Asset, fee: Decimal) {
+        self.transfer_costs += match asset {
+            // The base token lost leaves the position at its cost
+            Asset::Base => {
+                let cost = fee * self.average_cost();
+                self.position -= fee;
+                self.cost -= cost;
+                cost
+            }
+            Asset::Quote => fee,
+        };
+    }
+
+    /// P&L of `portfolio` with the base token of every venue at the mid price
+    /// of `mids`, indexed like the venues
+    pub fn report(&self, portfolio: &Portfolio, mids: &[Decimal]) -> PnlReport {
+        let average_cost = self.average_cost();
+        let venues = self
+            .venues
+            .iter()
+            .zip(mids)
+            .enumerate()
+            .map(|(index, (venue, mid))| {
+                let wallet = portfolio.wallet(index);
+                VenuePnl {
+                    venue: venue.clone(),
+                    mid: *mid,
+                    position: wallet.base,
+                    equity: wallet.quote + wallet.base * mid,
+                    unrealized: wallet.base * (mid - average_cost),
+                    fees: self
+                        .ledger
+                        .iter()
+                        .filter(|entry| entry.fill.venue == index)
+                        .map(|entry| entry.fill.fee)
+                        .sum(),
+                }
+            })
+            .collect::<Vec<_>>();
+        let fees = venues.iter().map(|venue| venue.fees).sum::<Decimal>();
+        let rebalancing_fees = self
+            .ledger
+            .iter()
+            .filter(|entry| entry.round_trip.is_none())
+            .map(|entry| entry.fill.fee)
+            .sum();
+
+        PnlReport {
+            starting_equity: self.starting_equity,
+            equity: venues.iter().map(|venue| venue.equity).sum(),
+            round_trips: self.round_trips.len(),
+            gross: self
+                .round_trips
+                .iter()
+                .map(|round_trip| {
+                    round_trip.amount * (round_trip.sell_price - round_trip.buy_price)
+                })
+                .sum(),
+            realized: self.closed - fees - self.transfer_costs,
+            unrealized: venues.iter().map(|venue| venue.unrealized).sum(),
+            fees,
+            rebalancing_fees,
+            transfer_costs: self.transfer_costs,
+            average_cost,
+            venues,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VenuePnl {
+    pub venue: String,
+    pub mid: Decimal,
+    /// Base token held on the venue
+    pub position: Decimal,
+    /// Value of the wallet of the venue at its mid price
+    pub equity: Decimal,
+    pub unrealized: Decimal,
+    /// Fees paid on the venue so far
+    pub fees: Decimal,
+}
+
+/// P&L at one point in time, in quote token
+#[derive(Clone, Debug)]
+pub struct PnlReport {
+    pub starting_equity: Decimal,
+    pub equity: Decimal,
+    pub round_trips: usize,
+    /// Profit of the amounts both bought and sold, before fees
+    pub gross: Decimal,
+    /// Profit of the round trips and of the closed positions, net of the fees
+    /// and transfer costs
+    pub realized: Decimal,
+    pub unrealized: Decimal,
+    pub fees: Decimal,
+    /// Part of the fees paid by the rebalancing trades
+    pub rebalancing_fees: Decimal,
+    pub transfer_costs: Decimal,
+    /// Average cost of the base token held
+    pub average_cost: Decimal,
+    pub venues: Vec<VenuePnl>,
+}
+
+impl PnlReport {
+    pub fn total(&self) -> Decimal {
+        self.realized + self.unrealized
+    }
+
+    /// Total P&L as a fraction of the starting equity, zero without any
+    /// starting equity
+    pub fn total_return(&self) -> Decimal {
+        if self.starting_equity <= dec!(0) {
+            return dec!(0);
+        }
+        self.total() / self.starting_equity
+    }
+}
+
+impl Display for PnlReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "round trips {}", self.round_trips)?;
+        writeln!(f, "gross P&L {:.4}", self.gross)?;
+        write!(f, "fees paid {:.4}", self.fees)?;
+        for venue in &self.venues {
+            write!(f, " {} {:.4}", venue.venue, venue.fees)?;
+        }
+        writeln!(f, ", rebalancing {:.4}", self.rebalancing_fees)?;
+        writeln!(f, "transfer costs {:.4}", self.transfer_costs)?;
+        writeln!(f, "realized P&L {:.4}", self.realized)?;
+        write!(
+            f,
+            "unrealized P&L {:.4} at average cost {:.4}",
+            self.unrealized, self.average_cost
+        )?;
+        for venue in &self.venues {
+            write!(
+                f,
+                " {} {:.4} ({:.4} at {:.4})",
+                venue.venue, venue.unrealized, venue.position, venue.mid
+            )?;
+        }
+        writeln!(f)?;
+        write!(
+            f,
+            "equity {:.4}. P&L {:.4} ({:.4}%)",
+            self.equity,
+            self.total(),
+            self.total_return() * dec!(100)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::Fill;
+
+    const FEE: Decimal = dec!(0.001);
+
+    /// Two venues holding 5 base token at 100 and 500 quote token each
+    fn open() -> (PnlEngine, Portfolio) {
+        let venues = vec!["a".to_string(), "b".to_string()];
+        let mut portfolio = Portfolio::new(
+            venues.iter().map(|venue| (venue.clone(), None)).collect(),
+            dec!(1000),
+        );
+        portfolio.rebalance(dec!(100));
+        let mut pnl = PnlEngine::new(venues);
+        pnl.open(&portfolio, dec!(100));
+        (pnl, portfolio)
+    }
+
+    fn fill(
+        portfolio: &mut Portfolio,
+        venue: usize,
+        side: Side,
+        amount: Decimal,
+        price: Decimal,
+    ) -> LegFill {
+        portfolio.apply_fill(venue, side, &Fill { amount, price }, FEE);
+        LegFill {
+            venue,
+            side,
+            amount,
+            price,
+            fee: amount * price * FEE,
+        }
+    }
+
+    /// Buy `bought` at 100 on the first venue and sell `sold` at 102 on the
+    /// second
+    fn round_trip(
+        pnl: &mut PnlEngine,
+        portfolio: &mut Portfolio,
+        bought: Decimal,
+        sold: Decimal,
+    ) -> RoundTrip {
+        let fills = vec![
+            fill(portfolio, 0, Side::Buy, bought, dec!(100)),
+            fill(portfolio, 1, Side::Sell, sold, dec!(102)),
+        ];
+        let trade = Trade {
+            amount: bought.min(sold),
+            buy_price: dec!(100),
+            sell_price: dec!(102),
+            fees: fills.iter().map(|fill| fill.fee).sum(),
+            exposure: bought - sold,
+            fills,
+        };
+        pnl.record_round_trip(&trade).clone()
+    }
+
+    #[test]
+    fn round_trips_are_realized_net_of_fees() {
+        let (mut pnl, mut portfolio) = open();
+        let round_trip = round_trip(&mut pnl, &mut portfolio, dec!(1), dec!(1));
+        assert_eq!(round_trip.realized, dec!(2) - dec!(0.202));
+
+        let report = pnl.report(&portfolio, &[dec!(100), dec!(100)]);
+        assert_eq!(report.starting_equity, dec!(2000));
+        assert_eq!(report.round_trips, 1);
+        assert_eq!(report.gross, dec!(2));
+        assert_eq!(report.fees, dec!(0.202));
+        assert_eq!(report.venues[0].fees, dec!(0.1));
+        assert_eq!(report.realized, dec!(1.798));
+        assert_eq!(report.unrealized, dec!(0));
+        // The P&L accounts for every change of the equity
+        assert_eq!(report.equity - report.starting_equity, report.total());
+        assert_eq!(report.total_return(), dec!(1.798) / dec!(2000));
+    }
+
+    #[test]
+    fn exposure_joins_the_position() {
+        let (mut pnl, mut portfolio) = open();
+        let round_trip = round_trip(&mut pnl, &mut portfolio, dec!(1.5), dec!(1));
+        assert_eq!(round_trip.exposure, dec!(0.5));
+        assert_eq!(round_trip.realized, dec!(2) - round_trip.fees);
+
+        let report = pnl.report(&portfolio, &[dec!(104), dec!(104)]);
+        assert_eq!(report.average_cost, dec!(100));
+        // 10.5 base token held, marked from 100 to 104
+        assert_eq!(report.unrealized, dec!(42));
+        assert_eq!(report.equity - report.starting_equity, report.total());
+
+        // Closing the exposure realizes it
+        let fill = fill(&mut portfolio, 0, Side::Sell, dec!(0.5), dec!(104));
+        pnl.record_fill(fill);
+        let report = pnl.report(&portfolio, &[dec!(104), dec!(104)]);
+        assert_eq!(report.rebalancing_fees, dec!(0.052));
+        assert_eq!(report.realized, dec!(4) - report.fees);
+        assert_eq!(report.unrealized, dec!(40));
+        assert_eq!(report.equity - report.starting_equity, report.total());
+    }
+
+    #[test]
+    fn transfers_cost_the_fee() {
+        let (mut pnl, mut portfolio) = open();
+        portfolio.transfer(0, 1, Asset::Base, dec!(1), dec!(0.1));
+        pnl.record_transfer(Asset::Base, dec!(0.1));
+        portfolio.transfer(1, 0, Asset::Quote, dec!(100), dec!(2));
+        pnl.record_transfer(Asset::Quote, dec!(2));
+
+        let report = pnl.report(&portfolio, &[dec!(100), dec!(100)]);
+        assert_eq!(report.transfer_costs, dec!(12));
+        assert_eq!(report.realized, dec!(-12));
+        assert_eq!(report.unrealized, dec!(0));
+        assert_eq!(report.equity - report.starting_equity, report.total());
+    }
+
+    #[test]
+    fn no_return_without_starting_equity() {
+        let venues = vec!["a".to_string()];
+        let portfolio = Portfolio::new(vec![("a".to_string(), None)], dec!(0));
+        let mut pnl = PnlEngine::new(venues);
+        pnl.open(&portfolio, dec!(100));
+        pnl.record_transfer(Asset::Quote, dec!(1));
+
+        let report = pnl.report(&portfolio, &[dec!(100)]);
+        assert_eq!(report.starting_equity, dec!(0));
+        assert_eq!(report.total(), dec!(-1));
+        assert_eq!(report.total_return(), dec!(0));
+    }
+}
@@ -0,0 +1,358 @@
+//! Balances of the bot on every exchange
+//!
+//! The portfolio owns the base and quote balances of every venue. Amounts
+//! needed by in-flight orders are reserved until the orders are closed, so
+//! they are not available to other orders.
+
+use std::fmt::Display;
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::exchange::{Fill, Side, Wallet};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Asset {
+    Base,
+    Quote,
+}
+
+/// Balance of one asset on one venue
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Balance {
+    pub total: Decimal,
+    /// Part of the total held for in-flight orders
+    pub reserved: Decimal,
+}
+
+impl Balance {
+    pub fn available(&self) -> Decimal {
+        self.total - self.reserved
+    }
+}
+
+/// Amount held for an order, returned with [`Portfolio::release`]
+#[derive(Debug)]
+#[must_use]
+pub struct Reservation {
+    venue: usize,
+    asset: Asset,
+    amount: Decimal,
+}
+
+struct Inventory {
+    name: String,
+    wallet: Wallet,
+    reserved: Wallet,
+    /// Maximum base amount held on the venue
+    max_position: Option<Decimal>,
+}
+
+impl Inventory {
+    fn balance(&self, asset: Asset) -> Balance {
+        match asset {
+            Asset::Base => Balance {
+                total: self.wallet.base,
+                reserved: self.reserved.base,
+            },
+            Asset::Quote => Balance {
+                total: self.wallet.quote,
+                reserved: self.reserved.quote,
+            },
+        }
+    }
+
+    fn reserved_mut(&mut self, asset: Asset) -> &mut Decimal {
+        match asset {
+            Asset::Base => &mut self.reserved.base,
+            Asset::Quote => &mut self.reserved.quote,
+        }
+    }
+}
+
+/// Venues are identified by their index, like the exchanges
+pub struct Portfolio {
+    inventories: Vec<Inventory>,
+}
+
+impl Portfolio {
+    /// Start every venue with `starting_value` of quote token. Venues are
+    /// given as their name and position limit.
+    pub fn new(venues: Vec<(String, Option<Decimal>)>, starting_value: Decimal) -> Self {
+        Self {
+            inventories: venues
+                .into_iter()
+                .map(|(name, max_position)| Inventory {
+                    name,
+                    wallet: Wallet::new(starting_value),
+                    reserved: Wallet::default(),
+                    max_position,
+                })
+                .collect(),
+        }
+    }
+
+    /// Number of venues
+    pub fn venues(&self) -> usize {
+        self.inventories.len()
+    }
+
+    /// Split the quote balance of every venue 50/50 between base and quote
+    pub fn rebalance(&mut self, price: Decimal) {
+        for inventory in self.inventories.iter_mut() {
+            inventory.wallet.rebalance(price);
+        }
+    }
+
+    pub fn wallet(&self, venue: usize) -> &Wallet {
+        &self.inventories[venue].wallet
+    }
+
+    /// Wallets of every venue
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    pub fn wallets(&self) -> Vec<Wallet> {
+        self.inventories
+            .iter()
+            .map(|inventory| inventory.wallet.clone())
+            .collect()
+    }
+
+    /// Replace the wallets of every venue, nothing must be reserved
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    pub fn restore(&mut self, wallets: Vec<Wallet>) {
+        for (inventory, wallet) in self.inventories.iter_mut().zip(wallets) {
+            inventory.wallet = wallet;
+        }
+    }
+
+    pub fn balance(&self, venue: usize, asset: Asset) -> Balance {
+        self.inventories[venue].balance(asset)
+    }
+
+    /// Base amount that can still be bought on the venue within its position
+    /// limit
+    pub fn position_capacity(&self, venue: usize) -> Option<Decimal> {
+        let inventory = &self.inventories[venue];
+        inventory
+            .max_position
+            .map(|max_position| (max_position - inventory.wallet.base).max(dec!(0)))
+    }
+
+    /// Hold `amount` of the available balance. `None` if less is available,
+    /// nothing is held then.
+    pub fn reserve(&mut self, venue: usize, asset: Asset, amount: Decimal) -> Option<Reservation> {
+        let inventory = &mut self.inventories[venue];
+        if amount < dec!(0) || amount > inventory.balance(asset).available() {
+            return None;
+        }
+        *inventory.reserved_mut(asset) += amount;
+
+        Some(Reservation {
+            venue,
+            asset,
+            amount,
+        })
+    }
+
+    pub fn release(&mut self, reservation: Reservation) {
+        *self.inventories[reservation.venue].reserved_mut(reservation.asset) -= reservation.amount;
+    }
+
+    /// Apply a fill and its `fee`, paid in quote token
+    pub fn apply_fill(&mut self, venue: usize, side: Side, fill: &Fill, fee: Decimal) {
+        let inventory = &mut self.inventories[venue];
+        let wallet = &mut inventory.wallet;
+        let notional = fill.price * fill.amount;
+
+        match side {
+            // We are buying base token for quote token
+            Side::Buy => {
+                wallet.base += fill.amount;
+                wallet.quote -= notional + notional * fee;
+                // The orders are reserved within the balance, only a fill
+                // above its limit price can overspend
+                if wallet.quote < dec!(0) {
+                    tracing::warn!(
+                        venue = %inventory.name,
+                        quote = %wallet.quote,
+                        "quote balance overspent"
+                    );
+                    wallet.quote = dec!(0);
+                }
+            }
+            // We are selling base token for quote token
+            Side::Sell => {
+                wallet.base -= fill.amount;
+                wallet.quote += notional - notional * fee;
+            }
+        }
+    }
+
+    /// Move `amount` of `asset` from one venue to another. `cost` is deducted
+    /// from the amount received.
+    pub fn transfer(
+        &mut self,
+        from: usize,
+        to: usize,
+        asset: Asset,
+        amount: Decimal,
+        cost: Decimal,
+    ) {
+        let received = (amount - cost).max(dec!(0));
+        match asset {
+            Asset::Base => {
+                self.inventories[from].wallet.base -= amount;
+                self.inventories[to].wallet.base += received;
+            }
+            Asset::Quote => {
+                self.inventories[from].wallet.quote -= amount;
+                self.inventories[to].wallet.quote += received;
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> PortfolioSnapshot {
+        PortfolioSnapshot {
+            venues: self
+                .inventories
+                .iter()
+                .map(|inventory| VenueSnapshot {
+                    venue: inventory.name.clone(),
+                    base: inventory.balance(Asset::Base),
+                    quote: inventory.balance(Asset::Quote),
+                    max_position: inventory.max_position,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Balances of every venue at the time it was taken
+#[derive(Clone, Debug)]
+pub struct PortfolioSnapshot {
+    pub venues: Vec<VenueSnapshot>,
+}
+
+#[derive(Clone, Debug)]
+pub struct VenueSnapshot {
+    pub venue: String,
+    pub base: Balance,
+    pub quote: Balance,
+    pub max_position: Option<Decimal>,
+}
+
+impl Display for PortfolioSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, venue) in self.venues.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(
+                f,
+                "{} base {} ({} reserved) quote {} ({} reserved)",
+                venue.venue,
+                venue.base.total,
+                venue.base.reserved.normalize(),
+                venue.quote.total,
+                venue.quote.reserved.normalize()
+            )?;
+            if let Some(max_position) = venue.max_position {
+                write!(f, " max position {}", max_position)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two venues with 1000 of quote token, the second holding at most 2 of
+    /// base token
+    fn portfolio() -> Portfolio {
+        Portfolio::new(
+            vec![("a".to_string(), None), ("b".to_string(), Some(dec!(2)))],
+            dec!(1000),
+        )
+    }
+
+    fn fill(amount: Decimal, price: Decimal) -> Fill {
+        Fill { amount, price }
+    }
+
+    #[test]
+    fn reservations_hold_the_balance() {
+        let mut portfolio = portfolio();
+        let reservation = portfolio.reserve(0, Asset::Quote, dec!(600)).unwrap();
+        let balance = portfolio.balance(0, Asset::Quote);
+        assert_eq!(balance.total, dec!(1000));
+        assert_eq!(balance.available(), dec!(400));
+
+        // Not more than what is left
+        assert!(portfolio.reserve(0, Asset::Quote, dec!(401)).is_none());
+        assert!(portfolio.reserve(0, Asset::Base, dec!(1)).is_none());
+        assert!(portfolio.reserve(0, Asset::Quote, dec!(-1)).is_none());
+        assert_eq!(portfolio.balance(0, Asset::Quote).available(), dec!(400));
+        // The other venue is not affected
+        assert_eq!(portfolio.balance(1, Asset::Quote).available(), dec!(1000));
+
+        let rest = portfolio.reserve(0, Asset::Quote, dec!(400)).unwrap();
+        assert_eq!(portfolio.balance(0, Asset::Quote).available(), dec!(0));
+        portfolio.release(reservation);
+        portfolio.release(rest);
+        assert_eq!(portfolio.balance(0, Asset::Quote).reserved, dec!(0));
+    }
+
+    #[test]
+    fn fills_move_both_assets_with_the_fee() {
+        let mut portfolio = portfolio();
+        portfolio.apply_fill(0, Side::Buy, &fill(dec!(2), dec!(100)), dec!(0.01));
+        let wallet = portfolio.wallet(0);
+        assert_eq!(wallet.base, dec!(2));
+        assert_eq!(wallet.quote, dec!(798));
+
+        portfolio.apply_fill(0, Side::Sell, &fill(dec!(1), dec!(110)), dec!(0.01));
+        let wallet = portfolio.wallet(0);
+        assert_eq!(wallet.base, dec!(1));
+        assert_eq!(wallet.quote, dec!(906.9));
+    }
+
+    #[test]
+    fn overspent_quote_is_floored() {
+        let mut portfolio = portfolio();
+        portfolio.apply_fill(0, Side::Buy, &fill(dec!(11), dec!(100)), dec!(0));
+        assert_eq!(portfolio.wallet(0).base, dec!(11));
+        assert_eq!(portfolio.wallet(0).quote, dec!(0));
+    }
+
+    #[test]
+    fn position_capacity_is_what_is_left_below_the_limit() {
+        let mut portfolio = portfolio();
+        assert_eq!(portfolio.position_capacity(0), None);
+        assert_eq!(portfolio.position_capacity(1), Some(dec!(2)));
+
+        portfolio.apply_fill(1, Side::Buy, &fill(dec!(1.5), dec!(100)), dec!(0));
+        assert_eq!(portfolio.position_capacity(1), Some(dec!(0.5)));
+        portfolio.apply_fill(1, Side::Buy, &fill(dec!(1), dec!(100)), dec!(0));
+        assert_eq!(portfolio.position_capacity(1), Some(dec!(0)));
+    }
+
+    #[test]
+    fn transfers_pay_their_cost_on_arrival() {
+        let mut portfolio = portfolio();
+        portfolio.rebalance(dec!(100));
+        portfolio.transfer(0, 1, Asset::Base, dec!(2), dec!(0.1));
+        assert_eq!(portfolio.wallet(0).base, dec!(3));
+        assert_eq!(portfolio.wallet(1).base, dec!(6.9));
+
+        portfolio.transfer(1, 0, Asset::Quote, dec!(100), dec!(1));
+        assert_eq!(portfolio.wallet(1).quote, dec!(400));
+        assert_eq!(portfolio.wallet(0).quote, dec!(599));
+
+        // A cost above the amount loses it all
+        portfolio.transfer(0, 1, Asset::Quote, dec!(1), dec!(5));
+        assert_eq!(portfolio.wallet(0).quote, dec!(598));
+        assert_eq!(portfolio.wallet(1).quote, dec!(400));
+    }
+}
@@ -0,0 +1,456 @@
+//! Rebalancing of the inventory between venues
+//!
+//! Arbitrage in one direction moves the base token to one venue and the quote
+//! token to the other, until neither can trade. When the share of the value of
+//! a venue held in base token drifts too far from one half, the inventory is
+//! brought back with transfers between the venues or with offsetting trades
+//! on the skewed venues. Their fills and fees are recorded in the P&L.
+
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::anyhow;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::{
+    exchange::Side,
+    execution::LegVenue,
+    pnl::PnlEngine,
+    portfolio::{Asset, Portfolio},
+    risk::RiskManager,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RebalanceMethod {
+    /// Never rebalance
+    #[default]
+    None,
+    /// Move the surplus of every asset to the venues missing it. Transfers
+    /// are simulated, with live execution they have to be done by hand
+    Transfer,
+    /// Buy or sell on every skewed venue until it holds as much base as quote
+    Trade,
+}
+
+impl FromStr for RebalanceMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(RebalanceMethod::None),
+            "transfer" => Ok(RebalanceMethod::Transfer),
+            "trade" => Ok(RebalanceMethod::Trade),
+            _ => Err(anyhow!("unknown rebalance method {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RebalanceConfig {
+    pub method: RebalanceMethod,
+    /// Largest distance of the base share of the value of a venue from one
+    /// half before it is rebalanced, between 0 and 0.5
+    pub threshold: Decimal,
+    /// Cost of one transfer between venues, in quote token
+    pub transfer_fee: Decimal,
+    /// Minimum time between two rebalancings, transfers are not instant
+    pub interval: Duration,
+    /// Price tolerance of the offsetting trades, as a fraction of the mid
+    /// price
+    pub slippage: Decimal,
+}
+
+pub struct Rebalancer {
+    config: RebalanceConfig,
+    last_run: Option<SystemTime>,
+}
+
+impl Rebalancer {
+    pub fn new(config: RebalanceConfig) -> Self {
+        Self {
+            config,
+            last_run: None,
+        }
+    }
+
+    /// Rebalance `portfolio` if a venue is skewed and the last rebalancing is
+    /// older than the interval at `now`. `venues` are indexed like the
+    /// portfolio. The offsetting trades are checked against `risk`. Returns
+    /// whether the inventory was rebalanced, its cost is logged and recorded
+    /// in `pnl`.
+    pub async fn run(
+        &mut self,
+        portfolio: &mut Portfolio,
+        pnl: &mut PnlEngine,
+        risk: &mut RiskManager,
+        venues: &[LegVenue<'_>],
+        now: SystemTime,
+    ) -> bool {
+        if self.config.method == RebalanceMethod::None {
+            return false;
+        }
+        if self.last_run.is_some_and(|last_run| {
+            now.duration_since(last_run).unwrap_or_default() < self.config.interval
+        }) {
+            return false;
+        }
+        let skewed = venues
+            .iter()
+            .filter(|venue| self.is_skewed(portfolio, venue))
+            .collect::<Vec<_>>();
+        if skewed.is_empty() {
+            return false;
+        }
+
+        let cost = match self.config.method {
+            RebalanceMethod::None => dec!(0),
+            RebalanceMethod::Transfer => self.transfer(portfolio, pnl, venues),
+            RebalanceMethod::Trade => {
+                let mut cost = dec!(0);
+                for venue in skewed {
+                    cost += self.trade(portfolio, pnl, risk, venue, now).await;
+                }
+                cost
+            }
+        };
+        self.last_run = Some(now);
+
+        tracing::info!(method = ?self.config.method, cost = %cost, "inventory rebalanced");
+        tracing::debug!("portfolio {}", portfolio.snapshot());
+        true
+    }
+
+    fn is_skewed(&self, portfolio: &Portfolio, venue: &LegVenue) -> bool {
+        let Some(price) = venue.book.mid_price() else {
+            return false;
+        };
+        let wallet = portfolio.wallet(venue.index);
+        let value = wallet.quote + wallet.base * price;
+        if value <= dec!(0) {
+            return false;
+        }
+
+        (wallet.base * price / value - dec!(0.5)).abs() > self.config.threshold
+    }
+
+    /// Even out every asset between the venues, valuing the transfer fee at
+    /// the price of the first venue
+    fn transfer(
+        &self,
+        portfolio: &mut Portfolio,
+        pnl: &mut PnlEngine,
+        venues: &[LegVenue],
+    ) -> Decimal {
+        let Some(price) = venues.first().and_then(|venue| venue.book.mid_price()) else {
+            return dec!(0);
+        };
+        let mut cost = dec!(0);
+
+        for asset in [Asset::Base, Asset::Quote] {
+            let balances = venues
+                .iter()
+                .map(|venue| portfolio.balance(venue.index, asset).available())
+                .collect::<Vec<_>>();
+            let target = balances.iter().sum::<Decimal>() / Decimal::from(balances.len());
+            let (fee, value) = match asset {
+                Asset::Base => (self.config.transfer_fee / price, price),
+                Asset::Quote => (self.config.transfer_fee, dec!(1)),
+            };
+
+            let mut surplus = balances
+                .iter()
+                .enumerate()
+                .filter(|(_, balance)| **balance > target)
+                .map(|(venue, balance)| (venue, balance - target))
+                .collect::<Vec<_>>();
+            let mut deficit = balances
+                .iter()
+                .enumerate()
+                .filter(|(_, balance)| **balance < target)
+                .map(|(venue, balance)| (venue, target - balance));
+
+            let mut next = deficit.next();
+            while let (Some((to, missing)), Some((from, excess))) = (next, surplus.last_mut()) {
+                let amount = missing.min(*excess);
+                // Not worth the fee
+                if amount * value > self.config.transfer_fee {
+                    portfolio.transfer(*from, to, asset, amount, fee);
+                    pnl.record_transfer(asset, fee);
+                    cost += self.config.transfer_fee;
+                    tracing::info!(
+                        from = %venues[*from].executor,
+                        to = %venues[to].executor,
+                        asset = ?asset,
+                        amount = %amount,
+                        fee = %self.config.transfer_fee,
+                        "transfer scheduled"
+                    );
+                }
+
+                *excess -= amount;
+                if excess.is_zero() {
+                    surplus.pop();
+                }
+                next = if missing > amount {
+                    Some((to, missing - amount))
+                } else {
+                    deficit.next()
+                };
+            }
+        }
+
+        cost
+    }
+
+    /// Trade the venue back to as much base as quote in value. The cost is the
+    /// fee and the distance of the fill from the mid price.
+    async fn trade(
+        &self,
+        portfolio: &mut Portfolio,
+        pnl: &mut PnlEngine,
+        risk: &mut RiskManager,
+        venue: &LegVenue<'_>,
+        now: SystemTime,
+    ) -> Decimal {
+        let Some(price) = venue.book.mid_price() else {
+            return dec!(0);
+        };
+        let wallet = portfolio.wallet(venue.index);
+        let target = (wallet.quote + wallet.base * price) / dec!(2) / price;
+        let excess = wallet.base - target;
+
+        let (side, amount, limit) = if excess > dec!(0) {
+            (Side::Sell, excess, price * (dec!(1) - self.config.slippage))
+        } else {
+            // Keep enough quote token for the fee
+            (
+                Side::Buy,
+                -excess / (dec!(1) + venue.executor.fee()),
+                price * (dec!(1) + self.config.slippage),
+            )
+        };
+
+        let leg = venue
+            .execute(portfolio, risk, now, side, amount, limit)
+            .await;
+        let Some(fill) = venue.leg_fill(&leg) else {
+            return dec!(0);
+        };
+        let cost = fill.fee + fill.amount * (fill.price - price).abs();
+        pnl.record_fill(fill);
+        cost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fmt::Display, sync::Mutex, time::UNIX_EPOCH};
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        exchange::{Fill, OrderBook, OrderExecutor, Wallet},
+        risk::RiskConfig,
+    };
+
+    /// Executor filling its orders whole at their limit price, recording them
+    struct LimitExecutor {
+        orders: Mutex<Vec<(Side, Decimal, Decimal)>>,
+    }
+
+    impl LimitExecutor {
+        fn new() -> Self {
+            Self {
+                orders: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn orders(&self) -> Vec<(Side, Decimal, Decimal)> {
+            self.orders.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl OrderExecutor for LimitExecutor {
+        fn fee(&self) -> Decimal {
+            dec!(0.001)
+        }
+
+        async fn execute_order(
+            &self,
+            side: Side,
+            amount: Decimal,
+            price: Decimal,
+        ) -> anyhow::Result<Fill> {
+            self.orders.lock().unwrap().push((side, amount, price));
+            Ok(Fill { amount, price })
+        }
+    }
+
+    impl Display for LimitExecutor {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "limit")
+        }
+    }
+
+    fn config(method: RebalanceMethod) -> RebalanceConfig {
+        RebalanceConfig {
+            method,
+            threshold: dec!(0.1),
+            transfer_fee: dec!(1),
+            interval: Duration::from_secs(60),
+            slippage: dec!(0.01),
+        }
+    }
+
+    /// Venues holding `(base, quote)`, with a P&L opened at 100
+    fn holding(wallets: &[(Decimal, Decimal)]) -> (Portfolio, PnlEngine) {
+        let names = ["a", "b"].map(String::from);
+        let mut portfolio = Portfolio::new(
+            names.iter().map(|name| (name.clone(), None)).collect(),
+            dec!(0),
+        );
+        portfolio.restore(
+            wallets
+                .iter()
+                .map(|&(base, quote)| Wallet { base, quote })
+                .collect(),
+        );
+        let mut pnl = PnlEngine::new(names.to_vec());
+        pnl.open(&portfolio, dec!(100));
+        (portfolio, pnl)
+    }
+
+    /// Rebalance with `method` at `secs`, on two venues priced 99/101
+    async fn run(
+        rebalancer: &mut Rebalancer,
+        portfolio: &mut Portfolio,
+        pnl: &mut PnlEngine,
+        executors: &[LimitExecutor; 2],
+        secs: u64,
+    ) -> bool {
+        let book = OrderBook::with_levels(&[(dec!(99), dec!(10))], &[(dec!(101), dec!(10))]);
+        let venues = executors
+            .iter()
+            .enumerate()
+            .map(|(index, executor)| LegVenue {
+                index,
+                executor,
+                book: &book,
+            })
+            .collect::<Vec<_>>();
+        let mut risk = RiskManager::new(RiskConfig::default());
+        rebalancer
+            .run(
+                portfolio,
+                pnl,
+                &mut risk,
+                &venues,
+                UNIX_EPOCH + Duration::from_secs(secs),
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn venues_are_rebalanced_past_the_threshold() {
+        let executors = [LimitExecutor::new(), LimitExecutor::new()];
+        let mut rebalancer = Rebalancer::new(config(RebalanceMethod::Transfer));
+
+        // 55% of the value of the first venue in base token
+        let (mut portfolio, mut pnl) = holding(&[(dec!(5.5), dec!(450)), (dec!(4.5), dec!(550))]);
+        assert!(!run(&mut rebalancer, &mut portfolio, &mut pnl, &executors, 0).await);
+
+        // 65%
+        let (mut portfolio, mut pnl) = holding(&[(dec!(6.5), dec!(350)), (dec!(3.5), dec!(650))]);
+        assert!(run(&mut rebalancer, &mut portfolio, &mut pnl, &executors, 0).await);
+        // Skewed again, within the interval
+        portfolio.transfer(1, 0, Asset::Base, dec!(2), dec!(0));
+        assert!(!run(&mut rebalancer, &mut portfolio, &mut pnl, &executors, 59).await);
+        assert!(run(&mut rebalancer, &mut portfolio, &mut pnl, &executors, 60).await);
+
+        let mut rebalancer = Rebalancer::new(config(RebalanceMethod::None));
+        portfolio.transfer(1, 0, Asset::Base, dec!(2), dec!(0));
+        assert!(!run(&mut rebalancer, &mut portfolio, &mut pnl, &executors, 0).await);
+    }
+
+    #[tokio::test]
+    async fn transfers_even_out_the_venues_at_their_cost() {
+        let executors = [LimitExecutor::new(), LimitExecutor::new()];
+        let mut rebalancer = Rebalancer::new(config(RebalanceMethod::Transfer));
+        let (mut portfolio, mut pnl) = holding(&[(dec!(7), dec!(300)), (dec!(3), dec!(700))]);
+
+        assert!(run(&mut rebalancer, &mut portfolio, &mut pnl, &executors, 0).await);
+
+        // No order is sent, the fee of 1 is paid on each transfer
+        assert!(executors
+            .iter()
+            .all(|executor| executor.orders().is_empty()));
+        assert_eq!(
+            portfolio.wallet(0),
+            &Wallet {
+                base: dec!(5),
+                quote: dec!(499)
+            }
+        );
+        assert_eq!(
+            portfolio.wallet(1),
+            &Wallet {
+                base: dec!(4.99),
+                quote: dec!(500)
+            }
+        );
+
+        let report = pnl.report(&portfolio, &[dec!(100), dec!(100)]);
+        assert_eq!(report.transfer_costs, dec!(2));
+        assert_eq!(report.realized, dec!(-2));
+        assert_eq!(report.fees, dec!(0));
+        assert_eq!(report.equity, report.starting_equity - dec!(2));
+    }
+
+    #[tokio::test]
+    async fn transfers_not_worth_their_fee_are_skipped() {
+        let executors = [LimitExecutor::new(), LimitExecutor::new()];
+        let config = RebalanceConfig {
+            transfer_fee: dec!(150),
+            ..config(RebalanceMethod::Transfer)
+        };
+        let mut rebalancer = Rebalancer::new(config);
+        let (mut portfolio, mut pnl) = holding(&[(dec!(6.5), dec!(350)), (dec!(3.5), dec!(650))]);
+
+        assert!(run(&mut rebalancer, &mut portfolio, &mut pnl, &executors, 0).await);
+        // 1.5 of base token and 150 of quote token are not worth more than
+        // the fee
+        assert_eq!(portfolio.wallet(0).base, dec!(6.5));
+        assert_eq!(portfolio.wallet(1).quote, dec!(650));
+        let report = pnl.report(&portfolio, &[dec!(100), dec!(100)]);
+        assert_eq!(report.transfer_costs, dec!(0));
+    }
+
+    #[tokio::test]
+    async fn trades_bring_every_skewed_venue_back_to_half() {
+        let executors = [LimitExecutor::new(), LimitExecutor::new()];
+        let mut rebalancer = Rebalancer::new(config(RebalanceMethod::Trade));
+        let (mut portfolio, mut pnl) = holding(&[(dec!(7), dec!(300)), (dec!(3), dec!(700))]);
+
+        assert!(run(&mut rebalancer, &mut portfolio, &mut pnl, &executors, 0).await);
+
+        // Each venue trades on its own book, within the slippage of the mid,
+        // nothing is transferred
+        assert_eq!(executors[0].orders(), vec![(Side::Sell, dec!(2), dec!(99))]);
+        let (side, amount, limit) = executors[1].orders()[0];
+        assert_eq!((side, limit), (Side::Buy, dec!(101)));
+        assert_eq!(amount, dec!(2) / dec!(1.001));
+        assert_eq!(portfolio.wallet(0).base, dec!(5));
+
+        let report = pnl.report(&portfolio, &[dec!(100), dec!(100)]);
+        assert_eq!(report.transfer_costs, dec!(0));
+        assert_eq!(report.rebalancing_fees, report.fees);
+        assert_eq!(report.fees, dec!(0.198) + amount * dec!(101) * dec!(0.001));
+        // Sold 2 below their cost of 100, net of the fees
+        assert_eq!(report.realized, dec!(-2) - report.fees);
+    }
+}
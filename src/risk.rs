@@ -0,0 +1,318 @@
+//! Risk limits checked before every arbitrage
+//!
+//! The strategy sizes its trades within the notional and exposure limits, the
+//! risk manager is the last gate before the orders are sent. The buy leg of
+//! an arbitrage, the orders correcting unbalanced legs and the rebalancing
+//! trades are all checked. An order breaking a limit, too many orders in a
+//! minute or a drawdown of the day beyond the limit trip the kill switch: no
+//! order is sent anymore, the market data keeps flowing and the P&L keeps
+//! being marked.
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+
+use crate::exchange::Side;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// Every limit is optional
+#[derive(Clone, Debug, Default)]
+pub struct RiskConfig {
+    /// Largest value of an order, in quote token
+    pub max_notional: Option<Decimal>,
+    /// Largest value of the base token held on a venue, at its mid price, in
+    /// quote token
+    pub max_exposure: Option<Decimal>,
+    pub max_trades_per_minute: Option<usize>,
+    /// Largest drop of the equity from its value at the start of the day, as
+    /// a fraction
+    pub max_daily_drawdown: Option<Decimal>,
+}
+
+/// Limit that tripped the kill switch
+#[derive(Clone, Debug)]
+pub enum RiskBreach {
+    Notional { notional: Decimal },
+    Exposure { venue: usize, exposure: Decimal },
+    TradeRate { trades: usize },
+    Drawdown { drawdown: Decimal },
+}
+
+impl Display for RiskBreach {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RiskBreach::Notional { notional } => write!(f, "trade notional {}", notional),
+            RiskBreach::Exposure { venue, exposure } => {
+                write!(f, "exposure {} on venue {}", exposure, venue)
+            }
+            RiskBreach::TradeRate { trades } => write!(f, "{} trades in a minute", trades),
+            RiskBreach::Drawdown { drawdown } => {
+                write!(f, "daily drawdown {:.4}%", drawdown * dec!(100))
+            }
+        }
+    }
+}
+
+/// Order about to be sent, for `amount` on the venue `venue` at `price`
+pub struct TradeIntent {
+    pub venue: usize,
+    pub side: Side,
+    pub amount: Decimal,
+    pub price: Decimal,
+    /// Base token held on the venue and its mid price
+    pub position: Decimal,
+    pub mid: Decimal,
+}
+
+pub struct RiskManager {
+    config: RiskConfig,
+    /// Time of the trades of the last minute
+    trades: VecDeque<SystemTime>,
+    /// Day of the start equity, in days since the unix epoch
+    day: u64,
+    day_start_equity: Option<Decimal>,
+    killed: Option<RiskBreach>,
+}
+
+impl RiskManager {
+    pub fn new(config: RiskConfig) -> Self {
+        Self {
+            config,
+            trades: VecDeque::new(),
+            day: 0,
+            day_start_equity: None,
+            killed: None,
+        }
+    }
+
+    /// Why trading was halted, `None` while trading
+    pub fn killed(&self) -> Option<&RiskBreach> {
+        self.killed.as_ref()
+    }
+
+    fn trip(&mut self, breach: RiskBreach) {
+        if self.killed.is_none() {
+            tracing::error!(breach = %breach, "kill switch tripped, trading halted");
+            self.killed = Some(breach);
+        }
+    }
+
+    /// Largest amount that can be bought on a venue holding `position` of
+    /// base token at `mid`, with `buy_amount` giving the amount bought for an
+    /// amount of quote token
+    pub fn max_amount(
+        &self,
+        position: Decimal,
+        mid: Decimal,
+        buy_amount: impl Fn(Decimal) -> Decimal,
+    ) -> Option<Decimal> {
+        let notional = self.config.max_notional.map(buy_amount);
+        let exposure = self
+            .config
+            .max_exposure
+            .map(|max_exposure| (max_exposure / mid - position).max(dec!(0)));
+
+        match (notional, exposure) {
+            (Some(notional), Some(exposure)) => Some(notional.min(exposure)),
+            (notional, exposure) => notional.or(exposure),
+        }
+    }
+
+    /// Let the order through or trip the kill switch. Only buying adds to
+    /// the exposure.
+    pub fn check(&mut self, trade: &TradeIntent, now: SystemTime) -> Result<(), RiskBreach> {
+        if let Some(breach) = &self.killed {
+            return Err(breach.clone());
+        }
+
+        let notional = trade.amount * trade.price;
+        let exposure = match trade.side {
+            Side::Buy => (trade.position + trade.amount) * trade.mid,
+            Side::Sell => dec!(0),
+        };
+        self.trades
+            .retain(|time| now.duration_since(*time).unwrap_or_default() < MINUTE);
+
+        let breach = if self.config.max_notional.is_some_and(|max| notional > max) {
+            Some(RiskBreach::Notional { notional })
+        } else if self.config.max_exposure.is_some_and(|max| exposure > max) {
+            Some(RiskBreach::Exposure {
+                venue: trade.venue,
+                exposure,
+            })
+        } else if self
+            .config
+            .max_trades_per_minute
+            .is_some_and(|max| self.trades.len() >= max)
+        {
+            Some(RiskBreach::TradeRate {
+                trades: self.trades.len() + 1,
+            })
+        } else {
+            None
+        };
+
+        match breach {
+            Some(breach) => {
+                self.trip(breach.clone());
+                Err(breach)
+            }
+            None => {
+                self.trades.push_back(now);
+                Ok(())
+            }
+        }
+    }
+
+    /// Follow the equity of the bot, tripping the kill switch if it fell more
+    /// than the limit since the start of the day
+    pub fn mark(&mut self, equity: Decimal, now: SystemTime) {
+        let day = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() / DAY.as_secs());
+        if day != self.day || self.day_start_equity.is_none() {
+            self.day = day;
+            self.day_start_equity = Some(equity);
+        }
+
+        let (Some(max), Some(start)) = (self.config.max_daily_drawdown, self.day_start_equity)
+        else {
+            return;
+        };
+        if start <= dec!(0) {
+            return;
+        }
+        let drawdown = (start - equity) / start;
+        if drawdown > max {
+            self.trip(RiskBreach::Drawdown { drawdown });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intent(side: Side, amount: Decimal, price: Decimal) -> TradeIntent {
+        TradeIntent {
+            venue: 0,
+            side,
+            amount,
+            price,
+            position: dec!(1),
+            mid: price,
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn notional_breach_trips_the_kill_switch() {
+        let mut risk = RiskManager::new(RiskConfig {
+            max_notional: Some(dec!(500)),
+            ..Default::default()
+        });
+
+        assert!(risk
+            .check(&intent(Side::Buy, dec!(5), dec!(100)), at(0))
+            .is_ok());
+        assert!(matches!(
+            risk.check(&intent(Side::Sell, dec!(6), dec!(100)), at(1)),
+            Err(RiskBreach::Notional { notional }) if notional == dec!(600)
+        ));
+        assert!(risk.killed().is_some());
+    }
+
+    #[test]
+    fn exposure_is_only_added_by_buys() {
+        let mut risk = RiskManager::new(RiskConfig {
+            max_exposure: Some(dec!(300)),
+            ..Default::default()
+        });
+
+        // Holding 1 already, selling 5 does not add to the exposure
+        assert!(risk
+            .check(&intent(Side::Sell, dec!(5), dec!(100)), at(0))
+            .is_ok());
+        assert!(risk
+            .check(&intent(Side::Buy, dec!(2), dec!(100)), at(1))
+            .is_ok());
+        assert!(matches!(
+            risk.check(&intent(Side::Buy, dec!(2.5), dec!(100)), at(2)),
+            Err(RiskBreach::Exposure { venue: 0, exposure }) if exposure == dec!(350)
+        ));
+    }
+
+    #[test]
+    fn trade_rate_counts_the_orders_of_the_last_minute() {
+        let mut risk = RiskManager::new(RiskConfig {
+            max_trades_per_minute: Some(2),
+            ..Default::default()
+        });
+        let order = intent(Side::Buy, dec!(1), dec!(100));
+
+        assert!(risk.check(&order, at(0)).is_ok());
+        assert!(risk.check(&order, at(10)).is_ok());
+        // The first order left the window
+        assert!(risk.check(&order, at(61)).is_ok());
+        assert!(matches!(
+            risk.check(&order, at(62)),
+            Err(RiskBreach::TradeRate { trades: 3 })
+        ));
+    }
+
+    #[test]
+    fn drawdown_is_measured_from_the_start_of_the_day() {
+        let mut risk = RiskManager::new(RiskConfig {
+            max_daily_drawdown: Some(dec!(0.1)),
+            ..Default::default()
+        });
+        let day = DAY.as_secs();
+
+        risk.mark(dec!(1000), at(day / 2));
+        risk.mark(dec!(950), at(day / 2 + 1));
+        assert!(risk.killed().is_none());
+        // The next day starts from the current equity
+        risk.mark(dec!(800), at(day + 1));
+        assert!(risk.killed().is_none());
+        risk.mark(dec!(710), at(day + 2));
+        assert!(matches!(
+            risk.killed(),
+            Some(RiskBreach::Drawdown { drawdown }) if *drawdown == dec!(0.1125)
+        ));
+        assert!(risk
+            .check(&intent(Side::Buy, dec!(1), dec!(100)), at(day + 3))
+            .is_err());
+    }
+
+    #[test]
+    fn kill_switch_latches_the_first_breach() {
+        let mut risk = RiskManager::new(RiskConfig {
+            max_notional: Some(dec!(500)),
+            max_daily_drawdown: Some(dec!(0.1)),
+            ..Default::default()
+        });
+
+        risk.mark(dec!(1000), at(0));
+        assert!(risk
+            .check(&intent(Side::Buy, dec!(6), dec!(100)), at(1))
+            .is_err());
+        risk.mark(dec!(500), at(2));
+
+        // Orders within the limits stay blocked by the first breach
+        assert!(matches!(
+            risk.check(&intent(Side::Buy, dec!(1), dec!(100)), at(3)),
+            Err(RiskBreach::Notional { .. })
+        ));
+        assert!(matches!(risk.killed(), Some(RiskBreach::Notional { .. })));
+    }
+}
@@ -0,0 +1,354 @@
+//! Persistence of the trading history in SQLite
+//!
+//! Executed legs, wallet snapshots and detected opportunities are appended to
+//! an embedded database by a writer thread, so storing never blocks trading.
+//! Every row carries the id of the run that wrote it. The last wallets are
+//! read back when the database is opened so paper accounts survive restarts.
+
+use std::{path::Path, str::FromStr, time::Duration};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use rust_decimal::Decimal;
+use tokio::sync::mpsc;
+
+use crate::{capture::now_millis, exchange::Wallet, pnl::LedgerEntry};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS legs (
+    id INTEGER PRIMARY KEY,
+    run INTEGER NOT NULL,
+    time INTEGER NOT NULL,
+    round_trip INTEGER,
+    venue TEXT NOT NULL,
+    side TEXT NOT NULL,
+    amount TEXT NOT NULL,
+    price TEXT NOT NULL,
+    fee TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS wallets (
+    id INTEGER PRIMARY KEY,
+    run INTEGER NOT NULL,
+    time INTEGER NOT NULL,
+    venue TEXT NOT NULL,
+    base TEXT NOT NULL,
+    quote TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS opportunities (
+    id INTEGER PRIMARY KEY,
+    run INTEGER NOT NULL,
+    time INTEGER NOT NULL,
+    buy_venue TEXT NOT NULL,
+    sell_venue TEXT NOT NULL,
+    ask TEXT NOT NULL,
+    bid TEXT NOT NULL,
+    spread TEXT NOT NULL
+);
+";
+
+enum Record {
+    Leg(LedgerEntry),
+    /// Wallets of every venue, by index
+    Wallets(Vec<Wallet>),
+    Opportunity {
+        buy: usize,
+        sell: usize,
+        ask: Decimal,
+        bid: Decimal,
+        spread: Decimal,
+    },
+}
+
+/// Writer of the database. Venues are identified by their index, like the
+/// exchanges.
+pub struct Storage {
+    sender: mpsc::UnboundedSender<(u64, Record)>,
+    /// Last stored wallet of every venue, when the database was opened
+    wallets: Vec<Option<Wallet>>,
+    /// Number of ledger entries already stored
+    stored_entries: usize,
+}
+
+impl Storage {
+    /// Open or create the database at `path`. `venues` are the names of the
+    /// venues, unique across the markets.
+    pub fn open(path: &Path, venues: Vec<String>) -> anyhow::Result<Self> {
+        let mut connection = Connection::open(path)?;
+        // Every market writes to the database from its own thread
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.execute_batch(SCHEMA)?;
+        let wallets = venues
+            .iter()
+            .map(|venue| last_wallet(&connection, venue))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        std::thread::spawn(move || write_records(&mut connection, &venues, receiver));
+
+        Ok(Self {
+            sender,
+            wallets,
+            stored_entries: 0,
+        })
+    }
+
+    /// Wallets of the last run, if every venue has one
+    pub fn last_wallets(&self) -> Option<Vec<Wallet>> {
+        self.wallets.iter().cloned().collect()
+    }
+
+    fn store(&self, record: Record) {
+        // The writer runs as long as the storage, a record it fails to write
+        // is logged and dropped: storing is best effort
+        let _ = self.sender.send((now_millis(), record));
+    }
+
+    /// Store the entries of `ledger` added since the last call
+    pub fn store_ledger(&mut self, ledger: &[LedgerEntry]) {
+        for entry in ledger.iter().skip(self.stored_entries) {
+            self.store(Record::Leg(entry.clone()));
+        }
+        self.stored_entries = self.stored_entries.max(ledger.len());
+    }
+
+    pub fn store_wallets(&self, wallets: Vec<Wallet>) {
+        self.store(Record::Wallets(wallets));
+    }
+
+    pub fn store_opportunity(
+        &self,
+        buy: usize,
+        sell: usize,
+        ask: Decimal,
+        bid: Decimal,
+        spread: Decimal,
+    ) {
+        self.store(Record::Opportunity {
+            buy,
+            sell,
+            ask,
+            bid,
+            spread,
+        });
+    }
+}
+
+fn last_wallet(connection: &Connection, venue: &str) -> anyhow::Result<Option<Wallet>> {
+    let row = connection
+        .query_row(
+            "SELECT base, quote FROM wallets WHERE venue = ?1 ORDER BY id DESC LIMIT 1",
+            params![venue],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+        )
+        .optional()?;
+
+    let Some((base, quote)) = row else {
+        return Ok(None);
+    };
+    Ok(Some(Wallet {
+        base: Decimal::from_str(&base)?,
+        quote: Decimal::from_str(&quote)?,
+    }))
+}
+
+fn write_records(
+    connection: &mut Connection,
+    venues: &[String],
+    mut receiver: mpsc::UnboundedReceiver<(u64, Record)>,
+) {
+    let run = now_millis();
+
+    while let Some((time, record)) = receiver.blocking_recv() {
+        // A record that can not be written is lost, the next ones may be
+        if let Err(err) = write_record(connection, venues, run, time, record) {
+            tracing::error!("failed to write to the database: {}", err);
+        }
+    }
+}
+
+fn write_record(
+    connection: &mut Connection,
+    venues: &[String],
+    run: u64,
+    time: u64,
+    record: Record,
+) -> rusqlite::Result<()> {
+    match record {
+        Record::Leg(entry) => {
+            connection.execute(
+                "INSERT INTO legs (run, time, round_trip, venue, side, amount, price, fee)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    run,
+                    time,
+                    entry.round_trip,
+                    venues[entry.fill.venue],
+                    format!("{:?}", entry.fill.side),
+                    entry.fill.amount.to_string(),
+                    entry.fill.price.to_string(),
+                    entry.fill.fee.to_string(),
+                ],
+            )?;
+        }
+        Record::Wallets(wallets) => {
+            // The wallets are read back by venue, a snapshot written in part
+            // would resume some venues from an older one
+            let transaction = connection.transaction()?;
+            for (venue, wallet) in wallets.iter().enumerate() {
+                transaction.execute(
+                    "INSERT INTO wallets (run, time, venue, base, quote)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        run,
+                        time,
+                        venues[venue],
+                        wallet.base.to_string(),
+                        wallet.quote.to_string(),
+                    ],
+                )?;
+            }
+            transaction.commit()?;
+        }
+        Record::Opportunity {
+            buy,
+            sell,
+            ask,
+            bid,
+            spread,
+        } => {
+            connection.execute(
+                "INSERT INTO opportunities (run, time, buy_venue, sell_venue, ask, bid, spread)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    run,
+                    time,
+                    venues[buy],
+                    venues[sell],
+                    ask.to_string(),
+                    bid.to_string(),
+                    spread.to_string(),
+                ],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{exchange::Side, pnl::LegFill};
+
+    fn database() -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(SCHEMA).unwrap();
+        connection
+    }
+
+    fn venues() -> Vec<String> {
+        vec!["a".to_string(), "b".to_string()]
+    }
+
+    fn wallet(base: Decimal, quote: Decimal) -> Wallet {
+        Wallet { base, quote }
+    }
+
+    /// Write `records` with the writer, until they run out
+    fn write(connection: &mut Connection, records: Vec<Record>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        for (time, record) in records.into_iter().enumerate() {
+            sender.send((time as u64, record)).unwrap();
+        }
+        drop(sender);
+        write_records(connection, &venues(), receiver);
+    }
+
+    fn count(connection: &Connection, table: &str) -> usize {
+        connection
+            .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn last_wallets_are_read_back() {
+        let mut connection = database();
+        assert_eq!(last_wallet(&connection, "a").unwrap(), None);
+
+        write(
+            &mut connection,
+            vec![
+                Record::Wallets(vec![wallet(dec!(5), dec!(500)), wallet(dec!(5), dec!(500))]),
+                Record::Wallets(vec![
+                    wallet(dec!(6), dec!(399.9)),
+                    wallet(dec!(4), dec!(600.1)),
+                ]),
+            ],
+        );
+
+        assert_eq!(
+            last_wallet(&connection, "a").unwrap(),
+            Some(wallet(dec!(6), dec!(399.9)))
+        );
+        assert_eq!(
+            last_wallet(&connection, "b").unwrap(),
+            Some(wallet(dec!(4), dec!(600.1)))
+        );
+    }
+
+    #[test]
+    fn wallet_snapshots_are_written_whole() {
+        let mut connection = database();
+        connection
+            .execute_batch(
+                "CREATE TRIGGER broken BEFORE INSERT ON wallets WHEN NEW.venue = 'b'
+                 BEGIN SELECT RAISE(ABORT, 'broken'); END;",
+            )
+            .unwrap();
+
+        write(
+            &mut connection,
+            vec![Record::Wallets(vec![
+                wallet(dec!(5), dec!(500)),
+                wallet(dec!(5), dec!(500)),
+            ])],
+        );
+
+        assert_eq!(last_wallet(&connection, "a").unwrap(), None);
+    }
+
+    #[test]
+    fn writer_keeps_going_after_an_error() {
+        let mut connection = database();
+        connection.execute_batch("DROP TABLE legs").unwrap();
+
+        write(
+            &mut connection,
+            vec![
+                Record::Leg(LedgerEntry {
+                    round_trip: Some(0),
+                    fill: LegFill {
+                        venue: 0,
+                        side: Side::Buy,
+                        amount: dec!(1),
+                        price: dec!(100),
+                        fee: dec!(0.1),
+                    },
+                }),
+                Record::Opportunity {
+                    buy: 0,
+                    sell: 1,
+                    ask: dec!(100),
+                    bid: dec!(101),
+                    spread: dec!(0.008),
+                },
+                Record::Wallets(vec![wallet(dec!(6), dec!(400)), wallet(dec!(4), dec!(600))]),
+            ],
+        );
+
+        assert_eq!(count(&connection, "opportunities"), 1);
+        assert_eq!(count(&connection, "wallets"), 2);
+    }
+}